@@ -1,8 +1,23 @@
+mod backend;
+mod config;
+mod doctor;
+mod exec;
+mod logs;
+mod profiles;
+mod prompt;
+mod shell;
 mod start;
+mod status;
 mod stop;
 mod welcome;
-mod backend;
-pub use start::start;
+pub use backend::run_backend;
+pub use config::config;
+pub use doctor::doctor;
+pub use exec::exec;
+pub use logs::logs;
+pub use profiles::profiles;
+pub use shell::shell;
+pub use start::{dry_run, start};
+pub use status::status;
 pub use stop::stop;
 pub use welcome::welcome;
-pub use backend::run_backend;
\ No newline at end of file
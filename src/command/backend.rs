@@ -1,176 +1,196 @@
 use crate::LxDosError;
 use crate::modules::app::gui::Gui;
-use crate::modules::app::instance::{InstanceMessage, WindowClient, WindowType};
+use crate::modules::app::instance::{
+    InstanceMessage, TOKEN_ENV, WindowAck, WindowClient, WindowType,
+};
+use crate::modules::profile::Profile;
 use async_channel::{self, Receiver, Sender};
-use gui::glib::{self, MainContext};
-use instance_pipe::Client;
+use gui::glib;
+use std::env;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-pub fn run_backend(pipe_name: &str) -> Result<(), LxDosError> {
-    let gui = Gui::new();
-    let client = Client::start(pipe_name)?;
+mod settings;
+
+pub fn run_backend(
+    pipe_name: &str,
+    parent_pid: u32,
+    window_type: WindowType,
+    profile: &Profile,
+) -> Result<(), LxDosError> {
+    let token = env::var(TOKEN_ENV)
+        .map_err(|_| LxDosError::Message(format!("{} is not set", TOKEN_ENV)))?;
+    let gui = Gui::new(&window_type, profile);
+    let window_client = Arc::new(WindowClient::connect(
+        profile, pipe_name, parent_pid, &token,
+    )?);
+
+    // 親プロセスが落ちたらパイプを閉じる。受信スレッドが終わるとGUIも終了する
+    window_client.shutdown_when_exits(parent_pid)?;
     let pipe_name = pipe_name.to_string();
-    let window_client = Arc::new(WindowClient::new(client));
     let client_handle = Arc::new(Mutex::new(None::<JoinHandle<Result<(), LxDosError>>>));
 
     let window_client_clone_gui_handler = Arc::clone(&window_client);
     let client_handle_clone_gui_handler = Arc::clone(&client_handle);
     let pipe_name_clone_gui_handler = pipe_name.clone();
+    let profile = profile.clone();
 
     gui.handler(move |app: &gui::Application| {
         use gui::prelude::*;
 
         let app_clone = app.clone();
-        let (tx, rx): (Sender<InstanceMessage>, Receiver<InstanceMessage>) =
-            async_channel::unbounded();
+        // フロントエンドからの要求には ack を返すための ID が付く
+        let (tx, rx): (
+            Sender<(Option<u64>, InstanceMessage)>,
+            Receiver<(Option<u64>, InstanceMessage)>,
+        ) = async_channel::unbounded();
 
         let window_client_thread_clone = Arc::clone(&window_client_clone_gui_handler);
         let tx_clone = tx.clone();
         let handle = thread::spawn(move || {
+            // フロントエンドからのメッセージを待ち受け、GUIスレッドへ渡す
             loop {
-                match window_client_thread_clone.poll_event() {
-                    Ok(messages) => {
-                        for message in messages {
-                            println!("Sending message to channel: {:?}", message);
-                            if let Err(e) = tx_clone.send_blocking(message) {
-                                eprintln!("Failed to send message to channel: {}", e);
-                                break;
-                            }
+                match window_client_thread_clone.recv() {
+                    Ok(Some(request)) => {
+                        log::debug!("Sending message to channel: {:?}", request.message);
+                        if let Err(e) = tx_clone.send_blocking((Some(request.id), request.message))
+                        {
+                            log::error!("Failed to send message to channel: {}", e);
+                            break;
                         }
                     }
+                    Ok(None) => {
+                        log::info!("Frontend closed the pipe");
+                        break;
+                    }
                     Err(e) => {
-                        eprintln!("Client poll error: {}", e);
+                        log::error!("Client receive error: {}", e);
                         break;
                     }
                 }
-                thread::sleep(Duration::from_millis(50));
             }
             Ok::<(), LxDosError>(())
         });
         *client_handle_clone_gui_handler.lock().unwrap() = Some(handle);
 
-        let main_context = MainContext::default();
-        let app_clone_for_idle = app_clone.clone();
-        let rx = Arc::new(Mutex::new(rx));
-
+        let app_clone_for_receiver = app_clone.clone();
         let window_client_clone_idle = Arc::clone(&window_client_clone_gui_handler);
-        main_context
-            .with_thread_default(|| {
-                glib::source::idle_add_local(move || {
-                    let rx = rx.lock().unwrap();
-                    match rx.try_recv() {
-                        Ok(message) => {
-                            match message {
-                                InstanceMessage::OpenWindow {
-                                    pipe_name,
-                                    window_type,
-                                } => {
-                                    println!(
-                                        "Received OpenWindow for pipe: {}, type: {:?}",
-                                        pipe_name, window_type
-                                    );
-                                }
-                                InstanceMessage::CloseWindow { pipe_name } => {
-                                    println!("Received CloseWindow for pipe: {}", pipe_name);
-                                    app_clone_for_idle.quit();
-                                }
-                                InstanceMessage::MaximizeWindow { pipe_name } => {
-                                    println!("Received MaximizeWindow for pipe: {}", pipe_name);
-                                    if let Some(window) = app_clone_for_idle.active_window() {
-                                        window.maximize();
-                                    }
-                                }
-                                InstanceMessage::MinimizeWindow { pipe_name } => {
-                                    println!("Received MinimizeWindow for pipe: {}", pipe_name);
-                                    if let Some(window) = app_clone_for_idle.active_window() {
-                                        window.minimize();
-                                    }
-                                }
-                                InstanceMessage::RestoreWindow { pipe_name } => {
-                                    println!("Received RestoreWindow for pipe: {}", pipe_name);
-                                    if let Some(window) = app_clone_for_idle.active_window() {
-                                        window.unmaximize();
-                                        window.present();
-                                    }
-                                }
+        let window_client_clone_ack = Arc::clone(&window_client_clone_gui_handler);
+        glib::spawn_future_local(async move {
+            while let Ok((id, message)) = rx.recv().await {
+                let ack = match message {
+                    InstanceMessage::OpenWindow {
+                        pipe_name,
+                        window_type,
+                    } => {
+                        log::debug!(
+                            "Received OpenWindow for pipe: {}, type: {:?}",
+                            pipe_name,
+                            window_type
+                        );
+                        match app_clone_for_receiver.active_window() {
+                            Some(window) => {
+                                window.present();
+                                WindowAck::Done
                             }
-                            glib::ControlFlow::Continue
-                        }
-                        Err(async_channel::TryRecvError::Empty) => glib::ControlFlow::Continue,
-                        Err(async_channel::TryRecvError::Closed) => {
-                            println!("Channel closed, stopping receiver");
-                            glib::ControlFlow::Break
+                            None => WindowAck::Failed("no window to present".to_string()),
                         }
                     }
-                });
-            })
-            .expect("Failed to attach idle source to MainContext");
+                    InstanceMessage::CloseWindow { pipe_name } => {
+                        log::debug!("Received CloseWindow for pipe: {}", pipe_name);
+                        app_clone_for_receiver.quit();
+                        WindowAck::Done
+                    }
+                    InstanceMessage::MaximizeWindow { pipe_name } => {
+                        log::debug!("Received MaximizeWindow for pipe: {}", pipe_name);
+                        with_active_window(&app_clone_for_receiver, |window| window.maximize())
+                    }
+                    InstanceMessage::MinimizeWindow { pipe_name } => {
+                        log::debug!("Received MinimizeWindow for pipe: {}", pipe_name);
+                        with_active_window(&app_clone_for_receiver, |window| window.minimize())
+                    }
+                    InstanceMessage::RestoreWindow { pipe_name } => {
+                        log::debug!("Received RestoreWindow for pipe: {}", pipe_name);
+                        with_active_window(&app_clone_for_receiver, |window| {
+                            window.unmaximize();
+                            window.present();
+                        })
+                    }
+                    InstanceMessage::ConfigChanged { .. } => WindowAck::Unsupported,
+                };
+                if let Some(id) = id
+                    && let Err(e) = window_client_clone_ack.ack(id, ack)
+                {
+                    log::error!("Failed to ack message {}: {}", id, e);
+                }
+            }
+            // The pipe is gone, either hung up by the frontend or by the watchdog.
+            log::debug!("Channel closed, stopping receiver");
+            app_clone_for_receiver.quit();
+        });
 
         let tx_for_activate = tx.clone();
         let pipe_name_clone_for_activate = pipe_name_clone_gui_handler.clone();
-        println!("Application activated, sending OpenWindow message.");
-        if let Err(e) = tx_for_activate.send_blocking(InstanceMessage::OpenWindow {
-            pipe_name: pipe_name_clone_for_activate.clone(),
-            window_type: WindowType::Main,
-        }) {
-            eprintln!("Failed to send OpenWindow message on activate: {}", e);
+        log::debug!("Application activated, sending OpenWindow message.");
+        if let Err(e) = tx_for_activate.send_blocking((
+            None,
+            InstanceMessage::OpenWindow {
+                pipe_name: pipe_name_clone_for_activate.clone(),
+                window_type: window_type.clone(),
+            },
+        )) {
+            log::error!("Failed to send OpenWindow message on activate: {}", e);
         }
 
         app_clone.connect_window_added(move |_, window| {
-            println!("Window added to application");
+            log::debug!("Window added to application");
             window.present();
         });
 
         app_clone.connect_window_removed(move |app, _| {
-            println!("Window removed from application");
+            log::debug!("Window removed from application");
             app.quit();
         });
         let pipe_name_clone_bg = pipe_name_clone_gui_handler.clone();
         thread::spawn(move || {
-            println!("Background thread started for pipe: {}", pipe_name_clone_bg);
+            log::debug!("Background thread started for pipe: {}", pipe_name_clone_bg);
             thread::sleep(Duration::from_secs(1));
-            println!(
+            log::debug!(
                 "Background thread task completed for pipe: {}",
                 pipe_name_clone_bg
             );
             Ok::<(), LxDosError>(())
         });
-        let window_title = "Lx DOS";
-        let button = gui::Button::builder()
-            .label("Press me!")
-            .margin_top(12)
-            .margin_bottom(12)
-            .margin_start(12)
-            .margin_end(12)
-            .build();
-
-        let window = Gui::window_builder(&app, window_title)
-            .child(&button)
-            .width_request(480)
-            .height_request(360)
-            .build();
-
-        let window_weak = window.downgrade();
-        button.connect_clicked(move |_| {
-            if let Some(window) = window_weak.upgrade() {
-                println!("Button clicked, closing window");
-                window.close();
+        let window = match &window_type {
+            WindowType::Main => main_window(app),
+            WindowType::Settings => {
+                // 保存したらフロントエンドに読み直してもらう
+                let window_client_clone_saved = Arc::clone(&window_client_clone_idle);
+                let pipe_name_clone_saved = pipe_name.clone();
+                settings::window(app, &profile, move || {
+                    if let Err(e) =
+                        window_client_clone_saved.send(&InstanceMessage::ConfigChanged {
+                            pipe_name: pipe_name_clone_saved.clone(),
+                        })
+                    {
+                        log::error!("Failed to send ConfigChanged: {}", e);
+                    }
+                })
             }
-        });
+        };
 
         let window_client_clone_close_request = Arc::clone(&window_client_clone_idle);
         let pipe_name_clone_close_request = pipe_name.clone();
         window.connect_close_request(move |window| {
-            println!(
+            log::debug!(
                 "Window close requested, sending CloseWindow: {}",
                 pipe_name_clone_close_request
             );
             if let Err(e) = window_client_clone_close_request.send(&InstanceMessage::CloseWindow {
                 pipe_name: pipe_name_clone_close_request.clone(),
             }) {
-                eprintln!("Failed to send CloseWindow on window close: {}", e);
+                log::error!("Failed to send CloseWindow on window close: {}", e);
             }
             window.close();
             glib::Propagation::Proceed
@@ -180,11 +200,54 @@ pub fn run_backend(pipe_name: &str) -> Result<(), LxDosError> {
     });
     gui.run();
 
+    // 受信スレッドは recv でブロックしているので、パイプを閉じて終了させる
+    window_client.shutdown();
     if let Some(handle) = client_handle.lock().unwrap().take() {
         handle
             .join()
             .map_err(|e| LxDosError::Message(format!("Client thread panicked: {:?}", e)))??;
     }
-    println!("Application closed");
+    log::info!("Application closed");
     Ok(())
 }
+
+fn main_window(app: &gui::Application) -> gui::ApplicationWindow {
+    use gui::prelude::*;
+
+    let window_title = "Lx DOS";
+    let button = gui::Button::builder()
+        .label("Press me!")
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+
+    let window = Gui::window_builder(app, window_title)
+        .child(&button)
+        .width_request(480)
+        .height_request(360)
+        .build();
+
+    let window_weak = window.downgrade();
+    button.connect_clicked(move |_| {
+        if let Some(window) = window_weak.upgrade() {
+            log::debug!("Button clicked, closing window");
+            window.close();
+        }
+    });
+    window
+}
+
+/// Applies `f` to the focused window, or reports that there is none.
+fn with_active_window(app: &gui::Application, f: impl FnOnce(&gui::Window)) -> WindowAck {
+    use gui::prelude::*;
+
+    match app.active_window() {
+        Some(window) => {
+            f(&window);
+            WindowAck::Done
+        }
+        None => WindowAck::Failed("no active window".to_string()),
+    }
+}
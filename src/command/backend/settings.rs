@@ -0,0 +1,294 @@
+use crate::LxDosError;
+use crate::modules::app::gui::Gui;
+use crate::modules::lx_dos::config::{
+    self, DisplayMode, GuestConfig, MIN_MEMORY_MIB, SharedFolder,
+};
+use crate::modules::profile::Profile;
+use gui::prelude::*;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Display modes in the order of the drop-down.
+const DISPLAY_MODES: &[(DisplayMode, &str)] = &[
+    (DisplayMode::Gtk, "GTK window"),
+    (DisplayMode::Spice, "SPICE server"),
+    (DisplayMode::Vnc, "VNC server"),
+    (DisplayMode::None, "None"),
+];
+/// Upper bound of the memory spin button, 1 TiB.
+const MAX_MEMORY_MIB: f64 = 1024.0 * 1024.0;
+
+/// One row of the shared folder list.
+struct FolderRow {
+    row: gui::Box,
+    name: gui::Entry,
+    path: gui::Entry,
+    read_only: gui::CheckButton,
+}
+
+/// Widgets holding the values being edited.
+struct Form {
+    memory: gui::SpinButton,
+    cpus: gui::SpinButton,
+    display: gui::DropDown,
+    autostart: gui::CheckButton,
+    folders_box: gui::Box,
+    folders: RefCell<Vec<FolderRow>>,
+}
+
+impl Form {
+    /// The edited guest definition, with fields the window does not show taken from `base`.
+    fn config(&self, base: &GuestConfig) -> GuestConfig {
+        let display = DISPLAY_MODES
+            .get(self.display.selected() as usize)
+            .map_or(base.display, |(mode, _)| *mode);
+        GuestConfig {
+            memory_mib: self.memory.value_as_int() as u32,
+            cpus: self.cpus.value_as_int() as u32,
+            display,
+            autostart: self.autostart.is_active(),
+            shared_folders: self
+                .folders
+                .borrow()
+                .iter()
+                .map(|folder| SharedFolder {
+                    name: folder.name.text().trim().to_string(),
+                    path: PathBuf::from(folder.path.text().trim()),
+                    read_only: folder.read_only.is_active(),
+                })
+                .collect(),
+            ..base.clone()
+        }
+    }
+
+    fn add_folder(self: &Rc<Self>, folder: Option<&SharedFolder>) {
+        let row = gui::Box::new(gui::Orientation::Horizontal, 6);
+        let name = gui::Entry::builder()
+            .placeholder_text("Name")
+            .width_chars(10)
+            .build();
+        let path = gui::Entry::builder()
+            .placeholder_text("/absolute/path")
+            .hexpand(true)
+            .build();
+        let read_only = gui::CheckButton::with_label("Read-only");
+        let remove = gui::Button::from_icon_name("list-remove-symbolic");
+        if let Some(folder) = folder {
+            name.set_text(&folder.name);
+            path.set_text(&folder.path.to_string_lossy());
+            read_only.set_active(folder.read_only);
+        }
+        row.append(&name);
+        row.append(&path);
+        row.append(&read_only);
+        row.append(&remove);
+        self.folders_box.append(&row);
+
+        let form = Rc::downgrade(self);
+        let row_clone = row.clone();
+        remove.connect_clicked(move |_| {
+            if let Some(form) = form.upgrade() {
+                form.folders
+                    .borrow_mut()
+                    .retain(|folder| folder.row != row_clone);
+                form.folders_box.remove(&row_clone);
+            }
+        });
+        self.folders.borrow_mut().push(FolderRow {
+            row,
+            name,
+            path,
+            read_only,
+        });
+    }
+}
+
+/// Builds the Settings window, which edits the guest definition of `profile`.
+///
+/// `on_saved` runs after a successful save so that the frontend can reload it. The
+/// window closes once saved: a running guest cannot be changed, so there is nothing
+/// to apply while it stays open.
+pub fn window(
+    app: &gui::Application,
+    profile: &Profile,
+    on_saved: impl Fn() + 'static,
+) -> gui::ApplicationWindow {
+    let content = gui::Box::builder()
+        .orientation(gui::Orientation::Vertical)
+        .spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    let window = Gui::window_builder(app, &format!("Lx-DOS Settings ({})", profile))
+        .child(&content)
+        .default_width(560)
+        .build();
+
+    let loaded = profile.config_path().and_then(|path| {
+        if !path.exists() {
+            return Err(LxDosError::Message(format!(
+                "No guest definition at {}, set one up with `lx-dos --profile {} welcome`",
+                path.display(),
+                profile.name()
+            )));
+        }
+        let config = GuestConfig::load_unchecked(&path)?;
+        Ok((path, config))
+    });
+    let (path, config) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log::error!("Cannot edit the guest definition: {}", e);
+            content.append(&message_label(&e.to_string(), true));
+            return window;
+        }
+    };
+
+    let grid = gui::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .build();
+    let memory = gui::SpinButton::with_range(MIN_MEMORY_MIB as f64, MAX_MEMORY_MIB, 256.0);
+    memory.set_value(config.memory_mib as f64);
+    // GuestConfig::validate と同じ範囲にする
+    let cpus = gui::SpinButton::with_range(1.0, config::max_cpus() as f64, 1.0);
+    cpus.set_value(config.cpus as f64);
+    let display_names: Vec<&str> = DISPLAY_MODES.iter().map(|(_, name)| *name).collect();
+    let display = gui::DropDown::from_strings(&display_names);
+    if let Some(index) = DISPLAY_MODES
+        .iter()
+        .position(|(mode, _)| *mode == config.display)
+    {
+        display.set_selected(index as u32);
+    }
+    let autostart = gui::CheckButton::with_label("Boot the guest when Lx-DOS starts");
+    autostart.set_active(config.autostart);
+
+    grid.attach(&field_label("Memory (MiB)"), 0, 0, 1, 1);
+    grid.attach(&memory, 1, 0, 1, 1);
+    grid.attach(&field_label("CPUs"), 0, 1, 1, 1);
+    grid.attach(&cpus, 1, 1, 1, 1);
+    grid.attach(&field_label("Display"), 0, 2, 1, 1);
+    grid.attach(&display, 1, 2, 1, 1);
+    grid.attach(&autostart, 1, 3, 1, 1);
+    content.append(&grid);
+    let adjusted: Vec<String> = [
+        out_of_range("Memory (MiB)", config.memory_mib, &memory),
+        out_of_range("CPUs", config.cpus, &cpus),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if !adjusted.is_empty() {
+        content.append(&message_label(&adjusted.join("\n"), true));
+    }
+
+    let folders_box = gui::Box::new(gui::Orientation::Vertical, 6);
+    let form = Rc::new(Form {
+        memory,
+        cpus,
+        display,
+        autostart,
+        folders_box: folders_box.clone(),
+        folders: RefCell::new(Vec::new()),
+    });
+    for folder in &config.shared_folders {
+        form.add_folder(Some(folder));
+    }
+    let add_folder = gui::Button::with_label("Add folder");
+    let form_clone_add = Rc::clone(&form);
+    add_folder.connect_clicked(move |_| form_clone_add.add_folder(None));
+    let folders_content = gui::Box::builder()
+        .orientation(gui::Orientation::Vertical)
+        .spacing(6)
+        .margin_top(6)
+        .margin_bottom(6)
+        .margin_start(6)
+        .margin_end(6)
+        .build();
+    folders_content.append(&folders_box);
+    folders_content.append(&add_folder);
+    let folders_frame = gui::Frame::new(Some("Shared folders"));
+    folders_frame.set_child(Some(&folders_content));
+    content.append(&folders_frame);
+
+    let status = message_label("", false);
+    content.append(&status);
+
+    let buttons = gui::Box::builder()
+        .orientation(gui::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gui::Align::End)
+        .build();
+    let save = gui::Button::with_label("Save");
+    buttons.append(&save);
+    content.append(&buttons);
+
+    let window_weak = window.downgrade();
+    save.connect_clicked(move |_| {
+        let edited = form.config(&config);
+        match edited.save(&path) {
+            Ok(()) => {
+                log::info!("Saved {}", path.display());
+                on_saved();
+                if let Some(window) = window_weak.upgrade() {
+                    window.close();
+                }
+            }
+            Err(e) => show_message(&status, &save_error(&path, e), true),
+        }
+    });
+    window
+}
+
+/// Warns that the spin button showing `value` of the guest definition had to move it
+/// into its range, so that Save does not change the value without a word.
+fn out_of_range(name: &str, value: u32, spin: &gui::SpinButton) -> Option<String> {
+    let shown = spin.value_as_int() as u32;
+    let (min, max) = spin.range();
+    (shown != value).then(|| {
+        format!(
+            "{} is {} in the guest definition, outside {} to {}; Save stores {}",
+            name, value, min, max, shown
+        )
+    })
+}
+
+fn field_label(text: &str) -> gui::Label {
+    gui::Label::builder().label(text).xalign(0.0).build()
+}
+
+fn message_label(text: &str, error: bool) -> gui::Label {
+    let label = gui::Label::builder()
+        .xalign(0.0)
+        .wrap(true)
+        .selectable(true)
+        .build();
+    show_message(&label, text, error);
+    label
+}
+
+fn show_message(label: &gui::Label, text: &str, error: bool) {
+    label.set_text(text);
+    if error {
+        label.add_css_class("error");
+    } else {
+        label.remove_css_class("error");
+    }
+}
+
+/// Lists validation problems by key, the way `lx-dos config check` does.
+fn save_error(path: &Path, error: LxDosError) -> String {
+    match error {
+        LxDosError::Config(errors) => errors
+            .errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        e => format!("Failed to save {}: {}", path.display(), e),
+    }
+}
@@ -0,0 +1,22 @@
+use crate::LxDosError;
+use crate::modules::lx_dos::config::GuestConfig;
+use crate::modules::profile::Profile;
+use crate::utils::args::ConfigCommands;
+
+pub fn config(profile: &Profile, command: ConfigCommands) -> Result<(), LxDosError> {
+    match command {
+        ConfigCommands::Check { file } => {
+            let path = match file {
+                Some(file) => file,
+                None => profile.config_path()?,
+            };
+            let config = GuestConfig::load(&path)?;
+            println!("{}: guest {:?} is valid", path.display(), config.name);
+            Ok(())
+        }
+        ConfigCommands::Path => {
+            println!("{}", profile.config_path()?.display());
+            Ok(())
+        }
+    }
+}
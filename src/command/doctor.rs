@@ -0,0 +1,572 @@
+use super::welcome;
+use crate::LxDosError;
+use crate::modules::app::control::{InstanceLock, control_pipe_name};
+use crate::modules::app::gui::Gui;
+use crate::modules::app::instance;
+use crate::modules::lx_dos::config::GuestConfig;
+use crate::modules::lx_dos::qemu;
+use crate::modules::profile::Profile;
+use crate::utils::dirs;
+use crate::utils::process::{self, find_in_path};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Oldest QEMU accepting the `server=on,wait=off` socket options used for QMP.
+const MIN_QEMU_VERSION: (u32, u32) = (6, 0);
+/// Windows 11 asks for a 64 GB disk, which a qcow2 image grows to as it fills up.
+const WARN_FREE_GIB: u64 = 64;
+const FAIL_FREE_GIB: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// Result of one check, with what to do about it unless it passed.
+#[derive(Debug, serde::Serialize)]
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DoctorOutput<'a> {
+    ok: bool,
+    checks: &'a [Check],
+}
+
+/// Checks that this computer can run the guest of `profile`, exiting with status 1
+/// if any check fails.
+pub fn doctor(profile: &Profile, json: bool) -> Result<(), LxDosError> {
+    let config = profile
+        .config_path()
+        .and_then(|path| GuestConfig::load_unchecked(&path))
+        .ok();
+    let mut checks = vec![
+        check_kvm(),
+        check_qemu(),
+        check_qemu_img(),
+        check_firmware(),
+        check_virtiofsd(config.as_ref()),
+        check_guest(profile),
+        check_free_space(profile, config.as_ref()),
+    ];
+    checks.extend(check_dirs());
+    checks.push(check_display());
+    checks.push(check_tray());
+    checks.push(check_pipes(profile));
+    let ok = checks.iter().all(|check| check.outcome != Outcome::Fail);
+
+    if json {
+        let output = DoctorOutput {
+            ok,
+            checks: &checks,
+        };
+        let json = serde_json::to_string_pretty(&output)
+            .map_err(|e| LxDosError::Message(e.to_string()))?;
+        println!("{}", json);
+    } else {
+        let name_width = checks
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0);
+        for check in &checks {
+            println!(
+                "{} {:<name_width$} {}",
+                check.outcome, check.name, check.message
+            );
+            if let Some(hint) = &check.hint {
+                println!("     {:<name_width$} hint: {}", "", hint);
+            }
+        }
+    }
+    if ok { Ok(()) } else { Err(LxDosError::Exit(1)) }
+}
+
+fn check_kvm() -> Check {
+    let path = Path::new("/dev/kvm");
+    if !path.exists() {
+        return Check::fail(
+            "kvm",
+            "/dev/kvm does not exist",
+            "enable virtualization (VT-x or AMD-V) in the firmware settings and load \
+             the kvm_intel or kvm_amd module",
+        );
+    }
+    if !accessible(path, libc::R_OK | libc::W_OK) {
+        return Check::fail(
+            "kvm",
+            "/dev/kvm is not readable and writable by this user",
+            "add yourself to the kvm group with `sudo usermod -aG kvm $USER`, then log in again",
+        );
+    }
+    Check::pass("kvm", "/dev/kvm is accessible")
+}
+
+fn check_qemu() -> Check {
+    let Some(path) = find_in_path(qemu::QEMU_BINARY) else {
+        return Check::fail(
+            "qemu",
+            format!("{} is not in PATH", qemu::QEMU_BINARY),
+            "install QEMU, e.g. the qemu-system-x86 package",
+        );
+    };
+    match version_of(&path) {
+        Some(version) if version < MIN_QEMU_VERSION => Check::fail(
+            "qemu",
+            format!(
+                "{} is version {}.{}, Lx-DOS needs {}.{} or newer",
+                path.display(),
+                version.0,
+                version.1,
+                MIN_QEMU_VERSION.0,
+                MIN_QEMU_VERSION.1
+            ),
+            "upgrade QEMU",
+        ),
+        Some(version) => Check::pass(
+            "qemu",
+            format!("{} version {}.{}", path.display(), version.0, version.1),
+        ),
+        None => Check::warn(
+            "qemu",
+            format!("Cannot tell the version of {}", path.display()),
+            format!("check that `{} --version` works", path.display()),
+        ),
+    }
+}
+
+fn check_qemu_img() -> Check {
+    match find_in_path(qemu::QEMU_IMG) {
+        Some(path) => Check::pass("qemu-img", path.display().to_string()),
+        None => Check::fail(
+            "qemu-img",
+            format!("{} is not in PATH", qemu::QEMU_IMG),
+            "install the QEMU utilities, e.g. the qemu-utils package",
+        ),
+    }
+}
+
+fn check_firmware() -> Check {
+    match qemu::find_firmware() {
+        Some(path) => Check::pass("firmware", path.display().to_string()),
+        None => Check::warn(
+            "firmware",
+            "No OVMF UEFI firmware found, Windows 11 does not boot without it",
+            "install OVMF, e.g. the ovmf or edk2-ovmf package",
+        ),
+    }
+}
+
+/// `virtiofsd` only matters to a guest with shared folders.
+fn check_virtiofsd(config: Option<&GuestConfig>) -> Check {
+    let folders = config.map_or(0, |config| config.shared_folders.len());
+    match qemu::find_virtiofsd() {
+        Some(path) => Check::pass("virtiofsd", path.display().to_string()),
+        None if folders == 0 => Check::pass("virtiofsd", "Not needed without shared folders"),
+        None => Check::fail(
+            "virtiofsd",
+            format!(
+                "virtiofsd is not installed, {} shared folder(s) cannot start",
+                folders
+            ),
+            "install virtiofsd, or remove shared_folders from the guest definition",
+        ),
+    }
+}
+
+fn check_guest(profile: &Profile) -> Check {
+    let path = match profile.config_path() {
+        Ok(path) => path,
+        Err(e) => return Check::fail("guest", e.to_string(), "set $HOME or $XDG_CONFIG_HOME"),
+    };
+    if !path.exists() {
+        return Check::warn(
+            "guest",
+            format!("Profile {} has no guest definition", profile),
+            format!("set one up with `lx-dos --profile {} welcome`", profile),
+        );
+    }
+    match GuestConfig::load(&path) {
+        Ok(config) => Check::pass(
+            "guest",
+            format!("{} defines guest {:?}", path.display(), config.name),
+        ),
+        Err(LxDosError::Config(errors)) => Check::fail(
+            "guest",
+            format!("{} has {} problem(s)", path.display(), errors.errors.len()),
+            format!(
+                "run `lx-dos --profile {} config check` for details",
+                profile
+            ),
+        ),
+        Err(e) => Check::fail(
+            "guest",
+            format!("Cannot read {}: {}", path.display(), e),
+            "fix the file permissions or remove it",
+        ),
+    }
+}
+
+/// Free space where the disk image lives, or where `lx-dos welcome` would create it.
+fn check_free_space(profile: &Profile, config: Option<&GuestConfig>) -> Check {
+    let image = match config {
+        Some(config) => Ok(config.disk_image.clone()),
+        None => profile.default_disk_image(),
+    };
+    let Some(dir) = image.ok().as_deref().and_then(existing_ancestor) else {
+        return Check::warn(
+            "disk-space",
+            "Cannot tell where the disk image goes",
+            "set $HOME or $XDG_DATA_HOME",
+        );
+    };
+    let free_gib = match free_bytes(&dir) {
+        Ok(bytes) => bytes / (1024 * 1024 * 1024),
+        Err(e) => {
+            return Check::warn(
+                "disk-space",
+                format!("Cannot measure free space on {}: {}", dir.display(), e),
+                "check that the directory is readable",
+            );
+        }
+    };
+    let message = format!("{} GiB free on {}", free_gib, dir.display());
+    if free_gib < FAIL_FREE_GIB {
+        Check::fail(
+            "disk-space",
+            message,
+            "free up space or move the disk image elsewhere",
+        )
+    } else if free_gib < WARN_FREE_GIB {
+        Check::warn(
+            "disk-space",
+            message,
+            format!(
+                "Windows needs up to {} GiB as the disk image grows",
+                WARN_FREE_GIB
+            ),
+        )
+    } else {
+        Check::pass("disk-space", message)
+    }
+}
+
+/// The directories Lx-DOS writes to must be writable once they exist. Missing ones
+/// are reported, not created.
+fn check_dirs() -> Vec<Check> {
+    let dirs: [(&'static str, Result<PathBuf, LxDosError>); 4] = [
+        ("runtime-dir", Ok(dirs::runtime_dir_path())),
+        ("config-dir", dirs::config_dir()),
+        ("state-dir", dirs::state_dir()),
+        ("data-dir", dirs::data_dir()),
+    ];
+    dirs.into_iter()
+        .map(|(name, dir)| {
+            let dir = match dir {
+                Ok(dir) => dir,
+                Err(e) => return Check::fail(name, e.to_string(), "set $HOME"),
+            };
+            match existing_ancestor(&dir) {
+                Some(existing) if accessible(&existing, libc::W_OK | libc::X_OK) => {
+                    if existing == dir {
+                        Check::pass(name, format!("{} is writable", dir.display()))
+                    } else {
+                        Check::pass(
+                            name,
+                            format!("{} does not exist yet and can be created", dir.display()),
+                        )
+                    }
+                }
+                Some(existing) => Check::fail(
+                    name,
+                    format!("{} is not writable", existing.display()),
+                    format!("make it writable with `chmod u+w {}`", existing.display()),
+                ),
+                None => Check::fail(
+                    name,
+                    format!("No parent of {} exists", dir.display()),
+                    "point the XDG variable at an existing directory",
+                ),
+            }
+        })
+        .collect()
+}
+
+fn check_display() -> Check {
+    if welcome::has_display() {
+        let display = ["WAYLAND_DISPLAY", "DISPLAY"]
+            .iter()
+            .find_map(|var| Some(format!("{}={}", var, env::var(var).ok()?)))
+            .unwrap_or_default();
+        Check::pass("display", display)
+    } else {
+        Check::warn(
+            "display",
+            "Neither WAYLAND_DISPLAY nor DISPLAY is set, windows cannot open",
+            "run Lx-DOS from a graphical session, or use `lx-dos start --cli`",
+        )
+    }
+}
+
+fn check_tray() -> Check {
+    match Gui::tray_host_available() {
+        Ok(true) => Check::pass("tray", "A StatusNotifier host shows the tray icon"),
+        Ok(false) => Check::warn(
+            "tray",
+            "No StatusNotifier host, the tray icon will not show",
+            "enable tray icons in the desktop (e.g. the AppIndicator extension on GNOME), \
+             or control Lx-DOS with `lx-dos shell`",
+        ),
+        Err(e) => Check::warn(
+            "tray",
+            format!("Cannot reach the D-Bus session bus: {}", e),
+            "run Lx-DOS inside a desktop session",
+        ),
+    }
+}
+
+/// Sockets in the profile's runtime directory that nobody listens on any more, left
+/// behind by a crashed instance or window backend.
+fn check_pipes(profile: &Profile) -> Check {
+    let dir = dirs::runtime_dir_path().join(profile.name());
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Check::pass("pipes", format!("{} does not exist yet", dir.display()));
+        }
+        Err(e) => {
+            return Check::warn(
+                "pipes",
+                format!("Cannot list {}: {}", dir.display(), e),
+                "see the runtime-dir check",
+            );
+        }
+    };
+    let mut stale: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_ok_and(|kind| kind.is_socket())
+                && is_stale_pipe(profile, &entry.file_name().to_string_lossy())
+        })
+        .map(|entry| entry.path())
+        .collect();
+    stale.sort();
+    if stale.is_empty() {
+        return Check::pass("pipes", format!("No stale pipes in {}", dir.display()));
+    }
+    let paths: Vec<String> = stale
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    Check::warn(
+        "pipes",
+        format!("{} stale pipe(s): {}", stale.len(), paths.join(", ")),
+        format!("remove them with `rm {}`", paths.join(" ")),
+    )
+}
+
+/// Whether the process serving the pipe socket `name` is gone.
+///
+/// Judged from the instance lock and the pid in window pipe names instead of by
+/// connecting, which window pipes would log as a refused client.
+fn is_stale_pipe(profile: &Profile, name: &str) -> bool {
+    let Some(pipe) = name.strip_suffix(".sock") else {
+        return false;
+    };
+    if pipe == control_pipe_name() {
+        return matches!(InstanceLock::holder(profile), Ok(None));
+    }
+    instance::window_pipe_owner(pipe).is_some_and(|pid| !process::is_alive(pid))
+}
+
+/// Major and minor version from the first line of `<binary> --version`, such as
+/// "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)".
+fn version_of(binary: &Path) -> Option<(u32, u32)> {
+    let output = Command::new(binary).arg("--version").output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.lines().next()?.split("version ").nth(1)?;
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|number| number.parse::<u32>().ok());
+    Some((numbers.next()??, numbers.next()??))
+}
+
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|ancestor| ancestor.is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Checks `mode` (`libc::R_OK` and so on) for the real user, like access(2).
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a NUL-terminated string that outlives the call.
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+fn free_bytes(dir: &Path) -> Result<u64, LxDosError> {
+    let path =
+        CString::new(dir.as_os_str().as_bytes()).map_err(|e| LxDosError::Message(e.to_string()))?;
+    // SAFETY: statvfs is plain data, so all zeroes is a valid value.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is valid for writes for the whole call.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(LxDosError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::app::instance::WindowType;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "lx-dos-doctor-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn versions_are_read_from_the_first_line() {
+        let dir = scratch_dir();
+        let binary = dir.join("qemu-system-x86_64");
+        fs::write(
+            &binary,
+            "#!/bin/sh\n\
+             echo 'QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)'\n\
+             echo 'Copyright (c) 2003-2023 Fabrice Bellard'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(version_of(&binary), Some((8, 2)));
+        assert_eq!(version_of(&dir.join("missing")), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn window_pipes_of_an_exited_frontend_are_stale() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let gone = child.id();
+        child.wait().unwrap();
+        let profile = Profile::default();
+        let pipe = |pid| {
+            format!(
+                "{}.sock",
+                instance::window_pipe_name(pid, &WindowType::Main)
+            )
+        };
+
+        assert!(is_stale_pipe(&profile, &pipe(gone)));
+        assert!(!is_stale_pipe(&profile, &pipe(std::process::id())));
+        assert!(!is_stale_pipe(&profile, "qmp.sock"));
+        assert!(!is_stale_pipe(
+            &profile,
+            &instance::window_pipe_name(gone, &WindowType::Main)
+        ));
+    }
+
+    #[test]
+    fn missing_paths_are_judged_by_their_nearest_existing_ancestor() {
+        let dir = scratch_dir();
+        assert_eq!(existing_ancestor(&dir.join("a/b/c")), Some(dir.clone()));
+        assert!(accessible(&dir, libc::W_OK));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_output_leaves_out_missing_hints() {
+        let checks = [
+            Check::pass("kvm", "/dev/kvm is usable"),
+            Check::fail(
+                "qemu",
+                "qemu-system-x86_64 is not installed",
+                "install QEMU",
+            ),
+        ];
+        let output = DoctorOutput {
+            ok: false,
+            checks: &checks,
+        };
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "ok": false,
+                "checks": [
+                    { "name": "kvm", "outcome": "pass", "message": "/dev/kvm is usable" },
+                    {
+                        "name": "qemu",
+                        "outcome": "fail",
+                        "message": "qemu-system-x86_64 is not installed",
+                        "hint": "install QEMU",
+                    },
+                ],
+            })
+        );
+    }
+}
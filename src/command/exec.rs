@@ -0,0 +1,62 @@
+use crate::LxDosError;
+use crate::modules::lx_dos::qga::{self, QgaClient};
+use crate::modules::profile::Profile;
+use std::io::{self, IsTerminal, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How often the agent is asked whether the command has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Runs `command` in the guest of `profile` through its guest agent, copying its
+/// output to ours and exiting with its exit code.
+///
+/// Piped stdin is handed to the command. The guest agent cannot stream: it only
+/// hands the output over once the command has exited, so long-running commands
+/// print nothing until then.
+pub fn exec(profile: &Profile, command: &[String]) -> Result<(), LxDosError> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(LxDosError::Message("No command to run".to_string()));
+    };
+    let socket = profile.runtime_dir()?.join(qga::SOCKET_NAME);
+    let mut client = QgaClient::connect(&socket).map_err(|e| match e {
+        LxDosError::Io(e) => LxDosError::Message(format!(
+            "The guest of profile {} is not running ({}: {})",
+            profile,
+            socket.display(),
+            e
+        )),
+        e => e,
+    })?;
+
+    let input = if io::stdin().is_terminal() {
+        None
+    } else {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input)?;
+        Some(input)
+    };
+    let pid = client.exec(program, args, input.as_deref())?;
+    log::debug!("Started {} in the guest as pid {}", program, pid);
+
+    let mut truncated = false;
+    let status = loop {
+        let status = client.exec_status(pid)?;
+        io::stdout().write_all(&status.stdout)?;
+        io::stderr().write_all(&status.stderr)?;
+        truncated |= status.truncated;
+        if status.exited {
+            break status;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    io::stdout().flush()?;
+    if truncated {
+        log::warn!("The guest agent dropped part of the output of {}", program);
+    }
+
+    match status.host_exit_code() {
+        0 => Ok(()),
+        code => Err(LxDosError::Exit(code)),
+    }
+}
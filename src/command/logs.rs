@@ -0,0 +1,121 @@
+use crate::LxDosError;
+use crate::modules::app::instance::{self, WindowType};
+use crate::modules::lx_dos::qemu;
+use crate::modules::profile::Profile;
+use crate::utils::logs;
+use clap::ValueEnum;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How often `--follow` checks the log files for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn logs(profile: &Profile, follow: bool, window: Option<WindowType>) -> Result<(), LxDosError> {
+    let window_types = match &window {
+        Some(window_type) => vec![window_type.clone()],
+        None => WindowType::value_variants().to_vec(),
+    };
+    let mut paths = window_types
+        .iter()
+        .map(|window_type| instance::log_path(profile, window_type))
+        .collect::<Result<Vec<_>, _>>()?;
+    if window.is_none() {
+        paths.push(profile.log_dir()?.join(qemu::GUEST_LOG));
+    }
+
+    // RFC 3339 の時刻は文字列として並べ替えれば時系列になる
+    let mut lines = Vec::new();
+    for path in &paths {
+        for file in logs::log_files(path) {
+            lines.extend(fs::read_to_string(file)?.lines().map(str::to_string));
+        }
+    }
+    lines.sort_by_cached_key(|line| logs::line_timestamp(line));
+    let mut stdout = io::stdout().lock();
+    for line in &lines {
+        writeln!(stdout, "{}", line)?;
+    }
+    drop(stdout);
+
+    if follow {
+        follow_logs(paths)
+    } else {
+        if lines.is_empty() {
+            println!("No logs in {}", profile.log_dir()?.display());
+        }
+        Ok(())
+    }
+}
+
+/// A log file being followed, reopened when it is rotated.
+struct Followed {
+    path: PathBuf,
+    file: Option<(File, u64)>,
+    partial: Vec<u8>,
+}
+
+impl Followed {
+    fn new(path: PathBuf) -> Result<Self, LxDosError> {
+        let file = match File::open(&path) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::End(0))?;
+                let inode = file.metadata()?.ino();
+                Some((file, inode))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(LxDosError::Io(e)),
+        };
+        Ok(Self {
+            path,
+            file,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Returns the lines completed since the last call.
+    fn read_lines(&mut self) -> Result<Vec<String>, LxDosError> {
+        if let Some((file, _)) = &mut self.file {
+            file.read_to_end(&mut self.partial)?;
+        }
+        // ローテーションされたら新しいファイルを先頭から読む
+        let current = fs::metadata(&self.path).ok().map(|metadata| metadata.ino());
+        if current.is_some() && current != self.file.as_ref().map(|(_, inode)| *inode) {
+            let mut file = File::open(&self.path)?;
+            file.read_to_end(&mut self.partial)?;
+            self.file = Some((file, current.unwrap_or_default()));
+        }
+
+        let Some(end) = self.partial.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(Vec::new());
+        };
+        let complete: Vec<u8> = self.partial.drain(..=end).collect();
+        Ok(String::from_utf8_lossy(&complete)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+fn follow_logs(paths: Vec<PathBuf>) -> Result<(), LxDosError> {
+    let mut followed = paths
+        .into_iter()
+        .map(Followed::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    loop {
+        let mut lines = Vec::new();
+        for log in &mut followed {
+            lines.extend(log.read_lines()?);
+        }
+        lines.sort_by_cached_key(|line| logs::line_timestamp(line));
+        let mut stdout = io::stdout().lock();
+        for line in &lines {
+            writeln!(stdout, "{}", line)?;
+        }
+        drop(stdout);
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::LxDosError;
+use crate::modules::app::control::InstanceLock;
+use crate::modules::profile::Profile;
+use crate::utils::args::ProfileCommands;
+
+pub fn profiles(command: ProfileCommands) -> Result<(), LxDosError> {
+    match command {
+        ProfileCommands::List => {
+            let profiles = Profile::list()?;
+            if profiles.is_empty() {
+                println!("No profiles, create one with `lx-dos profiles create <name>`");
+            }
+            for profile in profiles {
+                let state = match InstanceLock::holder(&profile)? {
+                    Some(pid) => format!("running ({})", pid),
+                    None => "stopped".to_string(),
+                };
+                println!(
+                    "{:<16} {:<14} {}",
+                    profile.name(),
+                    state,
+                    profile.config_path()?.display()
+                );
+            }
+            Ok(())
+        }
+        ProfileCommands::Create { name, from } => {
+            let profile = Profile::new(&name)?;
+            let from = from.as_deref().map(Profile::new).transpose()?;
+            let path = profile.create(from.as_ref())?;
+            println!("Created profile {} at {}", profile, path.display());
+            Ok(())
+        }
+        ProfileCommands::Remove { name } => {
+            let profile = Profile::new(&name)?;
+            if profile.is_default() {
+                return Err(LxDosError::Message(
+                    "The default profile cannot be removed".to_string(),
+                ));
+            }
+            if let Some(pid) = InstanceLock::holder(&profile)? {
+                return Err(LxDosError::Message(format!(
+                    "Profile {} is running ({}), stop it first",
+                    profile, pid
+                )));
+            }
+            profile.remove()?;
+            println!("Removed profile {}", profile);
+            Ok(())
+        }
+    }
+}
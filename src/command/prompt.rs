@@ -0,0 +1,275 @@
+use crate::LxDosError;
+use crate::modules::app::control::{ControlCommand, ControlReply};
+use crate::modules::app::instance::{InstanceMessage, WindowInfo, WindowType};
+use clap::ValueEnum;
+
+/// Commands understood by `lx-dos shell` and the `lx-dos start --cli` prompt.
+pub const HELP: &str = "\
+status                  show what Lx-DOS is doing
+windows                 list open windows
+open <window>           open a window (main, settings)
+close <window>          close a window
+maximize <window>       maximize a window
+minimize <window>       minimize a window
+restore <window>        restore a maximized or minimized window
+guest start             boot the guest
+guest stop              shut the guest down
+guest suspend           pause the guest
+guest resume            continue a paused guest
+guest status            show the state of the guest
+version                 show the protocol and binary version
+quit                    close every window and stop Lx-DOS
+exit                    leave the prompt
+help                    show this help";
+
+const COMMANDS: &[&str] = &[
+    "status", "windows", "open", "close", "maximize", "minimize", "restore", "guest", "version",
+    "quit", "exit", "help",
+];
+/// Commands taking a window name.
+const WINDOW_COMMANDS: &[&str] = &["open", "close", "maximize", "minimize", "restore"];
+const GUEST_COMMANDS: &[&str] = &["start", "stop", "suspend", "resume", "status"];
+
+/// What a line typed at a prompt asks for.
+pub enum PromptAction {
+    Control(ControlCommand),
+    /// A message for a window backend, sent once its pipe name is known.
+    Window(WindowType, WindowVerb),
+    Help,
+    Exit,
+    Nothing,
+}
+
+/// Window commands that are passed to the backend as an `InstanceMessage`.
+#[derive(Debug, Clone, Copy)]
+pub enum WindowVerb {
+    Maximize,
+    Minimize,
+    Restore,
+}
+
+impl WindowVerb {
+    fn message(self, pipe_name: String) -> InstanceMessage {
+        match self {
+            WindowVerb::Maximize => InstanceMessage::MaximizeWindow { pipe_name },
+            WindowVerb::Minimize => InstanceMessage::MinimizeWindow { pipe_name },
+            WindowVerb::Restore => InstanceMessage::RestoreWindow { pipe_name },
+        }
+    }
+}
+
+/// Turns a prompt line into the action it asks for.
+pub fn parse(line: &str) -> Result<PromptAction, LxDosError> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(PromptAction::Nothing);
+    };
+    let argument = words.next();
+    if let Some(extra) = words.next() {
+        return Err(LxDosError::Message(format!(
+            "Unexpected argument {:?}",
+            extra
+        )));
+    }
+    let window = || -> Result<WindowType, LxDosError> {
+        let name =
+            argument.ok_or_else(|| LxDosError::Message(format!("Usage: {} <window>", command)))?;
+        WindowType::from_str(name, true)
+            .map_err(|_| LxDosError::Message(format!("Unknown window {:?}", name)))
+    };
+    let command = match command {
+        "status" => ControlCommand::Status,
+        "windows" => ControlCommand::ListWindows,
+        "open" => ControlCommand::OpenWindow(window()?),
+        "close" => ControlCommand::CloseWindow(window()?),
+        "maximize" => return Ok(PromptAction::Window(window()?, WindowVerb::Maximize)),
+        "minimize" => return Ok(PromptAction::Window(window()?, WindowVerb::Minimize)),
+        "restore" => return Ok(PromptAction::Window(window()?, WindowVerb::Restore)),
+        "guest" => match argument {
+            Some("start") => ControlCommand::StartGuest,
+            Some("stop") => ControlCommand::StopGuest,
+            Some("suspend") => ControlCommand::SuspendGuest,
+            Some("resume") => ControlCommand::ResumeGuest,
+            Some("status") => ControlCommand::GuestStatus,
+            _ => {
+                return Err(LxDosError::Message(format!(
+                    "Usage: guest <{}>",
+                    GUEST_COMMANDS.join("|")
+                )));
+            }
+        },
+        "version" => ControlCommand::Version,
+        "quit" => ControlCommand::Quit,
+        "exit" => return Ok(PromptAction::Exit),
+        "help" | "?" => return Ok(PromptAction::Help),
+        other => {
+            return Err(LxDosError::Message(format!(
+                "Unknown command {:?}, try `help`",
+                other
+            )));
+        }
+    };
+    Ok(PromptAction::Control(command))
+}
+
+/// Builds the `SendWindow` request for a window command, addressed with the pipe
+/// name of the open window in `windows`.
+pub fn window_command(
+    window_type: WindowType,
+    verb: WindowVerb,
+    windows: &[WindowInfo],
+) -> Result<ControlCommand, LxDosError> {
+    let Some(window) = windows
+        .iter()
+        .find(|window| window.window_type == window_type)
+    else {
+        return Err(LxDosError::Message(format!(
+            "{} window is not open",
+            window_type
+        )));
+    };
+    let message = verb.message(window.pipe_name.clone());
+    Ok(ControlCommand::SendWindow(window_type, message))
+}
+
+/// Candidates for the word that ends at the end of `line`, and where that word starts.
+pub fn complete(line: &str) -> (usize, Vec<String>) {
+    let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+    let (before, word) = line.split_at(start);
+    let candidates: Vec<String> = match before.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => COMMANDS.iter().map(|command| command.to_string()).collect(),
+        [command] if WINDOW_COMMANDS.contains(&command) => WindowType::value_variants()
+            .iter()
+            .filter_map(|window| window.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect(),
+        ["guest"] => GUEST_COMMANDS.iter().map(|verb| verb.to_string()).collect(),
+        _ => Vec::new(),
+    };
+    let candidates = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(word))
+        .collect();
+    (start, candidates)
+}
+
+/// Prints the answer to a command typed at a prompt.
+pub fn print_reply(reply: &ControlReply) {
+    match reply {
+        ControlReply::Ok => println!("ok"),
+        ControlReply::Version { protocol, binary } => {
+            println!("Lx-DOS {} (control protocol {})", binary, protocol)
+        }
+        ControlReply::Status(report) => super::status::print_table(report),
+        ControlReply::Guest(guest) => super::status::print_guest(guest),
+        ControlReply::Windows(windows) if windows.is_empty() => println!("No open windows"),
+        ControlReply::Windows(windows) => {
+            for window in windows {
+                let pid = window
+                    .child_pid
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:<10} pid {:<8} {}",
+                    window.window_type, pid, window.pipe_name
+                );
+            }
+        }
+        ControlReply::Error { kind, message } => println!("Error: {}: {}", kind, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> ControlCommand {
+        match parse(line).unwrap() {
+            PromptAction::Control(command) => command,
+            _ => panic!("{:?} is not a control command", line),
+        }
+    }
+
+    #[test]
+    fn lines_become_control_commands() {
+        assert!(matches!(command("status"), ControlCommand::Status));
+        assert!(matches!(
+            command("  windows  "),
+            ControlCommand::ListWindows
+        ));
+        assert!(matches!(
+            command("open Settings"),
+            ControlCommand::OpenWindow(WindowType::Settings)
+        ));
+        assert!(matches!(
+            command("close main"),
+            ControlCommand::CloseWindow(WindowType::Main)
+        ));
+        assert!(matches!(command("guest start"), ControlCommand::StartGuest));
+        assert!(matches!(
+            command("guest status"),
+            ControlCommand::GuestStatus
+        ));
+        assert!(matches!(command("quit"), ControlCommand::Quit));
+        assert!(matches!(parse("exit").unwrap(), PromptAction::Exit));
+        assert!(matches!(
+            parse("maximize main").unwrap(),
+            PromptAction::Window(WindowType::Main, WindowVerb::Maximize)
+        ));
+        assert!(matches!(parse("?").unwrap(), PromptAction::Help));
+        assert!(matches!(parse("   ").unwrap(), PromptAction::Nothing));
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for line in [
+            "open",
+            "open tray",
+            "close main now",
+            "guest",
+            "guest reboot",
+            "reboot",
+        ] {
+            assert!(parse(line).is_err(), "{:?} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn window_commands_are_addressed_to_the_open_window() {
+        let windows = [WindowInfo {
+            window_type: WindowType::Settings,
+            pipe_name: "lx-dos-default-settings".to_string(),
+            child_pid: Some(42),
+            uptime_secs: 1,
+            last_message_secs: None,
+        }];
+        let command = window_command(WindowType::Settings, WindowVerb::Restore, &windows).unwrap();
+        assert!(matches!(
+            command,
+            ControlCommand::SendWindow(
+                WindowType::Settings,
+                InstanceMessage::RestoreWindow { ref pipe_name }
+            ) if pipe_name == "lx-dos-default-settings"
+        ));
+        assert!(window_command(WindowType::Main, WindowVerb::Restore, &windows).is_err());
+    }
+
+    #[test]
+    fn completion_offers_the_words_that_fit() {
+        assert_eq!(complete("ma"), (0, vec!["maximize".to_string()]));
+        assert_eq!(complete("open s"), (5, vec!["settings".to_string()]));
+        assert_eq!(
+            complete("guest s"),
+            (
+                6,
+                vec![
+                    "start".to_string(),
+                    "stop".to_string(),
+                    "suspend".to_string(),
+                    "status".to_string()
+                ]
+            )
+        );
+        assert_eq!(complete("status m"), (7, Vec::new()));
+    }
+}
@@ -0,0 +1,126 @@
+use super::prompt::{self, PromptAction};
+use crate::LxDosError;
+use crate::modules::app::control::{ControlClient, ControlCommand, ControlReply};
+use crate::modules::profile::Profile;
+use crate::utils::dirs;
+use rustyline::completion::Completer;
+use rustyline::config::Configurer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::fs;
+use std::time::Duration;
+
+/// How long to wait for the running instance to answer a command. Window commands
+/// wait up to `WINDOW_ACK_TIMEOUT` for the backend on the other side.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Lines kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+/// Completes the words of `prompt::HELP`.
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(prompt::complete(&line[..pos]))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Interactive prompt sending commands to the running instance of `profile`.
+pub fn shell(profile: &Profile) -> Result<(), LxDosError> {
+    let mut client = ControlClient::connect(profile).map_err(|e| {
+        LxDosError::Message(format!("Lx-DOS profile {} is not running: {}", profile, e))
+    })?;
+    if let ControlReply::Version { binary, .. } =
+        client.request(ControlCommand::Version, REPLY_TIMEOUT)?
+    {
+        println!(
+            "Connected to Lx-DOS {} profile {}, type `help` for commands",
+            binary, profile
+        );
+    }
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(|e| LxDosError::Message(e.to_string()))?;
+    editor.set_helper(Some(ShellHelper));
+    editor
+        .set_max_history_size(HISTORY_SIZE)
+        .map_err(|e| LxDosError::Message(e.to_string()))?;
+    let history_path = dirs::state_dir()?.join("shell_history");
+    // 初回は履歴ファイルがまだ無い
+    let _ = editor.load_history(&history_path);
+
+    loop {
+        let line = match editor.readline("lx-dos> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(LxDosError::Message(e.to_string())),
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        let command = match prompt::parse(&line) {
+            Ok(PromptAction::Control(command)) => command,
+            Ok(PromptAction::Window(window_type, verb)) => {
+                let windows = match client.request(ControlCommand::ListWindows, REPLY_TIMEOUT)? {
+                    ControlReply::Windows(windows) => windows,
+                    reply => {
+                        prompt::print_reply(&reply);
+                        continue;
+                    }
+                };
+                match prompt::window_command(window_type, verb, &windows) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            Ok(PromptAction::Help) => {
+                println!("{}", prompt::HELP);
+                continue;
+            }
+            Ok(PromptAction::Exit) => break,
+            Ok(PromptAction::Nothing) => continue,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let quit = matches!(command, ControlCommand::Quit);
+        prompt::print_reply(&client.request(command, REPLY_TIMEOUT)?);
+        if quit {
+            break;
+        }
+    }
+
+    if let Some(dir) = history_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if let Err(e) = editor.save_history(&history_path) {
+        log::warn!("Failed to save {}: {}", history_path.display(), e);
+    }
+    Ok(())
+}
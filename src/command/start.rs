@@ -1,59 +1,581 @@
+use super::prompt::{self, PromptAction};
+use super::welcome;
 use crate::LxDosError;
 use crate::modules::app::App;
-use crate::modules::app::instance::InstanceMessage;
+use crate::modules::app::control::{
+    CONTROL_PROTOCOL_VERSION, ControlClient, ControlCommand, ControlErrorKind, ControlReply,
+    ControlServer, FORWARDED_EXIT_CODE, InstanceLock, StatusReport,
+};
 use crate::modules::app::instance::WindowType;
+use crate::modules::app::instance::{InstanceMessage, PendingCommand};
+use crate::modules::lx_dos::config::{ConfigErrors, GuestConfig};
+use crate::modules::lx_dos::qemu::{self, QemuLaunch, QemuRuntime};
+use crate::modules::lx_dos::{FinishedOperation, GuestEvent, GuestOperation, GuestState};
+use crate::modules::profile::Profile;
+use crate::utils::process;
+use crossbeam_channel::{Receiver, Sender, after, at, never, select};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use system_tray::Event as TrayEvent;
 use system_tray::Menu as TrayMenu;
-pub fn start() -> Result<(), LxDosError> {
-    let mut app = App::default();
-
-    let tray = App::system_tray()
-        .menu(TrayMenu::new("Open".to_string(), "open".to_string()))
-        .menu(TrayMenu::new("Quit".to_string(), "quit".to_string()));
-    tray.start();
-
-    loop {
-        match tray.poll_event()? {
-            TrayEvent::MenuItemClicked(id) => match id.as_str() {
-                "open" => {
+
+/// How long windows get to close on their own before their backends are killed.
+const WINDOW_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a second `start` waits for the running instance's control pipe.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a running guest's runtime is asked for state changes if it offers
+/// nothing to wait on, see `GuestRuntime::watch`.
+const RUNTIME_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// `system_tray` can only be polled, so its thread checks it at this rate.
+const TRAY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs the frontend of `profile`.
+///
+/// With `headless`, no tray is shown and windows cannot be opened; the instance is
+/// driven from a prompt on stdin and through the control pipe instead.
+pub fn start(profile: &Profile, headless: bool) -> Result<(), LxDosError> {
+    let Some(_lock) = InstanceLock::acquire(profile)? else {
+        if headless {
+            let pid = InstanceLock::read_pid(profile)?
+                .map(|pid| pid.to_string())
+                .unwrap_or_else(|| "unknown pid".to_string());
+            return Err(LxDosError::Message(format!(
+                "Lx-DOS profile {} is already running ({}), stop it first",
+                profile, pid
+            )));
+        }
+        return forward(profile, ControlCommand::OpenWindow(WindowType::Main));
+    };
+    let started_at = Instant::now();
+    let control = ControlServer::start(profile)?;
+    during_setup(profile, &control, || welcome::offer(profile, headless))?;
+    let mut app = App::new(profile.clone());
+    app.headless = headless;
+    let guest_events = app.lx_dos.subscribe();
+    load_config(&mut app)?;
+    let (operations, finished) = crossbeam_channel::unbounded();
+    if app.lx_dos.config().is_some_and(|config| config.autostart) {
+        match app.lx_dos.begin_start() {
+            Ok(operation) => spawn_operation(operation, &operations),
+            Err(e) => log::error!("Failed to boot the guest on start: {}", e),
+        }
+    }
+
+    let result = run(
+        &mut app,
+        &control,
+        &guest_events,
+        &operations,
+        &finished,
+        started_at,
+    );
+    let closed = app.windows.close_all(WINDOW_CLOSE_TIMEOUT);
+    power_down(&mut app, &finished);
+    result?;
+    closed
+}
+
+/// Runs `setup` while answering every control request with an error saying so, so
+/// that a second `start` meanwhile is told why instead of timing out.
+fn during_setup<T>(profile: &Profile, control: &ControlServer, setup: impl FnOnce() -> T) -> T {
+    let requests = control.requests().clone();
+    let (done, finished) = crossbeam_channel::bounded::<()>(0);
+    let message = format!(
+        "Lx-DOS profile {} is being set up, try again once that is done",
+        profile
+    );
+    let answering = thread::spawn(move || {
+        loop {
+            select! {
+                recv(requests) -> request => {
+                    let Ok(request) = request else { break };
+                    let reply = ControlReply::Error {
+                        kind: ControlErrorKind::Failed,
+                        message: message.clone(),
+                    };
+                    if let Err(e) = request.reply(reply) {
+                        log::error!("Failed to reply to control request {}: {}", request.id, e);
+                    }
+                },
+                recv(finished) -> _ => break,
+            }
+        }
+    });
+    let result = setup();
+    drop(done);
+    let _ = answering.join();
+    result
+}
+
+/// Serves the tray, the control pipe, the windows and the guest until asked to quit.
+fn run(
+    app: &mut App,
+    control: &ControlServer,
+    guest_events: &Receiver<GuestEvent>,
+    operations: &Sender<FinishedOperation>,
+    finished: &Receiver<FinishedOperation>,
+    started_at: Instant,
+) -> Result<(), LxDosError> {
+    // 実行中のゲストの定義は差し替えられないので、止まるまで読み直しを待つ
+    let mut config_changed = false;
+
+    let mut crash_notice = None;
+    let mut tray = (!app.headless).then(|| Tray::spawn(app.profile.clone(), crash_notice.clone()));
+    let mut prompt_lines = if app.headless {
+        spawn_prompt()
+    } else {
+        never()
+    };
+    let window_events = app.windows.events();
+    // QMP に何か届いたら起こしてもらう。一度知らせたら次の周回で見張り直す
+    let (runtime_ready_tx, runtime_ready) = crossbeam_channel::unbounded();
+    let mut runtime_watched = false;
+
+    // 何も起きていない間はどのチャンネルも待機したまま眠る
+    'main: loop {
+        let mut runtime_tick = never();
+        if guest_active(app) && !runtime_watched {
+            match app.lx_dos.watch_runtime() {
+                Some(fd) => {
+                    let ready = runtime_ready_tx.clone();
+                    process::on_readable(fd, move || {
+                        let _ = ready.send(());
+                    });
+                    runtime_watched = true;
+                }
+                None => runtime_tick = after(RUNTIME_POLL_INTERVAL),
+            }
+        }
+        let restart_timer = app.windows.next_restart().map(at).unwrap_or_else(never);
+        let tray_events = tray.as_ref().map_or_else(never, |tray| tray.events.clone());
+        select! {
+            recv(tray_events) -> event => match event {
+                Ok(Ok(TrayEvent::MenuItemClicked(id))) => match id.as_str() {
+                    "open" => {
+                        app.windows.open_window(WindowType::Main)?;
+                    }
+                    "settings" => {
+                        app.windows.open_window(WindowType::Settings)?;
+                    }
+                    "quit" => break 'main,
+                    // クラッシュ通知をクリックしたら諦めたウィンドウを開き直す
+                    "retry" => {
+                        for health in app.windows.health() {
+                            if health.gave_up {
+                                app.windows.open_window(health.window_type)?;
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                Ok(Ok(TrayEvent::TrayClicked)) => {
                     app.windows.open_window(WindowType::Main)?;
                 }
-                "quit" => break,
-                _ => {}
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    return Err(LxDosError::Message("System tray thread stopped".to_string()));
+                }
             },
-            TrayEvent::TrayClicked => {
-                app.windows.open_window(WindowType::Main)?;
-            }
-            _ => {}
-        }
-
-        // サーバーからのメッセージをポーリング
-        match app.windows.poll_event() {
-            Ok(messages) => {
-                for message in messages {
-                    match message {
-                        InstanceMessage::OpenWindow {
-                            pipe_name,
-                            window_type,
-                        } => {
-                            println!("Received OpenWindow for pipe: {}", pipe_name);
-                            println!("WindowType: {}", window_type);
-                        }
-                        InstanceMessage::CloseWindow {
-                            pipe_name,
-                        } => {
-                            println!("Received CloseWindow for pipe: {}", pipe_name);
+            // コントロールパイプからの要求を処理
+            recv(control.requests()) -> request => {
+                let Ok(request) = request else { continue };
+                let quit = matches!(request.command, ControlCommand::Quit);
+                let command = request.command.clone();
+                handle_control(app, command, operations, started_at, move |reply| {
+                    if let Err(e) = request.reply(reply) {
+                        log::error!("Failed to reply to control request {}: {}", request.id, e);
+                    }
+                });
+                if quit {
+                    log::info!("Received Quit on control pipe");
+                    break 'main;
+                }
+            },
+            // バックエンドからのメッセージを処理
+            recv(window_events) -> event => {
+                let Ok(event) = event else { continue };
+                match app.windows.handle_event(event) {
+                    Some(InstanceMessage::OpenWindow {
+                        pipe_name,
+                        window_type,
+                    }) => {
+                        log::debug!("Received OpenWindow for pipe: {} ({})", pipe_name, window_type);
+                    }
+                    Some(InstanceMessage::CloseWindow { pipe_name }) => {
+                        log::debug!("Received CloseWindow for pipe: {}", pipe_name);
+                    }
+                    Some(InstanceMessage::ConfigChanged { pipe_name }) => {
+                        log::debug!("Received ConfigChanged for pipe: {}", pipe_name);
+                        config_changed = true;
+                    }
+                    _ => {}
+                }
+            },
+            recv(guest_events) -> event => {
+                if let Ok(event) = event {
+                    log::info!("Guest state changed: {} -> {}", event.from, event.to);
+                }
+            },
+            // 別スレッドで終わった起動・停止の結果を反映する
+            recv(finished) -> done => {
+                if let Ok(done) = done
+                    && let Err(e) = app.lx_dos.finish(done)
+                {
+                    log::error!("Guest operation failed: {}", e);
+                }
+            },
+            // 状態の変化は下でまとめて取り込む
+            recv(runtime_ready) -> _ => runtime_watched = false,
+            recv(runtime_tick) -> _ => {},
+            recv(restart_timer) -> _ => app.windows.restart_due(),
+            recv(prompt_lines) -> line => {
+                let Ok(line) = line else {
+                    // stdin が閉じられても、コントロールパイプからは操作できる
+                    prompt_lines = never();
+                    continue;
+                };
+                let command = match prompt::parse(&line) {
+                    Ok(PromptAction::Control(command)) => command,
+                    // このプロンプトを抜けることは Lx-DOS を終了することと同じ
+                    Ok(PromptAction::Exit) => ControlCommand::Quit,
+                    Ok(PromptAction::Window(window_type, verb)) => {
+                        match prompt::window_command(window_type, verb, &app.windows.windows()) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                println!("{}", e);
+                                show_prompt();
+                                continue;
+                            }
                         }
-                        _ => {}
                     }
+                    Ok(PromptAction::Help) => {
+                        println!("{}", prompt::HELP);
+                        show_prompt();
+                        continue;
+                    }
+                    Ok(PromptAction::Nothing) => {
+                        show_prompt();
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        show_prompt();
+                        continue;
+                    }
+                };
+                let quit = matches!(command, ControlCommand::Quit);
+                handle_control(app, command, operations, started_at, move |reply| {
+                    prompt::print_reply(&reply);
+                    if !quit {
+                        show_prompt();
+                    }
+                });
+                if quit {
+                    break 'main;
                 }
+            },
+        }
+
+        // QMP のイベントはコマンドの返事を待つ間にも溜まるので、毎周回取り込む
+        if guest_active(app)
+            && let Err(e) = app.lx_dos.poll_runtime()
+        {
+            log::error!("Guest runtime error: {}", e);
+        }
+
+        if config_changed && matches!(app.lx_dos.state(), GuestState::Stopped | GuestState::Failed)
+        {
+            config_changed = false;
+            if let Err(e) = load_config(app) {
+                log::error!("Failed to reload the guest definition: {}", e);
             }
-            Err(e) => {
-                eprintln!("Poll event error: {}", e);
-            }
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(50));
+        // system_tray cannot change the menu of a started tray, so a changed notice
+        // replaces the whole tray. The old one is gone before the new one starts.
+        let notice = app.windows.crash_notice();
+        if tray.is_some() && notice != crash_notice {
+            drop(tray.take());
+            tray = Some(Tray::spawn(app.profile.clone(), notice.clone()));
+            crash_notice = notice;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the guest has a runtime whose state changes need picking up.
+fn guest_active(app: &App) -> bool {
+    !app.lx_dos.busy() && !matches!(app.lx_dos.state(), GuestState::Stopped | GuestState::Failed)
+}
+
+/// Runs `operation` on its own thread; its outcome arrives on the other end of `finished`.
+fn spawn_operation(operation: GuestOperation, finished: &Sender<FinishedOperation>) {
+    let finished = finished.clone();
+    thread::spawn(move || {
+        let _ = finished.send(operation.run());
+    });
+}
+
+/// Shuts the guest down before `app` is dropped, which would kill QEMU outright.
+fn power_down(app: &mut App, finished: &Receiver<FinishedOperation>) {
+    // 起動や停止の途中なら、まずそれが終わるのを待つ
+    if app.lx_dos.busy()
+        && let Ok(done) = finished.recv()
+        && let Err(e) = app.lx_dos.finish(done)
+    {
+        log::error!("Guest operation failed: {}", e);
+    }
+    if matches!(
+        app.lx_dos.state(),
+        GuestState::Running | GuestState::Suspended
+    ) {
+        log::info!("Shutting the guest down");
+        if let Err(e) = app.lx_dos.stop() {
+            log::error!("Failed to shut the guest down: {}", e);
+        }
+    }
+}
+
+/// Prints the commands `start` would run to boot the guest, without running them.
+pub fn dry_run(profile: &Profile) -> Result<(), LxDosError> {
+    let config_path = profile.config_path()?;
+    if !config_path.exists() {
+        return Err(LxDosError::Message(format!(
+            "Profile {} has no guest definition, set one up with `lx-dos --profile {} welcome`",
+            profile, profile
+        )));
     }
+    let config = GuestConfig::load(&config_path)?;
+    println!("{}", qemu_launch(profile, &config)?);
     Ok(())
 }
+
+/// Loads the guest definition of the profile, if it has one, applies its window
+/// settings and prepares QEMU to boot it.
+fn load_config(app: &mut App) -> Result<(), LxDosError> {
+    let config_path = app.profile.config_path()?;
+    if !config_path.exists() {
+        log::info!("No guest definition at {}", config_path.display());
+        return Ok(());
+    }
+    app.lx_dos.load_config(&config_path)?;
+    let Some(config) = app.lx_dos.config() else {
+        return Ok(());
+    };
+    for window_type in [WindowType::Main, WindowType::Settings] {
+        let restart = config.windows.get(&window_type).restart;
+        app.windows.set_restart_policy(window_type, restart);
+    }
+    let launch = qemu_launch(&app.profile, config)?;
+    let log_path = app.profile.log_dir()?.join(qemu::GUEST_LOG);
+    app.lx_dos
+        .set_runtime(Box::new(QemuRuntime::new(launch, log_path)))
+}
+
+/// The processes that boot the guest defined by `config`.
+fn qemu_launch(profile: &Profile, config: &GuestConfig) -> Result<QemuLaunch, LxDosError> {
+    let firmware = qemu::find_firmware();
+    if firmware.is_none() {
+        log::warn!("No OVMF firmware found, the guest boots with the BIOS");
+    }
+    let virtiofsd = match qemu::find_virtiofsd() {
+        Some(virtiofsd) => virtiofsd,
+        None => {
+            if !config.shared_folders.is_empty() {
+                log::warn!("virtiofsd not found, shared folders will fail to start");
+            }
+            PathBuf::from("virtiofsd")
+        }
+    };
+    let config_path = profile.config_path()?;
+    QemuLaunch::for_guest(
+        config,
+        &profile.runtime_dir()?,
+        firmware.as_deref(),
+        &virtiofsd,
+    )
+    .map_err(|error| {
+        LxDosError::Config(ConfigErrors {
+            path: config_path,
+            errors: vec![error],
+        })
+    })
+}
+
+/// Reads prompt lines from stdin on a thread, for `start --cli`.
+fn spawn_prompt() -> Receiver<String> {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    println!("Lx-DOS is running without a desktop session, type `help` for commands");
+    show_prompt();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn show_prompt() {
+    if io::stdin().is_terminal() {
+        print!("lx-dos> ");
+        let _ = io::stdout().flush();
+    }
+}
+
+/// The tray icon, running on its own thread until dropped.
+///
+/// `system_tray` offers neither a blocking wait for events nor a way to change the
+/// menu once the tray is started. So this thread is the one place that still wakes
+/// up periodically, and a new menu means a new tray.
+struct Tray {
+    /// The clicks the main loop cares about.
+    events: Receiver<Result<TrayEvent, system_tray::Error>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tray {
+    /// Starts a tray whose menu shows `notice`, such as a window that keeps crashing.
+    fn spawn(profile: Profile, notice: Option<String>) -> Self {
+        let (tx, events) = crossbeam_channel::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut tray = App::system_tray(&profile);
+            if let Some(notice) = notice {
+                tray = tray.menu(TrayMenu::new(format!("⚠ {}", notice), "retry".to_string()));
+            }
+            let tray = tray
+                .menu(TrayMenu::new("Open".to_string(), "open".to_string()))
+                .menu(TrayMenu::new(
+                    "Settings…".to_string(),
+                    "settings".to_string(),
+                ))
+                .menu(TrayMenu::new("Quit".to_string(), "quit".to_string()));
+            tray.start();
+            while !thread_stop.load(Ordering::SeqCst) {
+                match tray.poll_event() {
+                    Ok(event @ (TrayEvent::MenuItemClicked(_) | TrayEvent::TrayClicked)) => {
+                        if tx.send(Ok(event)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => thread::sleep(TRAY_POLL_INTERVAL),
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Self {
+            events,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Tray {
+    /// Waits until the tray icon is removed, at most `TRAY_POLL_INTERVAL`.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Carries out `command` and passes the reply to `respond`, from another thread if
+/// it has to wait for a window backend.
+fn handle_control(
+    app: &mut App,
+    command: ControlCommand,
+    operations: &Sender<FinishedOperation>,
+    started_at: Instant,
+    respond: impl FnOnce(ControlReply) + Send + 'static,
+) {
+    let result = match command {
+        ControlCommand::Version => Ok(ControlReply::Version {
+            protocol: CONTROL_PROTOCOL_VERSION,
+            binary: env!("CARGO_PKG_VERSION").to_string(),
+        }),
+        ControlCommand::Status => Ok(ControlReply::Status(StatusReport {
+            profile: app.profile.name().to_string(),
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: started_at.elapsed().as_secs(),
+            windows: app.windows.windows(),
+            window_health: app.windows.health(),
+            guest: app.lx_dos.status(),
+        })),
+        ControlCommand::ListWindows => Ok(ControlReply::Windows(app.windows.windows())),
+        ControlCommand::OpenWindow(_) if app.headless => Err(LxDosError::Message(
+            "Lx-DOS runs without a desktop session (--cli), windows cannot be opened".to_string(),
+        )),
+        ControlCommand::OpenWindow(window_type) => app
+            .windows
+            .open_window(window_type)
+            .map(|()| ControlReply::Ok),
+        // ウィンドウの返事を待つ間もメインループは止めない
+        ControlCommand::CloseWindow(window_type) => match app.windows.close_window(window_type) {
+            Ok(pending) => return wait_for_window(pending, respond),
+            Err(e) => Err(e),
+        },
+        ControlCommand::SendWindow(window_type, message) => {
+            match app.windows.send_window_command(window_type, message) {
+                Ok(pending) => return wait_for_window(pending, respond),
+                Err(e) => Err(e),
+            }
+        }
+        // 起動と停止は時間がかかるので、始めたところで答える
+        ControlCommand::StartGuest => app.lx_dos.begin_start().map(|operation| {
+            spawn_operation(operation, operations);
+            ControlReply::Ok
+        }),
+        ControlCommand::StopGuest => app.lx_dos.begin_stop().map(|operation| {
+            spawn_operation(operation, operations);
+            ControlReply::Ok
+        }),
+        ControlCommand::SuspendGuest => app.lx_dos.suspend().map(|()| ControlReply::Ok),
+        ControlCommand::ResumeGuest => app.lx_dos.resume().map(|()| ControlReply::Ok),
+        ControlCommand::GuestStatus => Ok(ControlReply::Guest(app.lx_dos.status())),
+        ControlCommand::Quit => Ok(ControlReply::Ok),
+    };
+    respond(reply_of(result));
+}
+
+/// Waits for the window backend to answer `pending` on its own thread, then
+/// passes the reply to `respond`.
+fn wait_for_window(pending: PendingCommand, respond: impl FnOnce(ControlReply) + Send + 'static) {
+    thread::spawn(move || respond(reply_of(pending.wait().map(|()| ControlReply::Ok))));
+}
+
+fn reply_of(result: Result<ControlReply, LxDosError>) -> ControlReply {
+    result.unwrap_or_else(|e| ControlReply::Error {
+        kind: ControlErrorKind::Failed,
+        message: e.to_string(),
+    })
+}
+
+/// Hands `command` to the instance that holds the lock instead of starting a second tray.
+fn forward(profile: &Profile, command: ControlCommand) -> Result<(), LxDosError> {
+    let pid = InstanceLock::read_pid(profile)?
+        .map(|pid| pid.to_string())
+        .unwrap_or_else(|| "unknown pid".to_string());
+    let mut client = ControlClient::connect_timeout(profile, FORWARD_TIMEOUT)?;
+    client.request(command.clone(), FORWARD_TIMEOUT)?;
+    println!(
+        "Lx-DOS profile {} is already running ({}), forwarded {:?}",
+        profile, pid, command
+    );
+    Err(LxDosError::Exit(FORWARDED_EXIT_CODE))
+}
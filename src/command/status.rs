@@ -0,0 +1,162 @@
+use crate::LxDosError;
+use crate::modules::app::control::{ControlClient, ControlCommand, ControlReply, StatusReport};
+use crate::modules::app::instance::restart::WindowHealth;
+use crate::modules::lx_dos::GuestStatus;
+use crate::modules::profile::Profile;
+use std::time::Duration;
+
+/// How long to wait for the running instance to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(serde::Serialize)]
+struct StatusOutput<'a> {
+    running: bool,
+    #[serde(flatten)]
+    report: Option<&'a StatusReport>,
+}
+
+pub fn status(profile: &Profile, json: bool) -> Result<(), LxDosError> {
+    let report = match ControlClient::connect(profile) {
+        Ok(mut client) => match client.request(ControlCommand::Status, REPLY_TIMEOUT)? {
+            ControlReply::Status(report) => Some(report),
+            reply => {
+                return Err(LxDosError::Message(format!(
+                    "Unexpected reply to Status: {:?}",
+                    reply
+                )));
+            }
+        },
+        // 接続できなければ動いていない。版が違うなどの失敗はそのまま伝える
+        Err(LxDosError::Io(_)) => None,
+        Err(e) => return Err(e),
+    };
+
+    if json {
+        let output = StatusOutput {
+            running: report.is_some(),
+            report: report.as_ref(),
+        };
+        let json = serde_json::to_string_pretty(&output)
+            .map_err(|e| LxDosError::Message(e.to_string()))?;
+        println!("{}", json);
+    } else if let Some(report) = report {
+        print_table(&report);
+    } else {
+        println!("Lx-DOS profile {} is not running", profile);
+    }
+    Ok(())
+}
+
+pub(super) fn print_table(report: &StatusReport) {
+    println!(
+        "Lx-DOS {} profile {} (pid {}), up {}",
+        report.version,
+        report.profile,
+        report.pid,
+        format_duration(report.uptime_secs)
+    );
+    print_guest(&report.guest);
+    for health in &report.window_health {
+        print_health(health);
+    }
+    println!();
+
+    if report.windows.is_empty() {
+        println!("No open windows");
+        return;
+    }
+    let pipe_width = report
+        .windows
+        .iter()
+        .map(|window| window.pipe_name.len())
+        .max()
+        .unwrap_or(0)
+        .max("PIPE".len());
+    println!(
+        "{:<10} {:<pipe_width$} {:>8} {:>10} {:>14}",
+        "WINDOW", "PIPE", "PID", "UPTIME", "LAST MESSAGE"
+    );
+    for window in &report.windows {
+        let pid = window
+            .child_pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let last_message = window
+            .last_message_secs
+            .map(|secs| format!("{} ago", format_duration(secs)))
+            .unwrap_or_else(|| "never".to_string());
+        println!(
+            "{:<10} {:<pipe_width$} {:>8} {:>10} {:>14}",
+            window.window_type.to_string(),
+            window.pipe_name,
+            pid,
+            format_duration(window.uptime_secs),
+            last_message
+        );
+    }
+}
+
+pub(super) fn print_guest(guest: &GuestStatus) {
+    println!(
+        "Guest: {} for {} (runtime: {})",
+        guest.state,
+        format_duration(guest.state_secs),
+        guest.runtime.as_deref().unwrap_or("none")
+    );
+    if let Some(failure) = &guest.failure {
+        println!("Last failure: {}", failure);
+    }
+}
+
+fn print_health(health: &WindowHealth) {
+    let last_exit = match (health.last_exit, health.last_exit_secs) {
+        (Some(exit), Some(secs)) => format!("{} {} ago", exit, format_duration(secs)),
+        _ => "never exited".to_string(),
+    };
+    let next = if health.gave_up {
+        "gave up restarting".to_string()
+    } else if let Some(secs) = health.restart_in_secs {
+        format!("restart in {}", format_duration(secs))
+    } else {
+        format!("restart {}", health.restart)
+    };
+    println!(
+        "{} window: {}, {} crashes in the last minute, {}",
+        health.window_type, last_exit, health.crashes, next
+    );
+}
+
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_output_without_an_instance_only_says_so() {
+        let output = StatusOutput {
+            running: false,
+            report: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({ "running": false })
+        );
+    }
+
+    #[test]
+    fn durations_drop_the_units_they_do_not_need() {
+        assert_eq!(format_duration(7), "7s");
+        assert_eq!(format_duration(65), "1m 05s");
+        assert_eq!(format_duration(3 * 3600 + 2 * 60 + 1), "3h 02m 01s");
+    }
+}
@@ -1,5 +1,83 @@
 use crate::LxDosError;
+use crate::modules::app::control::{ControlClient, ControlCommand, InstanceLock};
+use crate::modules::profile::Profile;
+use crate::utils::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn stop() -> Result<(), LxDosError> {
-    Ok(())
+/// How long to wait for the running instance to acknowledge `Quit`.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn stop(profile: &Profile, timeout: u64, force: bool) -> Result<(), LxDosError> {
+    let Some(pid) = InstanceLock::holder(profile)? else {
+        if let Some(pid) = InstanceLock::read_pid(profile)?
+            && InstanceLock::clear_stale(profile)?
+        {
+            println!("Clearing stale lock file of Lx-DOS ({})", pid);
+        }
+        println!("Lx-DOS profile {} is not running", profile);
+        return Ok(());
+    };
+
+    let requested = ControlClient::connect(profile)
+        .and_then(|mut client| client.request(ControlCommand::Quit, REPLY_TIMEOUT));
+    match requested {
+        Ok(_) if wait_for_exit(profile, pid, Duration::from_secs(timeout)) => {
+            println!("Lx-DOS ({}) stopped", pid);
+            return Ok(());
+        }
+        Ok(_) if !force => {
+            return Err(LxDosError::Message(format!(
+                "Lx-DOS ({}) did not stop within {}s, retry with --force",
+                pid, timeout
+            )));
+        }
+        Err(e) if !force => {
+            return Err(LxDosError::Message(format!(
+                "Failed to reach Lx-DOS ({}) on its control pipe: {}",
+                pid, e
+            )));
+        }
+        _ => {}
+    }
+
+    kill(profile, pid)
+}
+
+/// Kills the children of `pid` first, such as window backends and QEMU, then `pid`
+/// itself.
+fn kill(profile: &Profile, pid: u32) -> Result<(), LxDosError> {
+    // 待っている間に終了していたら、その pid はもう別のプロセスかもしれない
+    if InstanceLock::holder(profile)? != Some(pid) {
+        println!("Lx-DOS ({}) stopped", pid);
+        return Ok(());
+    }
+    for child in process::children_of(pid) {
+        let name = process::name(child).unwrap_or_else(|| "child".to_string());
+        println!("Killing {} ({})", name, child);
+        if let Err(e) = process::send_signal(child, libc::SIGKILL) {
+            log::warn!("Failed to kill {} ({}): {}", name, child, e);
+        }
+    }
+    println!("Killing Lx-DOS ({})", pid);
+    if let Err(e) = process::send_signal(pid, libc::SIGKILL)
+        && process::is_alive(pid)
+    {
+        return Err(e);
+    }
+    wait_for_exit(profile, pid, Duration::from_secs(1));
+    InstanceLock::clear_stale(profile).map(|_| ())
+}
+
+/// Waits until `pid` no longer holds the profile's lock, which the kernel releases
+/// as soon as it exits.
+fn wait_for_exit(profile: &Profile, pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while InstanceLock::holder(profile).is_ok_and(|holder| holder == Some(pid)) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
 }
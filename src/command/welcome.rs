@@ -1,4 +1,237 @@
 use crate::LxDosError;
-pub fn welcome() -> Result<(), LxDosError> {
-    Ok(())
+use crate::modules::lx_dos::config::{
+    ConfigErrors, DisplayMode, GuestConfig, NetworkConfig, SharedFolder, WindowsConfig,
+};
+use crate::modules::lx_dos::qemu;
+use crate::modules::profile::Profile;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+mod terminal;
+mod wizard;
+
+/// Size of the disk image created for a new installation.
+const DEFAULT_DISK_GIB: u32 = 64;
+const DEFAULT_MEMORY_MIB: u32 = 4096;
+const DEFAULT_CPUS: u32 = 2;
+
+/// Where the guest's disk comes from.
+enum DiskSource {
+    /// Install Windows from `iso` onto a new disk image at `disk`.
+    Installer {
+        iso: PathBuf,
+        disk: PathBuf,
+        size_gib: u32,
+    },
+    /// Boot a disk image that already has Windows on it.
+    Existing(PathBuf),
+}
+
+/// What the terminal flow and the wizard ask for.
+struct Answers {
+    source: DiskSource,
+    memory_mib: u32,
+    cpus: u32,
+    shared_folder: Option<SharedFolder>,
+    autostart: bool,
+}
+
+impl Answers {
+    fn defaults(profile: &Profile) -> Self {
+        let host_cpus = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+        Self {
+            source: DiskSource::Installer {
+                iso: PathBuf::new(),
+                disk: profile.default_disk_image().unwrap_or_default(),
+                size_gib: DEFAULT_DISK_GIB,
+            },
+            memory_mib: DEFAULT_MEMORY_MIB,
+            cpus: DEFAULT_CPUS.min(host_cpus),
+            shared_folder: None,
+            autostart: false,
+        }
+    }
+}
+
+pub fn welcome(profile: &Profile) -> Result<(), LxDosError> {
+    let path = profile.config_path()?;
+    if path.exists() {
+        println!(
+            "Lx-DOS profile {} is already set up, its guest definition is {}",
+            profile,
+            path.display()
+        );
+        return profile.mark_first_run_done();
+    }
+    onboard(profile, has_display())
+}
+
+/// Offers the welcome flow the first time `lx-dos start` runs a profile that has no
+/// guest definition.
+pub(super) fn offer(profile: &Profile, headless: bool) -> Result<(), LxDosError> {
+    if profile.config_path()?.exists() || profile.first_run_done()? {
+        return Ok(());
+    }
+    let graphical = !headless && has_display();
+    if !graphical && !io::stdin().is_terminal() {
+        log::info!("No guest definition yet, run `lx-dos welcome` to set one up");
+        return Ok(());
+    }
+    onboard(profile, graphical)
+}
+
+fn onboard(profile: &Profile, graphical: bool) -> Result<(), LxDosError> {
+    let created = if graphical {
+        wizard::run(profile)?
+    } else {
+        terminal::run(profile)?
+    };
+    match created {
+        Some(path) => println!(
+            "Wrote the guest definition of profile {} to {}",
+            profile,
+            path.display()
+        ),
+        None => println!("Skipped, run `lx-dos welcome` to set up a guest later"),
+    }
+    profile.mark_first_run_done()
+}
+
+/// Creates the disk image if needed and writes the guest definition of `profile`.
+fn finish(profile: &Profile, answers: &Answers) -> Result<PathBuf, LxDosError> {
+    let (disk_image, installer, new_disk) = match &answers.source {
+        DiskSource::Installer {
+            iso,
+            disk,
+            size_gib,
+        } => (disk.clone(), Some(iso.clone()), Some(*size_gib)),
+        DiskSource::Existing(disk) => (disk.clone(), None, None),
+    };
+    let config = GuestConfig {
+        name: "Windows".to_string(),
+        disk_image,
+        memory_mib: answers.memory_mib,
+        cpus: answers.cpus,
+        display: DisplayMode::default(),
+        autostart: answers.autostart,
+        installer,
+        network: NetworkConfig::default(),
+        shared_folders: answers.shared_folder.iter().cloned().collect(),
+        windows: WindowsConfig::default(),
+    };
+
+    // 新しく作るディスクはまだ存在しないので、それ以外の問題だけを先に確かめる
+    let path = profile.config_path()?;
+    let errors: Vec<_> = config
+        .validate()
+        .into_iter()
+        .filter(|error| {
+            new_disk.is_none()
+                || !config.disk_image.is_absolute()
+                || error.key.as_deref() != Some("disk_image")
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(LxDosError::Config(ConfigErrors { path, errors }));
+    }
+    let created = match new_disk {
+        Some(size_gib) => qemu::create_disk_image(&config.disk_image, size_gib)?,
+        None => false,
+    };
+
+    let saved = profile.create(None).and_then(|_| {
+        config.save(&path).inspect_err(|_| {
+            let _ = fs::remove_file(&path);
+        })
+    });
+    // やり直せるよう、このときに作ったディスクは消しておく
+    if let Err(e) = saved {
+        if created && let Err(e) = fs::remove_file(&config.disk_image) {
+            log::error!("Failed to remove {}: {}", config.disk_image.display(), e);
+        }
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Whether a graphical session is available for the wizard.
+pub(super) fn has_display() -> bool {
+    ["WAYLAND_DISPLAY", "DISPLAY"]
+        .iter()
+        .any(|var| env::var_os(var).is_some_and(|value| !value.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A scratch directory holding an empty installer image.
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "lx-dos-welcome-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("windows.iso"), b"").unwrap();
+        dir
+    }
+
+    fn rejected_keys(answers: &Answers) -> Vec<Option<String>> {
+        match finish(&Profile::default(), answers) {
+            Err(LxDosError::Config(errors)) => {
+                errors.errors.into_iter().map(|error| error.key).collect()
+            }
+            other => panic!("expected invalid answers, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_new_disk_image_is_not_required_to_exist_yet() {
+        let dir = scratch_dir();
+        let answers = Answers {
+            source: DiskSource::Installer {
+                iso: dir.join("windows.iso"),
+                disk: dir.join("windows.qcow2"),
+                size_gib: DEFAULT_DISK_GIB,
+            },
+            memory_mib: 1,
+            ..Answers::defaults(&Profile::default())
+        };
+        // メモリの問題だけが報告され、ディスクイメージは作られない
+        assert_eq!(rejected_keys(&answers), [Some("memory_mib".to_string())]);
+        assert!(!dir.join("windows.qcow2").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_existing_disk_image_must_exist() {
+        let dir = scratch_dir();
+        let answers = Answers {
+            source: DiskSource::Existing(dir.join("windows.qcow2")),
+            ..Answers::defaults(&Profile::default())
+        };
+        assert_eq!(rejected_keys(&answers), [Some("disk_image".to_string())]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_new_disk_image_needs_an_absolute_path() {
+        let dir = scratch_dir();
+        let answers = Answers {
+            source: DiskSource::Installer {
+                iso: dir.join("windows.iso"),
+                disk: PathBuf::from("windows.qcow2"),
+                size_gib: DEFAULT_DISK_GIB,
+            },
+            ..Answers::defaults(&Profile::default())
+        };
+        assert_eq!(rejected_keys(&answers), [Some("disk_image".to_string())]);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,215 @@
+use super::{Answers, DiskSource};
+use crate::LxDosError;
+use crate::modules::lx_dos::config::SharedFolder;
+use crate::modules::profile::Profile;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Completes file names, which most answers are.
+struct PathHelper(FilenameCompleter);
+
+impl Completer for PathHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        self.0.complete(line, pos, ctx)
+    }
+}
+
+impl Hinter for PathHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PathHelper {}
+
+impl Validator for PathHelper {}
+
+impl Helper for PathHelper {}
+
+type PathEditor = Editor<PathHelper, DefaultHistory>;
+
+/// Asks for the guest definition on the terminal. Returns `None` if the user skips
+/// the setup.
+pub(super) fn run(profile: &Profile) -> Result<Option<PathBuf>, LxDosError> {
+    let mut editor: PathEditor = Editor::new().map_err(|e| LxDosError::Message(e.to_string()))?;
+    editor.set_helper(Some(PathHelper(FilenameCompleter::new())));
+
+    println!("Welcome to Lx-DOS! Profile {} has no guest yet.", profile);
+    if !ask_yes_no(&mut editor, "Set one up now?", true)?.unwrap_or(false) {
+        return Ok(None);
+    }
+    let mut answers = Answers::defaults(profile);
+    loop {
+        if !ask_answers(&mut editor, &mut answers)? {
+            return Ok(None);
+        }
+        match super::finish(profile, &answers) {
+            Ok(path) => return Ok(Some(path)),
+            Err(e) => {
+                println!("{}", e);
+                if !ask_yes_no(&mut editor, "Try again?", true)?.unwrap_or(false) {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Fills `answers`, offering the previous values as defaults. Returns `false` if the
+/// user gave up with Ctrl-C or Ctrl-D.
+fn ask_answers(editor: &mut PathEditor, answers: &mut Answers) -> Result<bool, LxDosError> {
+    let install = matches!(answers.source, DiskSource::Installer { .. });
+    let Some(install) = ask_yes_no(editor, "Install Windows from an ISO image?", install)? else {
+        return Ok(false);
+    };
+    answers.source = if install {
+        let (iso, disk, size_gib) = match &answers.source {
+            DiskSource::Installer {
+                iso,
+                disk,
+                size_gib,
+            } => (iso.clone(), disk.clone(), *size_gib),
+            DiskSource::Existing(_) => (PathBuf::new(), PathBuf::new(), super::DEFAULT_DISK_GIB),
+        };
+        let Some(iso) = ask(editor, "Installer ISO", &iso.to_string_lossy())? else {
+            return Ok(false);
+        };
+        let Some(disk) = ask(editor, "New disk image", &disk.to_string_lossy())? else {
+            return Ok(false);
+        };
+        let Some(size_gib) = ask_number(editor, "Disk size (GiB)", size_gib)? else {
+            return Ok(false);
+        };
+        DiskSource::Installer {
+            iso: PathBuf::from(iso),
+            disk: PathBuf::from(disk),
+            size_gib,
+        }
+    } else {
+        let disk = match &answers.source {
+            DiskSource::Existing(disk) => disk.clone(),
+            DiskSource::Installer { .. } => PathBuf::new(),
+        };
+        let Some(disk) = ask(editor, "Disk image", &disk.to_string_lossy())? else {
+            return Ok(false);
+        };
+        DiskSource::Existing(PathBuf::from(disk))
+    };
+
+    let Some(memory_mib) = ask_number(editor, "Memory (MiB)", answers.memory_mib)? else {
+        return Ok(false);
+    };
+    let Some(cpus) = ask_number(editor, "CPUs", answers.cpus)? else {
+        return Ok(false);
+    };
+    let folder = answers
+        .shared_folder
+        .as_ref()
+        .map(|folder| folder.path.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let Some(folder) = ask(
+        editor,
+        "Folder to share with the guest (empty for none)",
+        &folder,
+    )?
+    else {
+        return Ok(false);
+    };
+    let shared_folder = if folder.is_empty() {
+        None
+    } else {
+        let path = PathBuf::from(folder);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "host".to_string());
+        let Some(name) = ask(editor, "Name of the share in Windows", &name)? else {
+            return Ok(false);
+        };
+        Some(SharedFolder {
+            name,
+            path,
+            read_only: false,
+        })
+    };
+    let Some(autostart) = ask_yes_no(
+        editor,
+        "Boot the guest when Lx-DOS starts?",
+        answers.autostart,
+    )?
+    else {
+        return Ok(false);
+    };
+
+    answers.memory_mib = memory_mib;
+    answers.cpus = cpus;
+    answers.shared_folder = shared_folder;
+    answers.autostart = autostart;
+    Ok(true)
+}
+
+/// Reads one answer, prefilled with `default`. `None` if the user gave up.
+fn ask(
+    editor: &mut PathEditor,
+    question: &str,
+    default: &str,
+) -> Result<Option<String>, LxDosError> {
+    match editor.readline_with_initial(&format!("{}: ", question), (default, "")) {
+        // 端末でなければ初期値は表示されないので、空行を既定値として扱う
+        Ok(line) if line.trim().is_empty() && !io::stdin().is_terminal() => {
+            Ok(Some(default.to_string()))
+        }
+        Ok(line) => Ok(Some(line.trim().to_string())),
+        Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(None),
+        Err(e) => Err(LxDosError::Message(e.to_string())),
+    }
+}
+
+fn ask_yes_no(
+    editor: &mut PathEditor,
+    question: &str,
+    default: bool,
+) -> Result<Option<bool>, LxDosError> {
+    let choices = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        let Some(answer) = ask(editor, &format!("{} {}", question, choices), "")? else {
+            return Ok(None);
+        };
+        match answer.to_ascii_lowercase().as_str() {
+            "" => return Ok(Some(default)),
+            "y" | "yes" => return Ok(Some(true)),
+            "n" | "no" => return Ok(Some(false)),
+            _ => println!("Please answer y or n"),
+        }
+    }
+}
+
+fn ask_number<T: FromStr + ToString>(
+    editor: &mut PathEditor,
+    question: &str,
+    default: T,
+) -> Result<Option<T>, LxDosError> {
+    let default = default.to_string();
+    loop {
+        let Some(answer) = ask(editor, question, &default)? else {
+            return Ok(None);
+        };
+        match answer.parse() {
+            Ok(number) => return Ok(Some(number)),
+            Err(_) => println!("{:?} is not a number", answer),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use super::{Answers, DiskSource};
+use crate::LxDosError;
+use crate::modules::app::App;
+use crate::modules::app::gui::Gui;
+use crate::modules::lx_dos::config::SharedFolder;
+use crate::modules::profile::Profile;
+use gui::gio::prelude::{ApplicationExtManual, FileExt};
+use gui::prelude::*;
+use gui::{gio, glib};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Pages of the wizard, in order.
+const PAGES: &[&str] = &["disk", "resources", "folder"];
+
+/// Widgets holding the answers.
+struct Form {
+    install: gui::CheckButton,
+    iso: gui::Entry,
+    new_disk: gui::Entry,
+    size: gui::SpinButton,
+    disk: gui::Entry,
+    memory: gui::SpinButton,
+    cpus: gui::SpinButton,
+    autostart: gui::CheckButton,
+    share: gui::CheckButton,
+    folder: gui::Entry,
+    folder_name: gui::Entry,
+}
+
+impl Form {
+    fn answers(&self) -> Answers {
+        let source = if self.install.is_active() {
+            DiskSource::Installer {
+                iso: PathBuf::from(self.iso.text().trim()),
+                disk: PathBuf::from(self.new_disk.text().trim()),
+                size_gib: self.size.value_as_int() as u32,
+            }
+        } else {
+            DiskSource::Existing(PathBuf::from(self.disk.text().trim()))
+        };
+        let shared_folder = self.share.is_active().then(|| SharedFolder {
+            name: self.folder_name.text().trim().to_string(),
+            path: PathBuf::from(self.folder.text().trim()),
+            read_only: false,
+        });
+        Answers {
+            source,
+            memory_mib: self.memory.value_as_int() as u32,
+            cpus: self.cpus.value_as_int() as u32,
+            shared_folder,
+            autostart: self.autostart.is_active(),
+        }
+    }
+}
+
+/// Shows the setup wizard until it is finished or closed. Returns `None` if the
+/// user closes it without finishing.
+pub(super) fn run(profile: &Profile) -> Result<Option<PathBuf>, LxDosError> {
+    let created = Rc::new(RefCell::new(None));
+    // 起動中のバックエンドと混ざらないよう、別のアプリケーションIDで動かす
+    let app = gui::Application::builder()
+        .application_id(format!("{}.welcome", App::app_id()))
+        .flags(gio::ApplicationFlags::NON_UNIQUE)
+        .build();
+    let profile = profile.clone();
+    let created_clone = Rc::clone(&created);
+    app.connect_activate(move |app| {
+        window(app, &profile, Rc::clone(&created_clone)).present();
+    });
+    app.run_with_args::<&str>(&[]);
+    let created = created.borrow_mut().take();
+    Ok(created)
+}
+
+fn window(
+    app: &gui::Application,
+    profile: &Profile,
+    created: Rc<RefCell<Option<PathBuf>>>,
+) -> gui::ApplicationWindow {
+    let defaults = Answers::defaults(profile);
+    let content = gui::Box::builder()
+        .orientation(gui::Orientation::Vertical)
+        .spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    let window = Gui::window_builder(app, "Welcome to Lx-DOS")
+        .child(&content)
+        .default_width(560)
+        .build();
+    content.append(&message_label(
+        &format!(
+            "Profile {} has no guest yet. Tell Lx-DOS where Windows comes from and \
+             how much of this computer it may use.",
+            profile
+        ),
+        false,
+    ));
+
+    // ディスクのページ
+    let install = gui::CheckButton::with_label("Install Windows from an ISO image");
+    let existing = gui::CheckButton::with_label("Use a disk image that already has Windows");
+    existing.set_group(Some(&install));
+    install.set_active(true);
+    let iso = gui::Entry::builder().hexpand(true).build();
+    let new_disk = gui::Entry::builder().hexpand(true).build();
+    let size = gui::SpinButton::with_range(16.0, 4096.0, 8.0);
+    let disk = gui::Entry::builder().hexpand(true).sensitive(false).build();
+    if let DiskSource::Installer {
+        disk: default_disk,
+        size_gib,
+        ..
+    } = &defaults.source
+    {
+        new_disk.set_text(&default_disk.to_string_lossy());
+        size.set_value(*size_gib as f64);
+    }
+    let disk_page = grid();
+    disk_page.attach(&install, 0, 0, 3, 1);
+    attach_row(
+        &disk_page,
+        1,
+        "Installer ISO",
+        &iso,
+        Some(browse_button(&iso, false)),
+    );
+    attach_row(&disk_page, 2, "New disk image", &new_disk, None);
+    attach_row(&disk_page, 3, "Disk size (GiB)", &size, None);
+    disk_page.attach(&existing, 0, 4, 3, 1);
+    attach_row(
+        &disk_page,
+        5,
+        "Disk image",
+        &disk,
+        Some(browse_button(&disk, false)),
+    );
+    let install_widgets = [iso.clone(), new_disk.clone()];
+    let size_clone = size.clone();
+    let disk_clone = disk.clone();
+    install.connect_toggled(move |install| {
+        let active = install.is_active();
+        for entry in &install_widgets {
+            entry.set_sensitive(active);
+        }
+        size_clone.set_sensitive(active);
+        disk_clone.set_sensitive(!active);
+    });
+
+    // 資源のページ
+    let memory = gui::SpinButton::with_range(1024.0, 1024.0 * 1024.0, 256.0);
+    memory.set_value(defaults.memory_mib as f64);
+    let host_cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let cpus = gui::SpinButton::with_range(1.0, host_cpus as f64, 1.0);
+    cpus.set_value(defaults.cpus as f64);
+    let autostart = gui::CheckButton::with_label("Boot the guest when Lx-DOS starts");
+    let resources_page = grid();
+    attach_row(&resources_page, 0, "Memory (MiB)", &memory, None);
+    attach_row(&resources_page, 1, "CPUs", &cpus, None);
+    resources_page.attach(&autostart, 1, 2, 2, 1);
+
+    // 共有フォルダのページ
+    let share = gui::CheckButton::with_label("Share a folder of this computer with Windows");
+    let folder = gui::Entry::builder().hexpand(true).sensitive(false).build();
+    let folder_name = gui::Entry::builder().sensitive(false).build();
+    let folder_page = grid();
+    folder_page.attach(&share, 0, 0, 3, 1);
+    attach_row(
+        &folder_page,
+        1,
+        "Folder",
+        &folder,
+        Some(browse_button(&folder, true)),
+    );
+    attach_row(&folder_page, 2, "Name in Windows", &folder_name, None);
+    let folder_clone = folder.clone();
+    let folder_name_clone = folder_name.clone();
+    share.connect_toggled(move |share| {
+        folder_clone.set_sensitive(share.is_active());
+        folder_name_clone.set_sensitive(share.is_active());
+    });
+    let folder_name_clone = folder_name.clone();
+    folder.connect_changed(move |folder| {
+        if folder_name_clone.text().is_empty()
+            && let Some(name) = PathBuf::from(folder.text().as_str()).file_name()
+        {
+            folder_name_clone.set_text(&name.to_string_lossy());
+        }
+    });
+
+    let stack = gui::Stack::new();
+    stack.add_titled(&disk_page, Some(PAGES[0]), "Disk");
+    stack.add_titled(&resources_page, Some(PAGES[1]), "Resources");
+    stack.add_titled(&folder_page, Some(PAGES[2]), "Shared folder");
+    let switcher = gui::StackSwitcher::builder().stack(&stack).build();
+    content.append(&switcher);
+    content.append(&stack);
+
+    let status = message_label("", true);
+    content.append(&status);
+
+    let buttons = gui::Box::builder()
+        .orientation(gui::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gui::Align::End)
+        .build();
+    let skip = gui::Button::with_label("Skip");
+    let back = gui::Button::with_label("Back");
+    let next = gui::Button::with_label("Next");
+    back.set_sensitive(false);
+    buttons.append(&skip);
+    buttons.append(&back);
+    buttons.append(&next);
+    content.append(&buttons);
+
+    let form = Rc::new(Form {
+        install,
+        iso,
+        new_disk,
+        size,
+        disk,
+        memory,
+        cpus,
+        autostart,
+        share,
+        folder,
+        folder_name,
+    });
+
+    // ページが変わったらボタンの表示を合わせる
+    let back_clone = back.clone();
+    let next_clone = next.clone();
+    stack.connect_visible_child_name_notify(move |stack| {
+        let page = current_page(stack);
+        back_clone.set_sensitive(page > 0);
+        next_clone.set_label(if page + 1 == PAGES.len() {
+            "Finish"
+        } else {
+            "Next"
+        });
+    });
+    let stack_clone = stack.clone();
+    back.connect_clicked(move |_| {
+        let page = current_page(&stack_clone);
+        if page > 0 {
+            stack_clone.set_visible_child_name(PAGES[page - 1]);
+        }
+    });
+    let stack_clone = stack.clone();
+    let window_weak = window.downgrade();
+    let profile = profile.clone();
+    next.connect_clicked(move |_| {
+        let page = current_page(&stack_clone);
+        if page + 1 < PAGES.len() {
+            stack_clone.set_visible_child_name(PAGES[page + 1]);
+            return;
+        }
+        match super::finish(&profile, &form.answers()) {
+            Ok(path) => {
+                *created.borrow_mut() = Some(path);
+                if let Some(window) = window_weak.upgrade() {
+                    window.close();
+                }
+            }
+            Err(e) => status.set_text(&e.to_string()),
+        }
+    });
+    let window_weak = window.downgrade();
+    skip.connect_clicked(move |_| {
+        if let Some(window) = window_weak.upgrade() {
+            window.close();
+        }
+    });
+    window
+}
+
+fn current_page(stack: &gui::Stack) -> usize {
+    stack
+        .visible_child_name()
+        .and_then(|name| PAGES.iter().position(|page| *page == name.as_str()))
+        .unwrap_or(0)
+}
+
+fn grid() -> gui::Grid {
+    gui::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .build()
+}
+
+fn attach_row(
+    grid: &gui::Grid,
+    row: i32,
+    label: &str,
+    widget: &impl IsA<gui::Widget>,
+    extra: Option<gui::Button>,
+) {
+    grid.attach(
+        &gui::Label::builder().label(label).xalign(0.0).build(),
+        0,
+        row,
+        1,
+        1,
+    );
+    grid.attach(widget, 1, row, 1, 1);
+    if let Some(extra) = extra {
+        grid.attach(&extra, 2, row, 1, 1);
+    }
+}
+
+/// A button that fills `entry` with a file, or a folder, picked in a dialog.
+fn browse_button(entry: &gui::Entry, folder: bool) -> gui::Button {
+    let button = gui::Button::with_label("Browse…");
+    let entry = entry.clone();
+    let entry_sensitive = entry.clone();
+    button.connect_clicked(move |button| {
+        let dialog = gui::FileDialog::new();
+        let parent = button.root().and_downcast::<gui::Window>();
+        let entry = entry.clone();
+        let picked = move |result: Result<gio::File, glib::Error>| {
+            if let Ok(file) = result
+                && let Some(path) = file.path()
+            {
+                entry.set_text(&path.to_string_lossy());
+            }
+        };
+        if folder {
+            dialog.select_folder(parent.as_ref(), gio::Cancellable::NONE, picked);
+        } else {
+            dialog.open(parent.as_ref(), gio::Cancellable::NONE, picked);
+        }
+    });
+    // 入力欄が無効な間はボタンも押せないようにする
+    entry_sensitive
+        .bind_property("sensitive", &button, "sensitive")
+        .sync_create()
+        .build();
+    button
+}
+
+fn message_label(text: &str, error: bool) -> gui::Label {
+    let label = gui::Label::builder()
+        .label(text)
+        .xalign(0.0)
+        .wrap(true)
+        .selectable(true)
+        .build();
+    if error {
+        label.add_css_class("error");
+    }
+    label
+}
@@ -1,9 +1,22 @@
 use clap::Parser;
+use linux_lx_dos::LxDosError;
 use linux_lx_dos::command;
+use linux_lx_dos::modules::app::instance::PROFILE_ENV;
+use linux_lx_dos::modules::profile::Profile;
 use linux_lx_dos::utils::args::{Args, Commands, InnerArgs, InnerSubCommands};
+use linux_lx_dos::utils::logger;
+use std::process::ExitCode;
 
-fn main() -> Result<(), linux_lx_dos::LxDosError> {
-    if is_frontend() { frontend() } else { backend() }
+fn main() -> ExitCode {
+    let result = if is_frontend() { frontend() } else { backend() };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(LxDosError::Exit(code)) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
 
 fn is_frontend() -> bool {
@@ -27,17 +40,36 @@ fn frontend() -> Result<(), linux_lx_dos::LxDosError> {
         log::LevelFilter::Warn
     };
 
-    env_logger::builder().filter_level(log_level).init();
+    logger::init(log_level, args.log_format);
 
+    let profile = Profile::new(&args.profile)?;
     match args.command {
-        Commands::Start => command::start(),
-        Commands::Stop => command::stop(),
-        Commands::Welcome => command::welcome(),
+        Commands::Start { dry_run: true } => command::dry_run(&profile),
+        Commands::Start { dry_run: false } => command::start(&profile, args.cli),
+        Commands::Stop { timeout, force } => command::stop(&profile, timeout, force),
+        Commands::Status { json } => command::status(&profile, json),
+        Commands::Config { command } => command::config(&profile, command),
+        Commands::Profiles { command } => command::profiles(command),
+        Commands::Shell => command::shell(&profile),
+        Commands::Logs { follow, window } => command::logs(&profile, follow, window),
+        Commands::Welcome => command::welcome(&profile),
+        Commands::Doctor { json } => command::doctor(&profile, json),
+        Commands::Exec { command } => command::exec(&profile, &command),
     }
 }
 fn backend() -> Result<(), linux_lx_dos::LxDosError> {
+    logger::init_from_env();
     let args = InnerArgs::parse();
+    let profile = match std::env::var(PROFILE_ENV) {
+        Ok(name) => Profile::new(&name)?,
+        Err(_) => Profile::default(),
+    };
     match args.command {
-        InnerSubCommands::Window => command::run_backend(&args.pipe_name),
+        InnerSubCommands::Window { window_type } => command::run_backend(
+            &args.pipe_name,
+            args.parent_pid as u32,
+            window_type,
+            &profile,
+        ),
     }
 }
@@ -1,2 +1,3 @@
 pub mod app;
 pub mod lx_dos;
+pub mod profile;
@@ -1,30 +1,51 @@
-use crate::LxDosError;
-use crate::command;
+use crate::modules::lx_dos::LxDos;
+use crate::modules::profile::Profile;
+pub mod control;
 pub mod instance;
+pub mod ipc;
 pub mod messages;
-use crate::utils::args::Args;
-use crate::utils::args::Commands;
 use system_tray::SystemTray;
 pub mod gui;
 #[derive(Default)]
 pub struct App {
+    pub profile: Profile,
     pub windows: instance::WindowManager,
+    pub lx_dos: LxDos,
+    /// Running without tray and windows, see `lx-dos start --cli`.
+    pub headless: bool,
 }
 
 impl App {
-    pub fn exec(&self, args: Args) -> Result<(), LxDosError> {
-        match args.command {
-            Commands::Start => command::start(),
-            Commands::Stop => command::stop(),
-            Commands::Welcome => command::welcome(),
+    pub fn new(profile: Profile) -> Self {
+        Self {
+            windows: instance::WindowManager::new(&profile),
+            lx_dos: LxDos::default(),
+            headless: false,
+            profile,
         }
     }
 
-    pub fn system_tray() -> SystemTray {
-        SystemTray::new(&Self::organization(), &Self::app_id())
+    /// Tray entry of `profile`, named by `profile_app_id`.
+    pub fn system_tray(profile: &Profile) -> SystemTray {
+        SystemTray::new(&Self::organization(), &Self::profile_app_id(profile))
             .icon(include_bytes!("../../public/icon.svg"), "svg")
     }
 
+    /// `app_id` for the default profile, `<app_id>.<profile>` for every other one, so
+    /// that the instances of different profiles do not take each other's place.
+    pub fn profile_app_id(profile: &Profile) -> String {
+        if profile.is_default() {
+            return Self::app_id();
+        }
+        // D-Bus の名前の要素は数字で始められない
+        let separator = if profile.name().starts_with(|c: char| c.is_ascii_digit()) {
+            "._"
+        } else {
+            "."
+        };
+        format!("{}{}{}", Self::app_id(), separator, profile)
+    }
+
     pub fn organization() -> String {
         "LxDos".to_string()
     }
@@ -34,8 +55,20 @@ impl App {
     }
 }
 
-
 /// `App`構造体がスコープを外れてドロップされる際に、管理しているすべてのGUIアプリケーションを終了します。
 impl Drop for App {
     fn drop(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_profile_gets_its_own_application_id() {
+        let id = |name: &str| App::profile_app_id(&Profile::new(name).unwrap());
+        assert_eq!(id("default"), "com.the-infinitys.lx-dos");
+        assert_eq!(id("work"), "com.the-infinitys.lx-dos.work");
+        assert_eq!(id("11"), "com.the-infinitys.lx-dos._11");
+    }
+}
@@ -0,0 +1,594 @@
+use crate::LxDosError;
+use crate::modules::app::instance::restart::WindowHealth;
+use crate::modules::app::instance::{InstanceMessage, WindowInfo, WindowType};
+use crate::modules::app::ipc::{self, Connection, Server};
+use crate::modules::lx_dos::GuestStatus;
+use crate::modules::profile::Profile;
+use crate::utils::process;
+use crossbeam_channel::{Receiver, Sender};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Version of the control protocol, bumped on any incompatible change to the types below.
+pub const CONTROL_PROTOCOL_VERSION: u32 = 5;
+
+/// How long each side waits for the other's `ControlHello`.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// First frame sent by both ends of a control connection, before any request.
+///
+/// Its layout must never change, so that a client and an instance speaking
+/// different protocol versions can tell so before decoding anything else.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct ControlHello {
+    pub protocol: u32,
+    pub binary: String,
+}
+
+impl ControlHello {
+    fn ours() -> Self {
+        Self {
+            protocol: CONTROL_PROTOCOL_VERSION,
+            binary: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Requests accepted by a running `lx-dos start` on its control pipe.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub enum ControlCommand {
+    /// Report the protocol and binary version.
+    Version,
+    /// Report what the instance is doing.
+    Status,
+    /// List the windows managed by the instance.
+    ListWindows,
+    /// Open a window, or bring it to the front if it is already open.
+    OpenWindow(WindowType),
+    /// Ask a window to close.
+    CloseWindow(WindowType),
+    /// Pass a message to the backend of a window and wait for its ack.
+    SendWindow(WindowType, InstanceMessage),
+    /// Boot the guest. Replied to once booting has begun; `GuestStatus` tells how it went.
+    StartGuest,
+    /// Shut the guest down. Replied to once shutting down has begun.
+    StopGuest,
+    /// Pause the guest.
+    SuspendGuest,
+    /// Continue a paused guest.
+    ResumeGuest,
+    /// Report the state of the guest only.
+    GuestStatus,
+    /// Close every managed window and exit.
+    Quit,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ControlRequest {
+    pub id: u64,
+    pub command: ControlCommand,
+}
+
+/// Typed answer to a `ControlCommand`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub enum ControlReply {
+    Ok,
+    Version {
+        protocol: u32,
+        binary: String,
+    },
+    Status(StatusReport),
+    Windows(Vec<WindowInfo>),
+    Guest(GuestStatus),
+    Error {
+        kind: ControlErrorKind,
+        message: String,
+    },
+}
+
+/// Snapshot of a running instance, answered to `ControlCommand::Status`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct StatusReport {
+    pub profile: String,
+    pub pid: u32,
+    pub version: String,
+    pub uptime_secs: u64,
+    pub windows: Vec<WindowInfo>,
+    /// Exit and restart history of window backends that have exited.
+    pub window_health: Vec<WindowHealth>,
+    pub guest: GuestStatus,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ControlErrorKind {
+    /// The peer speaks a different `CONTROL_PROTOCOL_VERSION`.
+    UnsupportedVersion,
+    /// The command was understood but could not be carried out.
+    Failed,
+}
+
+impl std::fmt::Display for ControlErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlErrorKind::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            ControlErrorKind::Failed => write!(f, "request failed"),
+        }
+    }
+}
+
+/// Reply to the `ControlRequest` with the same `id`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ControlResponse {
+    pub id: u64,
+    pub reply: ControlReply,
+}
+
+/// Name of the per-user control pipe served by `lx-dos start` in a profile's runtime
+/// directory.
+pub fn control_pipe_name() -> String {
+    format!("{}_control_{}", ipc::PIPE_PREFIX, process::current_uid())
+}
+
+/// Exit status of a `lx-dos start` that handed its request to an already running instance.
+pub const FORWARDED_EXIT_CODE: u8 = 3;
+
+/// Exclusive lock on `lx-dos.lock` in the profile's runtime directory, holding the pid
+/// of the running `lx-dos start`.
+///
+/// The lock is released by the kernel if the process dies, and the pid is cleared
+/// when the value is dropped. The file itself is never removed: an instance that
+/// opened it just before would then lock a file nobody else can see anymore, while
+/// the next one creates and locks a new one.
+pub struct InstanceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl InstanceLock {
+    pub fn path(profile: &Profile) -> Result<PathBuf, LxDosError> {
+        Ok(profile.runtime_dir()?.join("lx-dos.lock"))
+    }
+
+    /// Takes the lock, or returns `None` if another instance already holds it.
+    pub fn acquire(profile: &Profile) -> Result<Option<Self>, LxDosError> {
+        Self::acquire_at(Self::path(profile)?)
+    }
+
+    /// Pid of the instance holding the lock, or `None` if no instance is running.
+    ///
+    /// The pid in the file alone is not trusted: after a crash it may name a pid
+    /// that has since been reused by an unrelated process.
+    pub fn holder(profile: &Profile) -> Result<Option<u32>, LxDosError> {
+        Self::holder_at(&Self::path(profile)?)
+    }
+
+    /// Reads the pid written by the last instance, without checking that it still runs.
+    pub fn read_pid(profile: &Profile) -> Result<Option<u32>, LxDosError> {
+        match fs::read_to_string(Self::path(profile)?) {
+            Ok(content) => Ok(content.trim().parse().ok()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(LxDosError::Io(e)),
+        }
+    }
+
+    /// Clears the pid left behind by an instance that died, returning whether there
+    /// was one. A lock that is still held is left alone.
+    pub fn clear_stale(profile: &Profile) -> Result<bool, LxDosError> {
+        Self::clear_stale_at(&Self::path(profile)?)
+    }
+
+    fn acquire_at(path: PathBuf) -> Result<Option<Self>, LxDosError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(LxDosError::Io(e)),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Some(Self { path, file }))
+    }
+
+    fn holder_at(path: &Path) -> Result<Option<u32>, LxDosError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(LxDosError::Io(e)),
+        };
+        match file.try_lock_shared() {
+            Ok(()) => return Ok(None),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(LxDosError::Io(e)),
+        }
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(content.trim().parse().ok())
+    }
+
+    fn clear_stale_at(path: &Path) -> Result<bool, LxDosError> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(LxDosError::Io(e)),
+        };
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(false),
+            Err(TryLockError::Error(e)) => return Err(LxDosError::Io(e)),
+        }
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        if content.trim().is_empty() {
+            return Ok(false);
+        }
+        file.set_len(0)?;
+        Ok(true)
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // ロックを持ったまま pid だけ消す。ファイルを消すと別の inode で二重起動できてしまう
+        if let Err(e) = self.file.set_len(0) {
+            log::error!("Failed to clear lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Accepts connections on the control pipe and hands their requests to the main loop.
+///
+/// Each client is read on its own thread; requests arrive on `requests`.
+pub struct ControlServer {
+    profile: Profile,
+    name: String,
+    requests: Receiver<IncomingRequest>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    pub fn start(profile: &Profile) -> Result<Self, LxDosError> {
+        let name = control_pipe_name();
+        let server = Server::start(profile, &name)?;
+        let (tx, requests) = crossbeam_channel::unbounded();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_stopped = Arc::clone(&stopped);
+        thread::spawn(move || {
+            loop {
+                let connection = match server.accept() {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::error!("Control pipe stopped accepting: {}", e);
+                        break;
+                    }
+                };
+                if accept_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                log::debug!("New client connected to control pipe");
+                let tx = tx.clone();
+                thread::spawn(move || serve_client(connection, tx));
+            }
+        });
+        Ok(Self {
+            profile: profile.clone(),
+            name,
+            requests,
+            stopped,
+        })
+    }
+
+    /// Requests received from all clients.
+    ///
+    /// Clients speaking another protocol version are turned away after the
+    /// `ControlHello` exchange, so their requests never show up here.
+    pub fn requests(&self) -> &Receiver<IncomingRequest> {
+        &self.requests
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        // 受付スレッドを起こして終了させ、ソケットを削除させる
+        self.stopped.store(true, Ordering::SeqCst);
+        ipc::wake(&self.profile, &self.name);
+    }
+}
+
+fn serve_client(connection: Connection, requests: Sender<IncomingRequest>) {
+    let peer = match connection.recv_timeout::<ControlHello>(HANDSHAKE_TIMEOUT) {
+        Ok(Some(peer)) => peer,
+        // ipc::wake の接続は何も送らずに切断する
+        Ok(None) => return,
+        Err(e) => {
+            log::debug!("Removing control client during handshake: {}", e);
+            return;
+        }
+    };
+    // 相手が古くても新しくても判断できるように、まず自分の版を返す
+    if let Err(e) = connection.send(&ControlHello::ours()) {
+        log::debug!("Removing control client during handshake: {}", e);
+        return;
+    }
+    if peer.protocol != CONTROL_PROTOCOL_VERSION {
+        log::warn!(
+            "Refusing control client lx-dos {} speaking protocol {}, expected {}",
+            peer.binary,
+            peer.protocol,
+            CONTROL_PROTOCOL_VERSION
+        );
+        connection.shutdown();
+        return;
+    }
+
+    loop {
+        let request = match connection.recv::<ControlRequest>() {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("Removing control client: {}", e);
+                break;
+            }
+        };
+        let incoming = IncomingRequest {
+            id: request.id,
+            command: request.command,
+            connection: connection.clone(),
+        };
+        if requests.send(incoming).is_err() {
+            break;
+        }
+    }
+}
+
+/// A request received by `ControlServer`, answered through `reply`.
+pub struct IncomingRequest {
+    pub id: u64,
+    pub command: ControlCommand,
+    connection: Connection,
+}
+
+impl IncomingRequest {
+    pub fn reply(&self, reply: ControlReply) -> Result<(), LxDosError> {
+        self.connection
+            .send(&ControlResponse { id: self.id, reply })
+    }
+}
+
+/// Connection to the control pipe of a running `lx-dos start`.
+pub struct ControlClient {
+    connection: Connection,
+    next_id: u64,
+}
+
+impl ControlClient {
+    /// Connects and exchanges `ControlHello` frames.
+    ///
+    /// An instance speaking another protocol version is reported as
+    /// `ControlErrorKind::UnsupportedVersion`.
+    pub fn connect(profile: &Profile) -> Result<Self, LxDosError> {
+        let connection = Connection::connect(profile, &control_pipe_name())?;
+        Self::handshake(connection)
+    }
+
+    fn handshake(connection: Connection) -> Result<Self, LxDosError> {
+        connection.send(&ControlHello::ours())?;
+        let peer: ControlHello = connection.recv_timeout(HANDSHAKE_TIMEOUT)?.ok_or_else(|| {
+            LxDosError::Message("Control pipe closed during handshake".to_string())
+        })?;
+        if peer.protocol != CONTROL_PROTOCOL_VERSION {
+            connection.shutdown();
+            return Err(LxDosError::Message(format!(
+                "{}: Lx-DOS {} speaks control protocol {}, this binary speaks {}",
+                ControlErrorKind::UnsupportedVersion,
+                peer.binary,
+                peer.protocol,
+                CONTROL_PROTOCOL_VERSION
+            )));
+        }
+        Ok(Self {
+            connection,
+            next_id: 1,
+        })
+    }
+
+    /// Keeps retrying until `timeout` elapses, for an instance that has taken the lock
+    /// but not yet opened its control pipe.
+    pub fn connect_timeout(profile: &Profile, timeout: Duration) -> Result<Self, LxDosError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match Self::connect(profile) {
+                Err(LxDosError::Io(_)) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends `command` and waits up to `timeout` for its reply.
+    ///
+    /// `ControlReply::Error` is turned into an `Err`.
+    pub fn request(
+        &mut self,
+        command: ControlCommand,
+        timeout: Duration,
+    ) -> Result<ControlReply, LxDosError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connection.send(&ControlRequest { id, command })?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let response = match self.connection.recv_timeout::<ControlResponse>(remaining) {
+                Ok(Some(response)) => response,
+                Ok(None) => {
+                    return Err(LxDosError::Message(
+                        "Control pipe closed before replying".to_string(),
+                    ));
+                }
+                Err(LxDosError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if response.id != id {
+                log::debug!("Ignoring reply to stale control request {}", response.id);
+                continue;
+            }
+            return match response.reply {
+                ControlReply::Error { kind, message } => {
+                    Err(LxDosError::Message(format!("{}: {}", kind, message)))
+                }
+                reply => Ok(reply),
+            };
+        }
+        Err(LxDosError::Message(format!(
+            "No reply to control request {} within {:?}",
+            id, timeout
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(protocol: u32) -> ControlHello {
+        ControlHello {
+            protocol,
+            binary: "0.0.0-test".to_string(),
+        }
+    }
+
+    #[test]
+    fn client_refuses_instance_with_other_version() {
+        for protocol in [CONTROL_PROTOCOL_VERSION - 1, CONTROL_PROTOCOL_VERSION + 1] {
+            let (client, instance) = Connection::pair().unwrap();
+            let fake = thread::spawn(move || {
+                let peer: ControlHello = instance.recv().unwrap().unwrap();
+                instance.send(&hello(protocol)).unwrap();
+                peer
+            });
+            let error = match ControlClient::handshake(client) {
+                Ok(_) => panic!("accepted protocol {}", protocol),
+                Err(e) => e.to_string(),
+            };
+            assert!(
+                error.starts_with("unsupported protocol version"),
+                "{}",
+                error
+            );
+            assert_eq!(fake.join().unwrap(), ControlHello::ours());
+        }
+    }
+
+    #[test]
+    fn instance_refuses_client_with_other_version() {
+        let (client, instance) = Connection::pair().unwrap();
+        let (tx, requests) = crossbeam_channel::unbounded();
+        let server = thread::spawn(move || serve_client(instance, tx));
+        client.send(&hello(CONTROL_PROTOCOL_VERSION + 1)).unwrap();
+        let reply: ControlHello = client.recv().unwrap().unwrap();
+        assert_eq!(reply, ControlHello::ours());
+        // 版が違うので、要求を送っても読まれずに切断される
+        let _ = client.send(&ControlRequest {
+            id: 1,
+            command: ControlCommand::Status,
+        });
+        assert!(client.recv::<ControlResponse>().unwrap_or(None).is_none());
+        server.join().unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_reach_the_instance_after_handshake() {
+        let (client, instance) = Connection::pair().unwrap();
+        let (tx, requests) = crossbeam_channel::unbounded();
+        thread::spawn(move || serve_client(instance, tx));
+        let answer = thread::spawn(move || {
+            let request = requests.recv().unwrap();
+            assert!(matches!(request.command, ControlCommand::Version));
+            request.reply(ControlReply::Ok).unwrap();
+        });
+        let mut client = ControlClient::handshake(client).unwrap();
+        let reply = client
+            .request(ControlCommand::Version, Duration::from_secs(2))
+            .unwrap();
+        assert!(matches!(reply, ControlReply::Ok));
+        answer.join().unwrap();
+    }
+
+    fn lock_path() -> PathBuf {
+        use std::sync::atomic::AtomicUsize;
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lx-dos-lock-test-{}-{}.lock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn lock_is_exclusive_and_names_its_holder() {
+        let path = lock_path();
+        assert_eq!(InstanceLock::holder_at(&path).unwrap(), None);
+        let lock = InstanceLock::acquire_at(path.clone()).unwrap().unwrap();
+        assert!(InstanceLock::acquire_at(path.clone()).unwrap().is_none());
+        assert_eq!(
+            InstanceLock::holder_at(&path).unwrap(),
+            Some(std::process::id())
+        );
+        assert!(!InstanceLock::clear_stale_at(&path).unwrap());
+
+        drop(lock);
+        // ファイルは残り、pid だけが消える
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(InstanceLock::holder_at(&path).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lock_file_is_never_replaced() {
+        let path = lock_path();
+        let first = InstanceLock::acquire_at(path.clone()).unwrap().unwrap();
+        // 二つ目の start は、一つ目が終わる直前にファイルを開いた
+        let racing = File::open(&path).unwrap();
+        drop(first);
+        let second = InstanceLock::acquire_at(path.clone()).unwrap().unwrap();
+        // 同じ inode なので、遅れてきた方はロックを取れない
+        assert!(matches!(racing.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(second);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_pid_is_cleared_in_place() {
+        let path = lock_path();
+        assert!(!InstanceLock::clear_stale_at(&path).unwrap());
+        // 強制終了された start は pid を残す
+        fs::write(&path, "4194304").unwrap();
+        assert_eq!(InstanceLock::holder_at(&path).unwrap(), None);
+        assert!(InstanceLock::clear_stale_at(&path).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(!InstanceLock::clear_stale_at(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
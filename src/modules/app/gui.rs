@@ -1,20 +1,33 @@
 use super::App;
-use gui::{builders::ApplicationWindowBuilder, gio::prelude::ApplicationExtManual};
+use super::instance::WindowType;
+use crate::LxDosError;
+use crate::modules::profile::Profile;
+use gui::glib::prelude::ToVariant;
+use gui::{builders::ApplicationWindowBuilder, gio, gio::prelude::ApplicationExtManual};
 pub struct Gui {
     gui: gui::Application,
 }
 impl Default for Gui {
     fn default() -> Self {
-        Self::new()
+        Self::new(&WindowType::Main, &Profile::default())
     }
 }
 
 impl Gui {
     // GUIアプリケーションをビルドします。
-    pub fn new() -> Self {
-        let flags = gui::gio::ApplicationFlags::HANDLES_OPEN;
+    // プロファイルごとに別の ID を使い、他のプロファイルのバックエンドに転送されないようにする
+    pub fn new(window_type: &WindowType, profile: &Profile) -> Self {
+        let mut flags = gui::gio::ApplicationFlags::HANDLES_OPEN;
+        let app_id = match window_type {
+            WindowType::Main => App::profile_app_id(profile),
+            // メインウィンドウのバックエンドに転送されないよう、別のIDで独立して動かす
+            WindowType::Settings => {
+                flags |= gui::gio::ApplicationFlags::NON_UNIQUE;
+                format!("{}.settings", App::profile_app_id(profile))
+            }
+        };
         let gui = gui::Application::builder()
-            .application_id(App::app_id())
+            .application_id(app_id)
             .flags(flags)
             .build();
         Self { gui }
@@ -46,4 +59,50 @@ impl Gui {
     pub fn run(&self) {
         self.gui.run();
     }
+
+    /// Whether a StatusNotifier host, which shows tray icons, is running on the
+    /// session bus.
+    pub fn tray_host_available() -> Result<bool, LxDosError> {
+        const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+        let dbus_error = |e: gui::glib::Error| LxDosError::Message(e.to_string());
+        let bus =
+            gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE).map_err(dbus_error)?;
+        let call = |bus_name, path, interface, method, parameters: gui::glib::Variant| {
+            bus.call_sync(
+                Some(bus_name),
+                path,
+                interface,
+                method,
+                Some(&parameters),
+                None,
+                gio::DBusCallFlags::NONE,
+                1000,
+                gio::Cancellable::NONE,
+            )
+            .map_err(dbus_error)
+        };
+        let has_owner = call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "NameHasOwner",
+            (WATCHER,).to_variant(),
+        )?;
+        if has_owner.get::<(bool,)>() != Some((true,)) {
+            return Ok(false);
+        }
+        // ウォッチャーがいても、ホストが登録されていなければアイコンは表示されない
+        let registered = call(
+            WATCHER,
+            "/StatusNotifierWatcher",
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            (WATCHER, "IsStatusNotifierHostRegistered").to_variant(),
+        )?;
+        Ok(registered
+            .child_value(0)
+            .as_variant()
+            .and_then(|value| value.get::<bool>())
+            .unwrap_or(false))
+    }
 }
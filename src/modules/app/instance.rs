@@ -1,11 +1,25 @@
+use super::ipc::{self, Connection, Server};
 use crate::LxDosError;
-use instance_pipe::{Client, Event, Server};
+use crate::modules::profile::Profile;
+use crate::utils::logger;
+use crate::utils::logs::{self, RotatingLog};
+use crate::utils::process;
+use crossbeam_channel::{Receiver, Sender};
 use std::collections::HashMap;
 use std::env;
-use std::process::{Child, Command, Stdio};
+use std::io;
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+pub mod restart;
+use restart::{RestartPolicy, RestartTracker, WindowExit, WindowHealth};
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq, Hash)]
+#[derive(
+    Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq, Hash, clap::ValueEnum,
+)]
 pub enum WindowType {
     Main,
     Settings,
@@ -35,283 +49,1097 @@ pub enum InstanceMessage {
     RestoreWindow {
         pipe_name: String,
     },
+    /// The guest definition was saved from a window and should be reloaded.
+    ConfigChanged {
+        pipe_name: String,
+    },
 }
 
-#[derive(Clone)]
-pub struct WindowServer {
-    server: Arc<Mutex<Server>>,
-    child: Arc<Mutex<Option<Child>>>,
-    clients: Arc<Mutex<Vec<Client>>>,
+/// Name of the pipe the frontend with pid `pid` serves the window `window_type` on.
+pub fn window_pipe_name(pid: u32, window_type: &WindowType) -> String {
+    format!(
+        "{}_pipe_{}_{}",
+        ipc::PIPE_PREFIX,
+        pid,
+        window_type.to_string().to_ascii_lowercase()
+    )
 }
 
-impl WindowServer {
-    pub fn new(server: Server, child: Option<Child>) -> Self {
+/// Pid of the frontend serving the window pipe `pipe_name`, if the name is one
+/// `WindowManager` gives its pipes.
+pub fn window_pipe_owner(pipe_name: &str) -> Option<u32> {
+    let rest = pipe_name.strip_prefix(&format!("{}_pipe_", ipc::PIPE_PREFIX))?;
+    rest.split('_').next()?.parse().ok()
+}
+
+/// Log file that the backends of `window_type` write their output to.
+pub fn log_path(profile: &Profile, window_type: &WindowType) -> Result<PathBuf, LxDosError> {
+    Ok(profile.log_dir()?.join(format!(
+        "{}.log",
+        window_type.to_string().to_ascii_lowercase()
+    )))
+}
+
+/// Answer of the other side to an `InstanceMessage`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub enum WindowAck {
+    Done,
+    Failed(String),
+    /// The receiver does not handle this message.
+    Unsupported,
+}
+
+/// How long `PendingCommand::wait` waits for the backend to answer.
+pub const WINDOW_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Version of the window pipe protocol, bumped on any incompatible change to
+/// `WindowFrame` or the types it carries.
+pub const WINDOW_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features this binary supports on the window pipe, announced in `Hello`.
+pub const WINDOW_CAPABILITIES: &[&str] =
+    &["ack", "config-changed", "maximize", "minimize", "restore"];
+
+/// Capabilities both ends must announce: the backend reports saved settings with
+/// `ConfigChanged` and the frontend relies on every request being acked.
+const REQUIRED_CAPABILITIES: &[&str] = &["ack", "config-changed"];
+
+/// How long each side waits for the other's `Hello`.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First frame sent by both ends of a window pipe.
+///
+/// Its layout must never change, so that any two binaries can at least tell each
+/// other apart.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u32,
+    pub binary: String,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    fn ours() -> Self {
         Self {
-            server: Arc::new(Mutex::new(server)),
-            child: Arc::new(Mutex::new(child)),
-            clients: Arc::new(Mutex::new(Vec::new())),
+            protocol: WINDOW_PROTOCOL_VERSION,
+            binary: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: WINDOW_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
         }
     }
 
-    pub fn poll_event(&self) -> Result<Vec<InstanceMessage>, LxDosError> {
-        let mut server = self
-            .server
-            .lock()
-            .map_err(|e| LxDosError::Message(e.to_string()))?;
-        let mut messages = Vec::new();
-
-        match server.poll_event() {
-            Ok(Some(Event::ConnectionAccepted(client))) => {
-                println!("New client connected to server");
-                let mut clients = self
-                    .clients
-                    .lock()
-                    .map_err(|e| LxDosError::Message(e.to_string()))?;
-                clients.push(client.clone());
-            }
-            Ok(Some(Event::MessageReceived(_))) => {
-                println!("Unexpected MessageReceived event in server");
-            }
-            Ok(Some(Event::MessageSent)) => {
-                println!("Server sent a message");
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Environment variable through which a backend receives the token of its pipe.
+pub const TOKEN_ENV: &str = "LXDOS_TOKEN";
+
+/// Environment variable naming the profile a backend belongs to. GApplication parses
+/// the backend's arguments and rejects options it does not know, such as `--profile`.
+pub const PROFILE_ENV: &str = "LXDOS_PROFILE";
+
+/// Sent by the backend right after the `Hello` exchange, proving it was spawned by
+/// the frontend.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Auth {
+    token: String,
+}
+
+/// A frame on a window pipe, after the `Hello` exchange. Every `Request` is answered by an `Ack` with the same `id`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+enum WindowFrame {
+    Request { id: u64, message: InstanceMessage },
+    Ack { id: u64, ack: WindowAck },
+}
+
+/// A message received from the other side, to be answered with `WindowClient::ack`.
+#[derive(Debug, Clone)]
+pub struct WindowRequest {
+    pub id: u64,
+    pub message: InstanceMessage,
+}
+
+/// Description of a managed window, as reported on the control pipe.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct WindowInfo {
+    pub window_type: WindowType,
+    pub pipe_name: String,
+    pub child_pid: Option<u32>,
+    pub uptime_secs: u64,
+    /// Seconds since the backend last sent a message, if it ever did.
+    pub last_message_secs: Option<u64>,
+}
+
+/// Something that happened to a managed window, delivered through `WindowManager::events`.
+#[derive(Debug)]
+pub struct WindowEvent {
+    pub window_type: WindowType,
+    /// Tells apart successive windows of the same type.
+    pub id: u64,
+    pub kind: WindowEventKind,
+}
+
+#[derive(Debug)]
+pub enum WindowEventKind {
+    Message(WindowRequest),
+    /// The backend hung up its pipe.
+    Disconnected,
+    /// The backend process exited.
+    Exited,
+}
+
+/// Frontend side of a window: the backend process and the pipe it connects to.
+///
+/// Only the spawned backend is accepted: its pid and uid are checked with
+/// `SO_PEERCRED` and it must present the token passed in `TOKEN_ENV`. Other clients
+/// are dropped.
+pub struct WindowServer {
+    profile: Profile,
+    pipe_name: String,
+    stopped: Arc<AtomicBool>,
+    /// Whether an `Exited` event will be sent when the backend exits.
+    exit_watched: bool,
+    child: Mutex<Option<Child>>,
+    client: Arc<Mutex<Option<WindowClient>>>,
+}
+
+impl WindowServer {
+    /// Listens on the pipe `pipe_name` of `profile`, spawns the backend with `command`
+    /// and reports what happens to it on `events`.
+    pub fn start(
+        window_type: WindowType,
+        id: u64,
+        profile: &Profile,
+        pipe_name: &str,
+        mut command: Command,
+        events: Sender<WindowEvent>,
+    ) -> Result<Self, LxDosError> {
+        // 子プロセスが接続する前にパイプを用意しておく
+        let server = Server::start(profile, pipe_name)?;
+        let token = new_token()?;
+        let child = command.env(TOKEN_ENV, &token).spawn()?;
+        let pid = child.id();
+        let client = Arc::new(Mutex::new(None));
+
+        let event = |kind| WindowEvent {
+            window_type: window_type.clone(),
+            id,
+            kind,
+        };
+        let exited = event(WindowEventKind::Exited);
+        let exit_events = events.clone();
+        let exit_watched = match process::on_exit(pid, move || {
+            let _ = exit_events.send(exited);
+        }) {
+            Ok(()) => true,
+            Err(e) => {
+                // The pipe hanging up still tells us when the backend is gone.
+                log::warn!("Cannot watch window backend ({}) for exit: {}", pid, e);
+                false
             }
-            Ok(None) => {}
-            Err(e) => return Err(LxDosError::Io(e)),
-        }
+        };
 
-        let mut clients = self
-            .clients
-            .lock()
-            .map_err(|e| LxDosError::Message(e.to_string()))?;
-        let mut i = 0;
-        while i < clients.len() {
-            match clients[i].poll_event::<InstanceMessage>() {
-                Ok(Some(Event::MessageReceived(message))) => {
-                    messages.push(message);
-                    i += 1;
-                }
-                Ok(Some(Event::MessageSent)) => {
-                    println!("Client sent a message");
-                    i += 1;
-                }
-                Ok(Some(Event::ConnectionAccepted(_))) => {
-                    println!("Unexpected connection event in client");
-                    i += 1;
+        let client_slot = Arc::clone(&client);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_stopped = Arc::clone(&stopped);
+        let disconnected = event(WindowEventKind::Disconnected);
+        thread::spawn(move || {
+            let client = loop {
+                let connection = match server.accept() {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::error!(
+                            "Failed to accept window backend for {:?}: {}",
+                            window_type,
+                            e
+                        );
+                        let _ = events.send(disconnected);
+                        return;
+                    }
+                };
+                if accept_stopped.load(Ordering::SeqCst) {
+                    return;
                 }
-                Ok(None) => {
-                    i += 1;
+                match WindowClient::accept(connection, pid, &token) {
+                    Ok(client) => break client,
+                    // 認証できない接続は切断して待ち続ける
+                    Err(e) => log::warn!("Refusing client on {:?} pipe: {}", window_type, e),
                 }
-                Err(e) => {
-                    println!("Removing disconnected client: {}", e);
-                    clients.remove(i);
+            };
+            // Only the backend may connect, so stop listening right away.
+            drop(server);
+            if let Ok(mut slot) = client_slot.lock() {
+                *slot = Some(client.clone());
+            }
+            loop {
+                match client.recv() {
+                    Ok(Some(request)) => {
+                        let message = WindowEvent {
+                            window_type: window_type.clone(),
+                            id,
+                            kind: WindowEventKind::Message(request),
+                        };
+                        if events.send(message).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("Pipe of {:?} failed: {}", window_type, e);
+                        break;
+                    }
                 }
             }
-        }
+            let _ = events.send(disconnected);
+        });
 
-        Ok(messages)
+        Ok(Self {
+            profile: profile.clone(),
+            pipe_name: pipe_name.to_string(),
+            stopped,
+            exit_watched,
+            child: Mutex::new(Some(child)),
+            client,
+        })
     }
 
-    // 子プロセスが終了したかをチェックする新しいメソッド
-    pub fn check_child_status(&self) -> Result<bool, LxDosError> {
-        if let Ok(mut child_lock) = self.child.lock() {
-            if let Some(child) = child_lock.as_mut() {
-                match child.try_wait() {
-                    Ok(Some(status)) => {
-                        println!("Child process exited with status: {}", status);
-                        Ok(true)
-                    }
-                    Ok(None) => Ok(false), // まだ実行中
-                    Err(e) => Err(LxDosError::Io(e)),
-                }
-            } else {
-                Ok(false) // Child process handle doesn't exist
+    /// The backend's end of the pipe, once it has connected.
+    pub fn client(&self) -> Result<WindowClient, LxDosError> {
+        self.client
+            .lock()
+            .map_err(|e| LxDosError::Message(e.to_string()))?
+            .clone()
+            .ok_or_else(|| {
+                LxDosError::Message(format!(
+                    "Backend of {} has not connected yet",
+                    self.pipe_name
+                ))
+            })
+    }
+
+    pub fn child_id(&self) -> Option<u32> {
+        self.child.lock().ok()?.as_ref().map(Child::id)
+    }
+
+    /// Takes the backend's piped stdout and stderr.
+    pub fn take_output(&self) -> Option<(ChildStdout, ChildStderr)> {
+        let mut child = self.child.lock().ok()?;
+        let child = child.as_mut()?;
+        Some((child.stdout.take()?, child.stderr.take()?))
+    }
+
+    /// Collects the exit status of the backend, killing it first if it still runs.
+    pub fn reap(&self) -> Option<ExitStatus> {
+        let mut child = self.child.lock().ok()?.take()?;
+        if let Ok(None) = child.try_wait()
+            && let Err(e) = child.kill()
+        {
+            log::error!("Failed to kill child process: {}", e);
+        }
+        match child.wait() {
+            Ok(status) => Some(status),
+            Err(e) => {
+                log::error!("Failed to wait for child process: {}", e);
+                None
             }
-        } else {
-            Err(LxDosError::Message(
-                "Failed to lock child mutex".to_string(),
-            ))
         }
     }
 }
 
 impl Drop for WindowServer {
     fn drop(&mut self) {
-        if let Ok(mut child) = self.child.lock() {
-            if let Some(mut child) = child.take() {
-                if let Err(e) = child.kill() {
-                    log::error!("Failed to kill child process: {}", e);
-                }
-                if let Err(e) = child.wait() {
-                    log::error!("Failed to wait for child process: {}", e);
-                }
-            }
+        self.stopped.store(true, Ordering::SeqCst);
+        match self.client.lock() {
+            Ok(client) if client.is_some() => client.iter().for_each(WindowClient::shutdown),
+            // Nobody connected yet: wake the acceptor so its thread can end.
+            _ => ipc::wake(&self.profile, &self.pipe_name),
         }
+        self.reap();
     }
 }
 
+/// One end of a window's pipe, used by the backend and, through `WindowServer`, by
+/// the frontend.
+///
+/// Acks are matched to their `send_and_wait` call by the thread reading `recv`, so
+/// somebody has to keep calling it.
 #[derive(Clone)]
 pub struct WindowClient {
-    client: Arc<Mutex<Client>>,
+    connection: Connection,
+    peer: Arc<Hello>,
+    next_id: Arc<AtomicU64>,
+    pending: Arc<Mutex<HashMap<u64, Sender<WindowAck>>>>,
 }
 
 impl WindowClient {
-    pub fn new(client: Client) -> Self {
-        Self {
-            client: Arc::new(Mutex::new(client)),
+    /// Exchanges `Hello` frames and refuses a peer speaking another protocol version
+    /// or lacking one of `REQUIRED_CAPABILITIES`.
+    fn handshake(connection: Connection) -> Result<Self, LxDosError> {
+        let ours = Hello::ours();
+        connection.send(&ours)?;
+        let peer: Hello = connection
+            .recv_timeout(HANDSHAKE_TIMEOUT)?
+            .ok_or_else(|| LxDosError::Message("Peer hung up during handshake".to_string()))?;
+        let missing: Vec<String> = REQUIRED_CAPABILITIES
+            .iter()
+            .filter(|capability| !peer.supports(capability))
+            .map(|capability| capability.to_string())
+            .collect();
+        if peer.protocol != ours.protocol || !missing.is_empty() {
+            connection.shutdown();
+            return Err(LxDosError::IncompatiblePeer {
+                ours: ours.protocol,
+                theirs: peer.protocol,
+                binary: peer.binary,
+                missing,
+            });
         }
+        Ok(Self {
+            connection,
+            peer: Arc::new(peer),
+            next_id: Arc::new(AtomicU64::new(1)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Authenticates a connection accepted by the frontend for the backend `child_pid`.
+    fn accept(connection: Connection, child_pid: u32, token: &str) -> Result<Self, LxDosError> {
+        verify_peer(&connection, child_pid)?;
+        let client = Self::handshake(connection)?;
+        let auth: Auth = client
+            .connection
+            .recv_timeout(HANDSHAKE_TIMEOUT)?
+            .ok_or_else(|| LxDosError::Message("Peer hung up during handshake".to_string()))?;
+        if !tokens_match(&auth.token, token) {
+            client.shutdown();
+            return Err(LxDosError::Unauthenticated("wrong token".to_string()));
+        }
+        Ok(client)
+    }
+
+    /// Connects a backend to the pipe of `profile` served by the frontend `parent_pid`,
+    /// proving itself with the `token` it was spawned with.
+    pub fn connect(
+        profile: &Profile,
+        pipe_name: &str,
+        parent_pid: u32,
+        token: &str,
+    ) -> Result<Self, LxDosError> {
+        let connection = Connection::connect(profile, pipe_name)?;
+        verify_peer(&connection, parent_pid)?;
+        let client = Self::handshake(connection)?;
+        client.connection.send(&Auth {
+            token: token.to_string(),
+        })?;
+        Ok(client)
+    }
+
+    /// What the other side announced in its `Hello`.
+    pub fn peer(&self) -> &Hello {
+        &self.peer
     }
 
+    /// Sends `message` without waiting for its ack.
     pub fn send(&self, message: &InstanceMessage) -> Result<(), LxDosError> {
-        let client = self
-            .client
-            .lock()
-            .map_err(|e| LxDosError::Message(e.to_string()))?;
-        client.send(message)?;
-        Ok(())
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connection.send(&WindowFrame::Request {
+            id,
+            message: message.clone(),
+        })
     }
 
-    pub fn poll_event(&self) -> Result<Vec<InstanceMessage>, LxDosError> {
-        let mut client = self
-            .client
-            .lock()
-            .map_err(|e| LxDosError::Message(e.to_string()))?;
-        let mut messages = Vec::new();
-        match client.poll_event::<InstanceMessage>() {
-            Ok(Some(Event::MessageReceived(message))) => {
-                messages.push(message);
-                Ok(messages)
-            }
-            Ok(Some(Event::ConnectionAccepted(_))) => {
-                println!("Unexpected connection event in client");
-                Ok(messages)
-            }
-            Ok(Some(Event::MessageSent)) => {
-                println!("Client sent a message");
-                Ok(messages)
+    /// Sends `message` and waits up to `timeout` for the other side to ack it.
+    pub fn send_and_wait(
+        &self,
+        message: &InstanceMessage,
+        timeout: Duration,
+    ) -> Result<WindowAck, LxDosError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.lock_pending()?.insert(id, tx);
+        let result = self
+            .connection
+            .send(&WindowFrame::Request {
+                id,
+                message: message.clone(),
+            })
+            .and_then(|()| match rx.recv_timeout(timeout) {
+                Ok(ack) => Ok(ack),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => Err(LxDosError::Message(
+                    format!("No ack for {:?} within {:?}", message, timeout),
+                )),
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Err(LxDosError::Message(
+                    format!("Pipe closed before {:?} was acked", message),
+                )),
+            });
+        self.lock_pending()?.remove(&id);
+        result
+    }
+
+    /// Answers the request `id` received from `recv`.
+    pub fn ack(&self, id: u64, ack: WindowAck) -> Result<(), LxDosError> {
+        self.connection.send(&WindowFrame::Ack { id, ack })
+    }
+
+    /// Blocks until the other side sends a request; `None` once it hung up.
+    ///
+    /// Acks received meanwhile are handed to the matching `send_and_wait`.
+    pub fn recv(&self) -> Result<Option<WindowRequest>, LxDosError> {
+        loop {
+            match self.connection.recv::<WindowFrame>() {
+                Ok(Some(WindowFrame::Request { id, message })) => {
+                    return Ok(Some(WindowRequest { id, message }));
+                }
+                Ok(Some(WindowFrame::Ack { id, ack })) => {
+                    // 待っている呼び出しがなければ send で送ったメッセージの ack
+                    if let Some(waiter) = self.lock_pending()?.remove(&id) {
+                        let _ = waiter.send(ack);
+                    }
+                }
+                result => {
+                    // 相手がいなくなったので待っている呼び出しをすべて起こす
+                    self.lock_pending()?.clear();
+                    return result.map(|_| None);
+                }
             }
-            Ok(None) => Ok(messages),
-            Err(e) => Err(LxDosError::Io(e)),
         }
     }
+
+    /// Hangs up, waking up a thread blocked in `recv`.
+    pub fn shutdown(&self) {
+        self.connection.shutdown();
+    }
+
+    /// Hangs up once the frontend `parent_pid` exits, so that a backend whose
+    /// frontend crashed sees `recv` end instead of lingering as an orphan.
+    pub fn shutdown_when_exits(&self, parent_pid: u32) -> Result<(), LxDosError> {
+        let connection = self.connection.clone();
+        process::on_exit(parent_pid, move || {
+            log::info!("Frontend {} exited, shutting down", parent_pid);
+            connection.shutdown();
+        })
+    }
+
+    fn lock_pending(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<u64, Sender<WindowAck>>>, LxDosError> {
+        self.pending
+            .lock()
+            .map_err(|e| LxDosError::Message(e.to_string()))
+    }
 }
 
 pub struct Window {
+    pub id: u64,
     pub pipe_name: String,
     pub server: WindowServer,
-    pub client: WindowClient,
+    pub opened_at: Instant,
+    pub last_message_at: Option<Instant>,
+    /// Set once the window was asked to close, so its exit is not treated as a crash.
+    pub closing: bool,
 }
 
 pub struct WindowManager {
-    pipe_name: String,
+    profile: Profile,
     windows: HashMap<WindowType, Window>,
+    next_id: u64,
+    events_tx: Sender<WindowEvent>,
+    events_rx: Receiver<WindowEvent>,
+    restarts: RestartTracker,
 }
 
 impl Default for WindowManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(&Profile::default())
     }
 }
 
 impl WindowManager {
-    pub fn new() -> Self {
-        let pipe_name = format!("lxdos_pipe_{}", std::process::id());
+    pub fn new(profile: &Profile) -> Self {
+        let (events_tx, events_rx) = crossbeam_channel::unbounded();
         Self {
-            pipe_name,
+            profile: profile.clone(),
             windows: HashMap::new(),
+            next_id: 1,
+            events_tx,
+            events_rx,
+            restarts: RestartTracker::default(),
         }
     }
 
-    pub fn poll_event(&mut self) -> Result<Vec<InstanceMessage>, LxDosError> {
-        let mut messages = Vec::new();
-        let mut windows_to_close = Vec::new();
+    /// Events of all managed windows, to be passed to `handle_event`.
+    pub fn events(&self) -> Receiver<WindowEvent> {
+        self.events_rx.clone()
+    }
 
-        // 終了した子プロセスや切断されたパイプを検知
-        for (window_type, window) in &self.windows {
-            match window.server.poll_event() {
-                Ok(mut new_messages) => {
-                    for message in &new_messages {
-                        if let InstanceMessage::CloseWindow { .. } = message {
-                            windows_to_close.push(window_type.clone());
-                        }
+    /// Updates the window bookkeeping and returns the message carried by `event`, if any.
+    ///
+    /// Events of windows that have already been removed are ignored.
+    pub fn handle_event(&mut self, event: WindowEvent) -> Option<InstanceMessage> {
+        let window = self
+            .windows
+            .get_mut(&event.window_type)
+            .filter(|window| window.id == event.id)?;
+        let message = match event.kind {
+            WindowEventKind::Message(request) => {
+                window.last_message_at = Some(Instant::now());
+                // フロントエンドが受け取るのは通知だけなので、受信したことを返す
+                let ack = match request.message {
+                    InstanceMessage::CloseWindow { .. } | InstanceMessage::ConfigChanged { .. } => {
+                        WindowAck::Done
                     }
-                    messages.append(&mut new_messages);
-                }
-                Err(e) => {
-                    println!("Server for {:?} disconnected: {}", window_type, e);
-                    windows_to_close.push(window_type.clone());
+                    _ => WindowAck::Unsupported,
+                };
+                if let Err(e) = window
+                    .server
+                    .client()
+                    .and_then(|client| client.ack(request.id, ack))
+                {
+                    log::warn!("Failed to ack {:?}: {}", event.window_type, e);
                 }
+                request.message
             }
-
-            if window.server.check_child_status()? {
-                println!("Child process for {:?} exited.", window_type);
-                windows_to_close.push(window_type.clone());
+            // 終了ステータスは Exited で拾うので、監視できている間は待つ
+            WindowEventKind::Disconnected if window.server.exit_watched => {
+                log::debug!("Backend of {:?} disconnected", event.window_type);
+                return None;
             }
-        }
+            WindowEventKind::Disconnected | WindowEventKind::Exited => {
+                let window = self.windows.remove(&event.window_type)?;
+                let exit = WindowExit::from_status(window.server.reap());
+                log::info!("Child process for {:?} exited: {}", event.window_type, exit);
+                if !window.closing {
+                    self.restarts.record_exit(&event.window_type, exit);
+                }
+                return None;
+            }
+        };
 
         // CloseWindowメッセージに基づいてウィンドウを削除
-        for closed_window_type in windows_to_close {
-            println!(
+        if let InstanceMessage::CloseWindow { .. } = message {
+            log::debug!(
                 "Cleaning up resources for closed window: {:?}",
-                closed_window_type
+                event.window_type
             );
-            self.windows.remove(&closed_window_type);
+            self.windows.remove(&event.window_type);
         }
-
-        Ok(messages)
+        Some(message)
     }
-    // WindowManager::open_window メソッドの修正
+
+    /// Opens a window on request, which also re-arms a restart policy that gave up.
     pub fn open_window(&mut self, window_type: WindowType) -> Result<(), LxDosError> {
+        self.restarts.reset(&window_type);
+        self.spawn_window(window_type)
+    }
+
+    fn spawn_window(&mut self, window_type: WindowType) -> Result<(), LxDosError> {
         if self.windows.contains_key(&window_type) {
-            println!("Window of type {:?} is already open", window_type);
+            log::debug!("Window of type {:?} is already open", window_type);
             return Ok(());
         }
 
         let current_exe = env::current_exe()?;
         let pid = std::process::id().to_string();
-        let child_pipe_name = format!(
-            "{}_{}",
-            self.pipe_name,
-            window_type.to_string().to_ascii_lowercase()
-        );
+        let child_pipe_name = window_pipe_name(std::process::id(), &window_type);
 
         // 子プロセスを起動する際に、子プロセス用のパイプ名を引数として渡す
-        let child = Command::new(current_exe)
+        let mut command = Command::new(current_exe);
+        command
             .env("LXDOS_BACKEND", &pid)
+            .env(PROFILE_ENV, self.profile.name())
             .arg(&pid)
-            .arg(&child_pipe_name) // ここで子プロセス用のパイプ名を渡す
+            .arg(&child_pipe_name)
             .arg("window")
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()?;
+            .arg(window_type.to_string().to_ascii_lowercase())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        logger::propagate(&mut command);
+        let log = RotatingLog::open(&log_path(&self.profile, &window_type)?)?;
 
-        // 親プロセスは子プロセス用のパイプでサーバーを起動し、子からの接続を待つ
-        let server = Server::start(&child_pipe_name)?;
-
-        // メインプロセスは子プロセスにメッセージを送るためのクライアントを起動する
-        let new_window_client = Client::start(&child_pipe_name)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let server = WindowServer::start(
+            window_type.clone(),
+            id,
+            &self.profile,
+            &child_pipe_name,
+            command,
+            self.events_tx.clone(),
+        )?;
+        // バックエンドの出力はウィンドウごとのログファイルへ
+        if let Some((stdout, stderr)) = server.take_output() {
+            let source = logs::Source {
+                window: window_type.to_string().to_ascii_lowercase(),
+                pid: server.child_id().unwrap_or_default(),
+            };
+            logs::capture(log, source, stdout, stderr);
+        }
 
         let new_window = Window {
+            id,
             pipe_name: child_pipe_name,
-            server: WindowServer::new(server, Some(child)),
-            client: WindowClient::new(new_window_client),
+            server,
+            opened_at: Instant::now(),
+            last_message_at: None,
+            closing: false,
         };
 
         self.windows.insert(window_type, new_window);
 
         Ok(())
     }
+    pub fn windows(&self) -> Vec<WindowInfo> {
+        self.windows
+            .iter()
+            .map(|(window_type, window)| WindowInfo {
+                window_type: window_type.clone(),
+                pipe_name: window.pipe_name.clone(),
+                child_pid: window.server.child_id(),
+                uptime_secs: window.opened_at.elapsed().as_secs(),
+                last_message_secs: window.last_message_at.map(|at| at.elapsed().as_secs()),
+            })
+            .collect()
+    }
+
+    /// Asks a single window to close; it is removed once its backend reports back or exits.
+    ///
+    /// The request goes out with `PendingCommand::wait`, see `send_window_command`.
+    pub fn close_window(&mut self, window_type: WindowType) -> Result<PendingCommand, LxDosError> {
+        let window = self.windows.get_mut(&window_type).ok_or_else(|| {
+            LxDosError::Message(format!("Window of type {:?} is not open", window_type))
+        })?;
+        window.closing = true;
+        let pipe_name = window.pipe_name.clone();
+        self.send_window_command(window_type, InstanceMessage::CloseWindow { pipe_name })
+    }
+
+    /// Asks every window to close and waits up to `timeout` for the backends to exit.
+    ///
+    /// Backends still running afterwards are killed when their `WindowServer` is dropped.
+    pub fn close_all(&mut self, timeout: Duration) -> Result<(), LxDosError> {
+        for (window_type, window) in &mut self.windows {
+            window.closing = true;
+            let message = InstanceMessage::CloseWindow {
+                pipe_name: window.pipe_name.clone(),
+            };
+            if let Err(e) = window
+                .server
+                .client()
+                .and_then(|client| client.send(&message))
+            {
+                log::warn!("Failed to send CloseWindow to {:?}: {}", window_type, e);
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        while !self.windows.is_empty() {
+            match self.events_rx.recv_deadline(deadline) {
+                Ok(event) => {
+                    self.handle_event(event);
+                }
+                Err(_) => break,
+            }
+        }
+        self.windows.clear();
+        Ok(())
+    }
+    pub fn set_restart_policy(&mut self, window_type: WindowType, policy: RestartPolicy) {
+        self.restarts.set_policy(window_type, policy);
+    }
+
+    /// When the next crashed backend is due to be restarted.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.restarts.next_restart()
+    }
+
+    /// Restarts the backends whose backoff has elapsed.
+    pub fn restart_due(&mut self) {
+        for window_type in self.restarts.take_due() {
+            if let Err(e) = self.spawn_window(window_type.clone()) {
+                log::error!("Failed to restart {:?}: {}", window_type, e);
+                self.restarts
+                    .record_exit(&window_type, WindowExit::from_status(None));
+            }
+        }
+    }
+
+    pub fn health(&self) -> Vec<WindowHealth> {
+        self.restarts.health()
+    }
+
+    /// One-line warning for the tray about windows that keep crashing, if any.
+    pub fn crash_notice(&self) -> Option<String> {
+        let mut notices: Vec<String> = self
+            .health()
+            .into_iter()
+            .filter(|health| health.gave_up)
+            .map(|health| match health.last_exit {
+                Some(exit) => format!("{} window keeps crashing ({})", health.window_type, exit),
+                None => format!("{} window keeps crashing", health.window_type),
+            })
+            .collect();
+        notices.sort();
+        (!notices.is_empty()).then(|| notices.join(", "))
+    }
+
+    /// Addresses `command` to a window. It is sent by `PendingCommand::wait`, which
+    /// blocks until the backend answers and so belongs on another thread.
     pub fn send_window_command(
         &self,
         window_type: WindowType,
         command: InstanceMessage,
-    ) -> Result<(), LxDosError> {
-        if let Some(window) = self.windows.get(&window_type) {
-            window.client.send(&command)?;
-            return Ok(());
+    ) -> Result<PendingCommand, LxDosError> {
+        let Some(window) = self.windows.get(&window_type) else {
+            return Err(LxDosError::Message(format!(
+                "No client found for window of type {:?}",
+                window_type
+            )));
+        };
+        Ok(PendingCommand {
+            client: window.server.client()?,
+            window_type,
+            command,
+        })
+    }
+}
+
+/// A command for a window backend, returned by `WindowManager::send_window_command`.
+pub struct PendingCommand {
+    window_type: WindowType,
+    command: InstanceMessage,
+    client: WindowClient,
+}
+
+impl PendingCommand {
+    /// Sends the command and waits up to `WINDOW_ACK_TIMEOUT` for the backend to
+    /// carry it out.
+    ///
+    /// A `Failed` or `Unsupported` ack is turned into an `Err`.
+    pub fn wait(self) -> Result<(), LxDosError> {
+        let Self {
+            window_type,
+            command,
+            client,
+        } = self;
+        match client.send_and_wait(&command, WINDOW_ACK_TIMEOUT)? {
+            WindowAck::Done => Ok(()),
+            WindowAck::Failed(reason) => Err(LxDosError::Message(format!(
+                "{:?} failed {:?}: {}",
+                window_type, command, reason
+            ))),
+            WindowAck::Unsupported => Err(LxDosError::Message(format!(
+                "{:?} does not support {:?}",
+                window_type, command
+            ))),
+        }
+    }
+}
+
+/// Checks that the other end of `connection` is process `pid` of the current user.
+fn verify_peer(connection: &Connection, pid: u32) -> Result<(), LxDosError> {
+    let peer = connection.peer_credentials()?;
+    if peer.uid != process::current_uid() {
+        return Err(LxDosError::Unauthenticated(format!(
+            "peer runs as uid {}",
+            peer.uid
+        )));
+    }
+    if peer.pid != pid {
+        return Err(LxDosError::Unauthenticated(format!(
+            "peer is pid {}, expected {}",
+            peer.pid, pid
+        )));
+    }
+    Ok(())
+}
+
+/// 32 hex digits from the kernel's random pool.
+fn new_token() -> Result<String, LxDosError> {
+    let mut bytes = [0u8; 16];
+    // SAFETY: `bytes` is valid for writes of its whole length.
+    let read = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+    if read != bytes.len() as isize {
+        return Err(LxDosError::Io(io::Error::last_os_error()));
+    }
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compares without stopping at the first difference, so timing reveals nothing.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the other end of a window pipe: sends `hello` and returns the `Hello` it
+    /// received in turn.
+    fn peer(connection: Connection, hello: Hello) -> thread::JoinHandle<Option<Hello>> {
+        thread::spawn(move || {
+            connection.send(&hello).unwrap();
+            connection.recv_timeout(HANDSHAKE_TIMEOUT).unwrap()
+        })
+    }
+
+    fn hello(protocol: u32, capabilities: &[&str]) -> Hello {
+        Hello {
+            protocol,
+            binary: "9.9.9".to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn refused(connection: Connection) -> LxDosError {
+        match WindowClient::handshake(connection) {
+            Ok(_) => panic!("handshake succeeded"),
+            Err(err) => err,
+        }
+    }
+
+    fn connected(connection: Connection) -> WindowClient {
+        match WindowClient::handshake(connection) {
+            Ok(client) => client,
+            Err(err) => panic!("handshake failed: {}", err),
+        }
+    }
+
+    fn incompatible(err: LxDosError) -> (u32, Vec<String>) {
+        match err {
+            LxDosError::IncompatiblePeer {
+                ours,
+                theirs,
+                binary,
+                missing,
+            } => {
+                assert_eq!(ours, WINDOW_PROTOCOL_VERSION);
+                assert_eq!(binary, "9.9.9");
+                (theirs, missing)
+            }
+            err => panic!("expected IncompatiblePeer, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn handshake_accepts_the_same_protocol() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let peer = peer(theirs, Hello::ours());
+        let client = WindowClient::handshake(ours).unwrap();
+        assert!(client.peer().supports("maximize"));
+        assert_eq!(peer.join().unwrap(), Some(Hello::ours()));
+    }
+
+    #[test]
+    fn handshake_refuses_an_older_protocol() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let older = hello(WINDOW_PROTOCOL_VERSION - 1, WINDOW_CAPABILITIES);
+        let peer = peer(theirs.clone(), older);
+        let err = refused(ours);
+        assert_eq!(incompatible(err), (WINDOW_PROTOCOL_VERSION - 1, Vec::new()));
+        peer.join().unwrap();
+        // 拒否した側は切断する
+        assert!(theirs.recv::<WindowFrame>().unwrap_or(None).is_none());
+    }
+
+    #[test]
+    fn handshake_refuses_a_newer_protocol() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let newer = hello(WINDOW_PROTOCOL_VERSION + 1, WINDOW_CAPABILITIES);
+        let peer = peer(theirs, newer);
+        let err = refused(ours);
+        assert_eq!(incompatible(err), (WINDOW_PROTOCOL_VERSION + 1, Vec::new()));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_refuses_a_peer_missing_a_required_capability() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let lacking = hello(WINDOW_PROTOCOL_VERSION, &["ack", "maximize"]);
+        let peer = peer(theirs, lacking);
+        let err = refused(ours);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Peer lx-dos 9.9.9 speaks window protocol {0} without config-changed, \
+                 this binary speaks {0}",
+                WINDOW_PROTOCOL_VERSION
+            )
+        );
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_lists_every_missing_capability() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let peer = peer(theirs, hello(WINDOW_PROTOCOL_VERSION, &[]));
+        let err = refused(ours);
+        let (_, missing) = incompatible(err);
+        assert_eq!(missing, REQUIRED_CAPABILITIES);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_fails_if_the_peer_hangs_up() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        drop(theirs);
+        refused(ours);
+    }
+
+    #[test]
+    fn accept_checks_the_token() {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let backend = thread::spawn(move || {
+            let client = WindowClient::handshake(theirs).unwrap();
+            client
+                .connection
+                .send(&Auth {
+                    token: "wrong".to_string(),
+                })
+                .unwrap();
+        });
+        let err = WindowClient::accept(ours, std::process::id(), "right").err();
+        assert!(
+            matches!(err, Some(LxDosError::Unauthenticated(_))),
+            "{:?}",
+            err
+        );
+        backend.join().unwrap();
+    }
+
+    /// A frontend and a backend that finished their handshake. The frontend's acks
+    /// are read on a thread of its own, as `WindowServer` does.
+    fn client_pair() -> (WindowClient, WindowClient) {
+        let (ours, theirs) = Connection::pair().unwrap();
+        let backend_side = thread::spawn(move || connected(theirs));
+        let frontend = connected(ours);
+        let backend = backend_side.join().unwrap();
+        let reader = frontend.clone();
+        thread::spawn(move || while let Ok(Some(_)) = reader.recv() {});
+        (frontend, backend)
+    }
+
+    fn close(pipe_name: &str) -> InstanceMessage {
+        InstanceMessage::CloseWindow {
+            pipe_name: pipe_name.to_string(),
         }
-        Err(LxDosError::Message(format!(
-            "No client found for window of type {:?}",
-            window_type
-        )))
+    }
+
+    fn send_and_wait(
+        client: &WindowClient,
+        message: InstanceMessage,
+        timeout: Duration,
+    ) -> thread::JoinHandle<Result<WindowAck, LxDosError>> {
+        let client = client.clone();
+        thread::spawn(move || client.send_and_wait(&message, timeout))
+    }
+
+    #[test]
+    fn acks_are_matched_to_their_request_by_id() {
+        let (frontend, backend) = client_pair();
+        let first = send_and_wait(&frontend, close("first"), Duration::from_secs(5));
+        let first_request = backend.recv().unwrap().unwrap();
+        let second = send_and_wait(&frontend, close("second"), Duration::from_secs(5));
+        let second_request = backend.recv().unwrap().unwrap();
+        assert!(matches!(
+            first_request.message,
+            InstanceMessage::CloseWindow { pipe_name } if pipe_name == "first"
+        ));
+
+        // 届いた順とは逆に答える
+        backend
+            .ack(second_request.id, WindowAck::Failed("busy".to_string()))
+            .unwrap();
+        backend.ack(first_request.id, WindowAck::Done).unwrap();
+        assert_eq!(first.join().unwrap().unwrap(), WindowAck::Done);
+        assert_eq!(
+            second.join().unwrap().unwrap(),
+            WindowAck::Failed("busy".to_string())
+        );
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let (frontend, backend) = client_pair();
+        let started = Instant::now();
+        let err = frontend
+            .send_and_wait(&close("main"), Duration::from_millis(200))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("No ack for"), "{}", err);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(frontend.lock_pending().unwrap().is_empty());
+
+        // 遅れて届いた ack は誰も待っていないので捨てられる
+        let request = backend.recv().unwrap().unwrap();
+        backend.ack(request.id, WindowAck::Done).unwrap();
+        let next = send_and_wait(&frontend, close("main"), Duration::from_secs(5));
+        let request = backend.recv().unwrap().unwrap();
+        backend.ack(request.id, WindowAck::Unsupported).unwrap();
+        assert_eq!(next.join().unwrap().unwrap(), WindowAck::Unsupported);
+    }
+
+    #[test]
+    fn waiters_wake_up_when_the_peer_hangs_up() {
+        let (frontend, backend) = client_pair();
+        let waiting = send_and_wait(&frontend, close("main"), Duration::from_secs(60));
+        backend.recv().unwrap().unwrap();
+        let started = Instant::now();
+        backend.shutdown();
+        let err = waiting.join().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("Pipe closed before"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn pending_commands_report_what_the_backend_answered() {
+        let (frontend, backend) = client_pair();
+        let answers = thread::spawn(move || {
+            for ack in [WindowAck::Done, WindowAck::Unsupported] {
+                let request = backend.recv().unwrap().unwrap();
+                backend.ack(request.id, ack).unwrap();
+            }
+        });
+        let pending = |message| PendingCommand {
+            window_type: WindowType::Settings,
+            command: message,
+            client: frontend.clone(),
+        };
+        pending(close("settings")).wait().unwrap();
+        let err = pending(InstanceMessage::MaximizeWindow {
+            pipe_name: "settings".to_string(),
+        })
+        .wait()
+        .unwrap_err();
+        assert!(err.to_string().contains("does not support"), "{}", err);
+        answers.join().unwrap();
+    }
+
+    #[test]
+    fn backend_hangs_up_when_its_frontend_dies() {
+        // sleep をフロントエンドに見立てて殺す
+        let mut frontend = Command::new("sleep").arg("60").spawn().unwrap();
+        let (ours, theirs) = Connection::pair().unwrap();
+        let frontend_side = thread::spawn(move || connected(ours));
+        let backend = connected(theirs);
+        let _frontend_client = frontend_side.join().unwrap();
+        backend.shutdown_when_exits(frontend.id()).unwrap();
+
+        let (tx, hung_up) = crossbeam_channel::bounded(1);
+        let reader = backend.clone();
+        thread::spawn(move || {
+            let _ = tx.send(reader.recv().map(|request| request.is_none()));
+        });
+        assert!(hung_up.recv_timeout(Duration::from_millis(200)).is_err());
+
+        frontend.kill().unwrap();
+        frontend.wait().unwrap();
+        let hung_up = hung_up
+            .recv_timeout(Duration::from_secs(5))
+            .expect("backend still waits for its dead frontend");
+        assert!(hung_up.unwrap());
     }
 }
@@ -0,0 +1,343 @@
+use super::WindowType;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+/// Delay before restarting a backend after its first crash, doubled for every
+/// further crash.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+/// A backend crashing this many times within `CRASH_WINDOW` is not restarted again
+/// until the window is opened by hand.
+const MAX_CRASHES: usize = 5;
+const CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+/// What to do when a window backend exits without being asked to close.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    /// Restart after a non-zero exit or a signal, with backoff.
+    OnFailure,
+    /// Restart after any exit, with backoff. Clean exits count as crashes, so a
+    /// backend that keeps quitting is given up on like one that keeps failing.
+    Always,
+}
+
+impl RestartPolicy {
+    /// Policy used when the guest definition does not set one.
+    pub fn default_for(window_type: &WindowType) -> Self {
+        match window_type {
+            WindowType::Main => RestartPolicy::OnFailure,
+            WindowType::Settings => RestartPolicy::Never,
+        }
+    }
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// How a window backend ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct WindowExit {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl WindowExit {
+    pub fn from_status(status: Option<ExitStatus>) -> Self {
+        Self {
+            code: status.and_then(|status| status.code()),
+            signal: status.and_then(|status| status.signal()),
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.code != Some(0)
+    }
+}
+
+impl std::fmt::Display for WindowExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit code {}", code),
+            (None, Some(signal)) => write!(f, "killed by signal {}", signal),
+            (None, None) => write!(f, "unknown exit status"),
+        }
+    }
+}
+
+/// Restart bookkeeping of one window type, as reported on the control pipe.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct WindowHealth {
+    pub window_type: WindowType,
+    pub restart: RestartPolicy,
+    /// Exits within the last minute that count toward giving up.
+    pub crashes: usize,
+    pub last_exit: Option<WindowExit>,
+    pub last_exit_secs: Option<u64>,
+    /// Seconds until the pending restart, if one is scheduled.
+    pub restart_in_secs: Option<u64>,
+    /// Restarts stopped because the backend kept crashing.
+    pub gave_up: bool,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    crashes: Vec<Instant>,
+    last_exit: Option<(WindowExit, Instant)>,
+    restart_at: Option<Instant>,
+    gave_up: bool,
+}
+
+/// Decides when crashed backends are restarted, per `WindowType`.
+#[derive(Debug, Default)]
+pub struct RestartTracker {
+    policies: HashMap<WindowType, RestartPolicy>,
+    history: HashMap<WindowType, History>,
+}
+
+impl RestartTracker {
+    pub fn policy(&self, window_type: &WindowType) -> RestartPolicy {
+        self.policies
+            .get(window_type)
+            .copied()
+            .unwrap_or_else(|| RestartPolicy::default_for(window_type))
+    }
+
+    pub fn set_policy(&mut self, window_type: WindowType, policy: RestartPolicy) {
+        self.policies.insert(window_type, policy);
+    }
+
+    /// Records an unexpected exit and schedules a restart if the policy asks for one.
+    pub fn record_exit(&mut self, window_type: &WindowType, exit: WindowExit) {
+        self.record_exit_at(window_type, exit, Instant::now());
+    }
+
+    fn record_exit_at(&mut self, window_type: &WindowType, exit: WindowExit, now: Instant) {
+        let policy = self.policy(window_type);
+        let history = self.history.entry(window_type.clone()).or_default();
+        history.last_exit = Some((exit, now));
+        history
+            .crashes
+            .retain(|at| now.duration_since(*at) < CRASH_WINDOW);
+        if exit.is_failure() || policy == RestartPolicy::Always {
+            history.crashes.push(now);
+        }
+
+        let restart = match policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => exit.is_failure(),
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            return;
+        }
+        if history.crashes.len() >= MAX_CRASHES {
+            log::warn!(
+                "{:?} exited {} times within {:?}, not restarting it",
+                window_type,
+                history.crashes.len(),
+                CRASH_WINDOW
+            );
+            history.gave_up = true;
+            history.restart_at = None;
+            return;
+        }
+        let backoff = match history.crashes.len() {
+            0 => RESTART_BACKOFF,
+            crashes => RESTART_BACKOFF
+                .saturating_mul(1 << (crashes - 1).min(16))
+                .min(MAX_RESTART_BACKOFF),
+        };
+        log::info!("Restarting {:?} in {:?} ({})", window_type, backoff, exit);
+        history.restart_at = Some(now + backoff);
+    }
+
+    /// Forgets crashes and pending restarts, for a window opened by hand.
+    pub fn reset(&mut self, window_type: &WindowType) {
+        if let Some(history) = self.history.get_mut(window_type) {
+            history.crashes.clear();
+            history.restart_at = None;
+            history.gave_up = false;
+        }
+    }
+
+    /// When the earliest pending restart is due.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.history
+            .values()
+            .filter_map(|history| history.restart_at)
+            .min()
+    }
+
+    /// Takes the window types whose restart is due.
+    pub fn take_due(&mut self) -> Vec<WindowType> {
+        let now = Instant::now();
+        self.history
+            .iter_mut()
+            .filter(|(_, history)| history.restart_at.is_some_and(|at| at <= now))
+            .map(|(window_type, history)| {
+                history.restart_at = None;
+                window_type.clone()
+            })
+            .collect()
+    }
+
+    /// Window types that have exited at least once.
+    pub fn health(&self) -> Vec<WindowHealth> {
+        let now = Instant::now();
+        self.history
+            .iter()
+            .map(|(window_type, history)| WindowHealth {
+                window_type: window_type.clone(),
+                restart: self.policy(window_type),
+                crashes: history
+                    .crashes
+                    .iter()
+                    .filter(|at| now.duration_since(**at) < CRASH_WINDOW)
+                    .count(),
+                last_exit: history.last_exit.map(|(exit, _)| exit),
+                last_exit_secs: history
+                    .last_exit
+                    .map(|(_, at)| now.duration_since(at).as_secs()),
+                restart_in_secs: history
+                    .restart_at
+                    .map(|at| at.saturating_duration_since(now).as_secs()),
+                gave_up: history.gave_up,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CRASHED: WindowExit = WindowExit {
+        code: None,
+        signal: Some(libc::SIGSEGV),
+    };
+    const CLEAN: WindowExit = WindowExit {
+        code: Some(0),
+        signal: None,
+    };
+
+    fn tracker(policy: RestartPolicy) -> RestartTracker {
+        let mut tracker = RestartTracker::default();
+        tracker.set_policy(WindowType::Main, policy);
+        tracker
+    }
+
+    /// Delay until the restart scheduled by an exit at `now`.
+    fn backoff(tracker: &RestartTracker, now: Instant) -> Option<Duration> {
+        Some(tracker.history[&WindowType::Main].restart_at? - now)
+    }
+
+    fn gave_up(tracker: &RestartTracker) -> bool {
+        tracker.history[&WindowType::Main].gave_up
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_crash() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let start = Instant::now();
+        let mut delays = Vec::new();
+        for i in 0..MAX_CRASHES as u64 - 1 {
+            let now = start + Duration::from_secs(i);
+            tracker.record_exit_at(&WindowType::Main, CRASHED, now);
+            delays.push(backoff(&tracker, now).unwrap().as_secs());
+        }
+        assert_eq!(delays, [1, 2, 4, 8]);
+        assert!(!gave_up(&tracker));
+    }
+
+    #[test]
+    fn gives_up_after_max_crashes() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let now = Instant::now();
+        for _ in 0..MAX_CRASHES {
+            tracker.record_exit_at(&WindowType::Main, CRASHED, now);
+        }
+        assert!(gave_up(&tracker));
+        assert_eq!(tracker.next_restart(), None);
+    }
+
+    #[test]
+    fn crashes_expire_after_the_crash_window() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let start = Instant::now();
+        for _ in 0..MAX_CRASHES - 1 {
+            tracker.record_exit_at(&WindowType::Main, CRASHED, start);
+        }
+        let later = start + CRASH_WINDOW;
+        tracker.record_exit_at(&WindowType::Main, CRASHED, later);
+        assert!(!gave_up(&tracker));
+        assert_eq!(backoff(&tracker, later), Some(RESTART_BACKOFF));
+    }
+
+    #[test]
+    fn reset_forgets_crashes() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let now = Instant::now();
+        for _ in 0..MAX_CRASHES {
+            tracker.record_exit_at(&WindowType::Main, CRASHED, now);
+        }
+        tracker.reset(&WindowType::Main);
+        assert!(!gave_up(&tracker));
+        tracker.record_exit_at(&WindowType::Main, CRASHED, now);
+        assert_eq!(backoff(&tracker, now), Some(RESTART_BACKOFF));
+    }
+
+    #[test]
+    fn on_failure_ignores_clean_exits() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let now = Instant::now();
+        for _ in 0..MAX_CRASHES {
+            tracker.record_exit_at(&WindowType::Main, CLEAN, now);
+        }
+        assert_eq!(backoff(&tracker, now), None);
+        assert!(!gave_up(&tracker));
+        assert!(tracker.history[&WindowType::Main].crashes.is_empty());
+    }
+
+    #[test]
+    fn always_counts_clean_exits_toward_giving_up() {
+        let mut tracker = tracker(RestartPolicy::Always);
+        let now = Instant::now();
+        tracker.record_exit_at(&WindowType::Main, CLEAN, now);
+        assert_eq!(backoff(&tracker, now), Some(RESTART_BACKOFF));
+        tracker.record_exit_at(&WindowType::Main, CLEAN, now);
+        assert_eq!(backoff(&tracker, now), Some(RESTART_BACKOFF * 2));
+        for _ in 2..MAX_CRASHES {
+            tracker.record_exit_at(&WindowType::Main, CLEAN, now);
+        }
+        assert!(gave_up(&tracker));
+    }
+
+    #[test]
+    fn never_does_not_restart() {
+        let mut tracker = tracker(RestartPolicy::Never);
+        let now = Instant::now();
+        tracker.record_exit_at(&WindowType::Main, CRASHED, now);
+        assert_eq!(tracker.next_restart(), None);
+        assert_eq!(tracker.health()[0].last_exit, Some(CRASHED));
+    }
+
+    #[test]
+    fn due_restarts_are_taken_once() {
+        let mut tracker = tracker(RestartPolicy::OnFailure);
+        let past = Instant::now() - RESTART_BACKOFF * 2;
+        tracker.record_exit_at(&WindowType::Main, CRASHED, past);
+        assert_eq!(tracker.take_due(), [WindowType::Main]);
+        assert!(tracker.take_due().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::LxDosError;
+use crate::modules::profile::Profile;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Largest frame accepted from a peer, to bound allocations on corrupt input.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Start of every pipe name. The profile is not part of the names, its runtime
+/// directory already keeps the pipes of different profiles apart.
+pub const PIPE_PREFIX: &str = "lxdos";
+
+/// Location of the unix socket behind the pipe `name` of `profile`, in the profile's
+/// runtime directory.
+pub fn socket_path(profile: &Profile, name: &str) -> Result<PathBuf, LxDosError> {
+    Ok(profile.runtime_dir()?.join(format!("{}.sock", name)))
+}
+
+/// Unblocks a thread waiting in `Server::accept` on the pipe `name`.
+///
+/// The acceptor receives a connection that hangs up immediately.
+pub fn wake(profile: &Profile, name: &str) {
+    if let Ok(path) = socket_path(profile, name) {
+        let _ = UnixStream::connect(path);
+    }
+}
+
+/// Listening end of a named pipe. The socket file is removed when dropped.
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    pub fn start(profile: &Profile, name: &str) -> Result<Self, LxDosError> {
+        let path = socket_path(profile, name)?;
+        // A socket nobody listens on is left over from a crashed process.
+        if path.exists() && UnixStream::connect(&path).is_err() {
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self { listener, path })
+    }
+
+    /// Blocks until a client connects.
+    pub fn accept(&self) -> Result<Connection, LxDosError> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(Connection::new(stream))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path)
+            && e.kind() != ErrorKind::NotFound
+        {
+            log::error!("Failed to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Process on the other end of a `Connection`, as recorded by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+}
+
+/// One end of a connected pipe, carrying length-prefixed bincode frames.
+///
+/// Clones share the socket: any clone may `send`, but only one thread should `recv`.
+#[derive(Clone)]
+pub struct Connection {
+    stream: Arc<UnixStream>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl Connection {
+    fn new(stream: UnixStream) -> Self {
+        Self {
+            stream: Arc::new(stream),
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Connects to the pipe `name` of `profile` served by a `Server`.
+    pub fn connect(profile: &Profile, name: &str) -> Result<Self, LxDosError> {
+        Ok(Self::new(UnixStream::connect(socket_path(profile, name)?)?))
+    }
+
+    /// Two connected ends without a socket file, for tests.
+    #[cfg(test)]
+    pub fn pair() -> Result<(Self, Self), LxDosError> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Self::new(a), Self::new(b)))
+    }
+
+    pub fn send<T: Serialize>(&self, message: &T) -> Result<(), LxDosError> {
+        let payload = bincode::serde::encode_to_vec(message, bincode::config::standard())
+            .map_err(|e| LxDosError::Message(format!("Failed to encode message: {}", e)))?;
+        let _guard = self
+            .write_lock
+            .lock()
+            .map_err(|e| LxDosError::Message(e.to_string()))?;
+        let mut stream = &*self.stream;
+        stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+        stream.write_all(&payload)?;
+        Ok(())
+    }
+
+    /// Blocks until a message arrives. Returns `None` once the peer has hung up.
+    pub fn recv<T: DeserializeOwned>(&self) -> Result<Option<T>, LxDosError> {
+        let mut stream = &*self.stream;
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(LxDosError::Io(e)),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(LxDosError::Message(format!(
+                "Refusing a {} byte frame from the peer",
+                len
+            )));
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload)?;
+        let (message, _) = bincode::serde::decode_from_slice(&payload, bincode::config::standard())
+            .map_err(|e| LxDosError::Message(format!("Failed to decode message: {}", e)))?;
+        Ok(Some(message))
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    ///
+    /// A timeout can leave a frame half read, so the connection should be dropped
+    /// after an `Err`.
+    pub fn recv_timeout<T: DeserializeOwned>(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<T>, LxDosError> {
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let result = self.recv();
+        self.stream.set_read_timeout(None)?;
+        result
+    }
+
+    /// Reads `SO_PEERCRED`: the process that connected, or that was listening.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials, LxDosError> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` outlive the call and `len` holds the size of `cred`.
+        let result = unsafe {
+            libc::getsockopt(
+                self.stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return Err(LxDosError::Io(std::io::Error::last_os_error()));
+        }
+        Ok(PeerCredentials {
+            pid: cred.pid as u32,
+            uid: cred.uid,
+        })
+    }
+
+    /// Hangs up both directions, waking up a thread blocked in `recv`.
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
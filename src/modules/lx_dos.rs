@@ -1,3 +1,686 @@
-#[derive(Default, Debug)]
+use crate::LxDosError;
+use crossbeam_channel::{Receiver, Sender};
+use std::os::fd::OwnedFd;
+use std::time::SystemTime;
+pub mod config;
+#[cfg(test)]
+pub mod fake;
+pub mod paths;
+pub mod qemu;
+pub mod qga;
+pub mod qmp;
+pub mod runtime;
+use config::GuestConfig;
+use runtime::{GuestInput, GuestRuntime, RuntimeState, Screenshot};
+
+/// Lifecycle state of the Windows guest.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum GuestState {
+    #[default]
+    Stopped,
+    Starting,
+    Running,
+    Suspending,
+    Suspended,
+    Stopping,
+    Failed,
+}
+
+impl GuestState {
+    /// Whether the guest may move from `self` to `next`.
+    pub fn can_transition_to(self, next: GuestState) -> bool {
+        use GuestState::*;
+        matches!(
+            (self, next),
+            (Stopped, Starting)
+                | (Starting, Running | Stopping | Failed)
+                | (Running, Suspending | Stopping | Failed)
+                | (Suspending, Suspended | Running | Failed)
+                | (Suspended, Running | Stopping | Failed)
+                | (Stopping, Stopped | Failed)
+                // 起動に失敗しても QEMU が残っていることがあるので、止められるようにする
+                | (Failed, Starting | Stopping | Stopped)
+        )
+    }
+}
+
+impl std::fmt::Display for GuestState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A state change, as delivered to `LxDos::subscribe` receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestEvent {
+    pub from: GuestState,
+    pub to: GuestState,
+    pub at: SystemTime,
+    /// Why the guest failed, set when `to` is `GuestState::Failed`.
+    pub reason: Option<String>,
+}
+
+/// Snapshot of the guest, as reported by `lx-dos status`.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct GuestStatus {
+    pub state: GuestState,
+    /// Name of the runtime driving the guest, if one is configured.
+    pub runtime: Option<String>,
+    /// Seconds spent in `state` so far.
+    pub state_secs: u64,
+    pub failure: Option<String>,
+}
+
+/// A start or stop taken out of `LxDos`, so that the runtime can block on another
+/// thread while the owner of `LxDos` keeps serving requests.
+///
+/// Created by `LxDos::begin_start` and `LxDos::begin_stop`. The guest stays
+/// `Starting` or `Stopping` until the outcome of `run` is handed to `LxDos::finish`.
+#[derive(Debug)]
+pub struct GuestOperation {
+    runtime: Box<dyn GuestRuntime>,
+    to: GuestState,
+}
+
+impl GuestOperation {
+    /// Performs the operation, blocking until the runtime is done.
+    pub fn run(mut self) -> FinishedOperation {
+        let result = match self.to {
+            GuestState::Running => self.runtime.start(),
+            _ => self.runtime.stop(),
+        };
+        FinishedOperation {
+            runtime: self.runtime,
+            to: self.to,
+            result,
+        }
+    }
+}
+
+/// The outcome of a `GuestOperation`, to be handed back to `LxDos::finish`.
+#[derive(Debug)]
+pub struct FinishedOperation {
+    runtime: Box<dyn GuestRuntime>,
+    to: GuestState,
+    result: Result<(), LxDosError>,
+}
+
+/// Owns the lifecycle of the Windows guest.
+///
+/// Every state change goes through `transition`, which rejects moves that
+/// `GuestState::can_transition_to` does not allow and notifies all subscribers.
+/// The guest itself is driven through a `GuestRuntime`.
+#[derive(Debug)]
 pub struct LxDos {
+    state: GuestState,
+    since: SystemTime,
+    failure: Option<String>,
+    subscribers: Vec<Sender<GuestEvent>>,
+    runtime: Option<Box<dyn GuestRuntime>>,
+    /// Name of the runtime while a `GuestOperation` has it.
+    lent: Option<String>,
+    config: Option<GuestConfig>,
+}
+
+impl Default for LxDos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LxDos {
+    pub fn new() -> Self {
+        Self {
+            state: GuestState::Stopped,
+            since: SystemTime::now(),
+            failure: None,
+            subscribers: Vec::new(),
+            runtime: None,
+            lent: None,
+            config: None,
+        }
+    }
+
+    pub fn with_runtime(runtime: Box<dyn GuestRuntime>) -> Self {
+        let mut lx_dos = Self::new();
+        lx_dos.runtime = Some(runtime);
+        lx_dos
+    }
+
+    /// Replaces the runtime. Only allowed while the guest is not running.
+    pub fn set_runtime(&mut self, runtime: Box<dyn GuestRuntime>) -> Result<(), LxDosError> {
+        self.require_idle("replace the runtime")?;
+        self.runtime = Some(runtime);
+        Ok(())
+    }
+
+    /// The guest definition, if one has been loaded.
+    pub fn config(&self) -> Option<&GuestConfig> {
+        self.config.as_ref()
+    }
+
+    /// Loads the guest definition at `path`. Only allowed while the guest is not running.
+    pub fn load_config(&mut self, path: &std::path::Path) -> Result<(), LxDosError> {
+        self.require_idle("load a guest definition")?;
+        self.config = Some(GuestConfig::load(path)?);
+        Ok(())
+    }
+
+    pub fn state(&self) -> GuestState {
+        self.state
+    }
+
+    /// When the guest entered its current state.
+    pub fn since(&self) -> SystemTime {
+        self.since
+    }
+
+    /// Reason of the last failure, kept until the guest is started again.
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    pub fn status(&self) -> GuestStatus {
+        GuestStatus {
+            state: self.state,
+            runtime: self
+                .runtime
+                .as_ref()
+                .map(|runtime| runtime.name().to_string())
+                .or_else(|| self.lent.clone()),
+            state_secs: self.since.elapsed().map(|d| d.as_secs()).unwrap_or(0),
+            failure: self.failure.clone(),
+        }
+    }
+
+    /// Returns a receiver for all state changes from now on.
+    pub fn subscribe(&mut self) -> Receiver<GuestEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    pub fn transition(&mut self, to: GuestState) -> Result<(), LxDosError> {
+        if to == GuestState::Failed {
+            return self.fail("unknown error");
+        }
+        self.apply(to, None)
+    }
+
+    /// Moves the guest to `GuestState::Failed`, recording `reason`.
+    pub fn fail(&mut self, reason: impl Into<String>) -> Result<(), LxDosError> {
+        self.apply(GuestState::Failed, Some(reason.into()))
+    }
+
+    pub fn start(&mut self) -> Result<(), LxDosError> {
+        let operation = self.begin_start()?;
+        self.finish(operation.run())
+    }
+
+    pub fn stop(&mut self) -> Result<(), LxDosError> {
+        let operation = self.begin_stop()?;
+        self.finish(operation.run())
+    }
+
+    /// Moves to `Starting` and lends the runtime out to boot the guest elsewhere.
+    pub fn begin_start(&mut self) -> Result<GuestOperation, LxDosError> {
+        self.begin(GuestState::Starting, GuestState::Running)
+    }
+
+    /// Moves to `Stopping` and lends the runtime out to shut the guest down elsewhere.
+    ///
+    /// A suspended guest is resumed first, as a paused one ignores the power button.
+    pub fn begin_stop(&mut self) -> Result<GuestOperation, LxDosError> {
+        if self.state == GuestState::Suspended
+            && let Err(e) = self.resume()
+        {
+            log::warn!("Failed to resume the guest to shut it down: {}", e);
+        }
+        self.begin(GuestState::Stopping, GuestState::Stopped)
+    }
+
+    /// Takes the runtime back from a finished operation and settles the state.
+    pub fn finish(&mut self, finished: FinishedOperation) -> Result<(), LxDosError> {
+        self.runtime = Some(finished.runtime);
+        self.lent = None;
+        self.settle(finished.result, finished.to)
+    }
+
+    /// Whether a `GuestOperation` is still out.
+    pub fn busy(&self) -> bool {
+        self.lent.is_some()
+    }
+
+    pub fn suspend(&mut self) -> Result<(), LxDosError> {
+        self.runtime()?;
+        self.transition(GuestState::Suspending)?;
+        match self.runtime()?.pause() {
+            Ok(()) => self.transition(GuestState::Suspended),
+            // The guest keeps running if it could not be paused.
+            Err(e) => {
+                self.transition(GuestState::Running)?;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn resume(&mut self) -> Result<(), LxDosError> {
+        if self.state != GuestState::Suspended {
+            return Err(LxDosError::InvalidTransition {
+                from: self.state,
+                to: GuestState::Running,
+            });
+        }
+        // Like `suspend`, the guest stays as it was if the runtime could not resume it.
+        self.runtime()?.resume()?;
+        self.transition(GuestState::Running)
+    }
+
+    /// Asks the runtime what the guest is doing and follows changes that happened
+    /// outside of `LxDos`, such as a shutdown from inside Windows or a crash.
+    pub fn refresh(&mut self) -> Result<(), LxDosError> {
+        let reported = self.runtime()?.query_state()?;
+        self.reconcile(reported)
+    }
+
+    /// See `GuestRuntime::watch`; `None` while a `GuestOperation` has the runtime.
+    pub fn watch_runtime(&self) -> Option<OwnedFd> {
+        self.runtime.as_deref()?.watch()
+    }
+
+    /// Applies the state changes the runtime reported on its own, e.g. QMP events.
+    pub fn poll_runtime(&mut self) -> Result<(), LxDosError> {
+        let Some(runtime) = self.runtime.as_deref_mut() else {
+            return Ok(());
+        };
+        for reported in runtime.poll_changes()? {
+            self.reconcile(reported)?;
+        }
+        Ok(())
+    }
+
+    fn reconcile(&mut self, reported: RuntimeState) -> Result<(), LxDosError> {
+        match (self.state, reported) {
+            (GuestState::Running | GuestState::Suspended, RuntimeState::Crashed(reason)) => {
+                self.fail(reason)
+            }
+            (GuestState::Running | GuestState::Suspended, RuntimeState::Stopped) => {
+                self.transition(GuestState::Stopping)?;
+                self.transition(GuestState::Stopped)
+            }
+            (GuestState::Running, RuntimeState::Paused) => {
+                self.transition(GuestState::Suspending)?;
+                self.transition(GuestState::Suspended)
+            }
+            (GuestState::Suspended, RuntimeState::Running) => self.transition(GuestState::Running),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn send_input(&mut self, input: &GuestInput) -> Result<(), LxDosError> {
+        self.require_running()?;
+        self.runtime()?.send_input(input)
+    }
+
+    pub fn fetch_screen(&mut self) -> Result<Screenshot, LxDosError> {
+        self.require_running()?;
+        self.runtime()?.fetch_screen()
+    }
+
+    fn runtime(&mut self) -> Result<&mut dyn GuestRuntime, LxDosError> {
+        match self.runtime.as_deref_mut() {
+            Some(runtime) => Ok(runtime),
+            None => Err(Self::no_runtime(self.state, self.lent.is_some())),
+        }
+    }
+
+    /// Why there is no runtime to use: none was set, or a `GuestOperation` has it.
+    fn no_runtime(state: GuestState, lent: bool) -> LxDosError {
+        if lent {
+            LxDosError::Message(format!(
+                "The guest is {}, try again once that is done",
+                state
+            ))
+        } else {
+            LxDosError::Message("No guest runtime is configured".to_string())
+        }
+    }
+
+    fn begin(&mut self, via: GuestState, to: GuestState) -> Result<GuestOperation, LxDosError> {
+        let Some(runtime) = self.runtime.take() else {
+            return Err(Self::no_runtime(self.state, self.busy()));
+        };
+        if let Err(e) = self.transition(via) {
+            self.runtime = Some(runtime);
+            return Err(e);
+        }
+        self.lent = Some(runtime.name().to_string());
+        Ok(GuestOperation { runtime, to })
+    }
+
+    fn require_idle(&self, action: &str) -> Result<(), LxDosError> {
+        if matches!(self.state, GuestState::Stopped | GuestState::Failed) {
+            Ok(())
+        } else {
+            Err(LxDosError::Message(format!(
+                "Cannot {} while the guest is {}",
+                action, self.state
+            )))
+        }
+    }
+
+    fn require_running(&self) -> Result<(), LxDosError> {
+        if self.state == GuestState::Running {
+            Ok(())
+        } else {
+            Err(LxDosError::Message(format!(
+                "The guest is {}, not Running",
+                self.state
+            )))
+        }
+    }
+
+    /// Completes an operation: moves to `to` on success and to `Failed` otherwise.
+    fn settle(&mut self, result: Result<(), LxDosError>, to: GuestState) -> Result<(), LxDosError> {
+        match result {
+            Ok(()) => self.transition(to),
+            Err(e) => {
+                self.fail(e.to_string())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn apply(&mut self, to: GuestState, reason: Option<String>) -> Result<(), LxDosError> {
+        let from = self.state;
+        if !from.can_transition_to(to) {
+            return Err(LxDosError::InvalidTransition { from, to });
+        }
+        let at = SystemTime::now();
+        self.state = to;
+        self.since = at;
+        match to {
+            GuestState::Failed => self.failure = reason.clone(),
+            GuestState::Starting => self.failure = None,
+            _ => {}
+        }
+        log::debug!("Guest state {} -> {}", from, to);
+
+        let event = GuestEvent {
+            from,
+            to,
+            at,
+            reason,
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::{FakeOp, FakeRuntime};
+    use super::*;
+    use std::time::Duration;
+
+    fn lx_dos() -> (LxDos, FakeRuntime) {
+        let fake = FakeRuntime::new();
+        (LxDos::with_runtime(Box::new(fake.clone())), fake)
+    }
+
+    fn running() -> (LxDos, FakeRuntime) {
+        let (mut lx_dos, fake) = lx_dos();
+        lx_dos.start().unwrap();
+        (lx_dos, fake)
+    }
+
+    #[test]
+    fn start_and_stop() {
+        let (mut lx_dos, fake) = lx_dos();
+        let events = lx_dos.subscribe();
+        lx_dos.start().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+        lx_dos.stop().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+        assert_eq!(fake.calls(), [FakeOp::Start, FakeOp::Stop]);
+
+        let seen: Vec<_> = events.try_iter().map(|e| (e.from, e.to)).collect();
+        assert_eq!(
+            seen,
+            [
+                (GuestState::Stopped, GuestState::Starting),
+                (GuestState::Starting, GuestState::Running),
+                (GuestState::Running, GuestState::Stopping),
+                (GuestState::Stopping, GuestState::Stopped),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_start_records_the_reason() {
+        let (mut lx_dos, fake) = lx_dos();
+        let events = lx_dos.subscribe();
+        fake.fail_next(FakeOp::Start, "no kvm");
+        assert!(lx_dos.start().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Failed);
+        assert_eq!(lx_dos.failure(), Some("no kvm"));
+        let failed = events.try_iter().last().unwrap();
+        assert_eq!(failed.to, GuestState::Failed);
+        assert_eq!(failed.reason.as_deref(), Some("no kvm"));
+
+        // 再起動すれば失敗の理由は消える
+        lx_dos.start().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+        assert_eq!(lx_dos.failure(), None);
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        let (mut lx_dos, _) = running();
+        lx_dos.suspend().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Suspended);
+        lx_dos.resume().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+    }
+
+    #[test]
+    fn failed_suspend_keeps_the_guest_running() {
+        let (mut lx_dos, fake) = running();
+        fake.fail_next(FakeOp::Pause, "busy");
+        assert!(lx_dos.suspend().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Running);
+        assert_eq!(lx_dos.failure(), None);
+    }
+
+    #[test]
+    fn failed_resume_keeps_the_guest_suspended() {
+        let (mut lx_dos, fake) = running();
+        lx_dos.suspend().unwrap();
+        fake.fail_next(FakeOp::Resume, "busy");
+        assert!(lx_dos.resume().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Suspended);
+        assert_eq!(lx_dos.failure(), None);
+        lx_dos.resume().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+    }
+
+    #[test]
+    fn failed_stop_fails_the_guest() {
+        let (mut lx_dos, fake) = running();
+        fake.fail_next(FakeOp::Stop, "QMP gone");
+        assert!(lx_dos.stop().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Failed);
+    }
+
+    #[test]
+    fn invalid_transitions_are_rejected() {
+        let (mut lx_dos, fake) = lx_dos();
+        assert!(matches!(
+            lx_dos.stop(),
+            Err(LxDosError::InvalidTransition {
+                from: GuestState::Stopped,
+                to: GuestState::Stopping
+            })
+        ));
+        assert!(matches!(
+            lx_dos.resume(),
+            Err(LxDosError::InvalidTransition { .. })
+        ));
+        assert!(lx_dos.suspend().is_err());
+        // 拒否された操作はランタイムまで届かない
+        assert!(fake.calls().is_empty());
+
+        lx_dos.start().unwrap();
+        assert!(lx_dos.start().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Running);
+    }
+
+    #[test]
+    fn without_runtime_nothing_changes() {
+        let mut lx_dos = LxDos::new();
+        assert!(lx_dos.start().is_err());
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+        assert!(lx_dos.poll_runtime().is_ok());
+    }
+
+    #[test]
+    fn runtime_and_config_only_change_while_idle() {
+        let (mut lx_dos, _) = running();
+        assert!(lx_dos.set_runtime(Box::new(FakeRuntime::new())).is_err());
+        assert!(
+            lx_dos
+                .load_config(std::path::Path::new("/nonexistent.toml"))
+                .unwrap_err()
+                .to_string()
+                .contains("while the guest is Running")
+        );
+        lx_dos.stop().unwrap();
+        assert!(lx_dos.set_runtime(Box::new(FakeRuntime::new())).is_ok());
+    }
+
+    #[test]
+    fn follows_changes_reported_by_the_runtime() {
+        let (mut lx_dos, fake) = running();
+        fake.set_state(RuntimeState::Paused);
+        lx_dos.poll_runtime().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Suspended);
+        fake.set_state(RuntimeState::Running);
+        lx_dos.poll_runtime().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+
+        // Windows の中からシャットダウンされた
+        fake.set_state(RuntimeState::Stopped);
+        lx_dos.poll_runtime().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+    }
+
+    #[test]
+    fn crash_fails_the_guest() {
+        let (mut lx_dos, fake) = running();
+        fake.set_state(RuntimeState::Crashed("QEMU exited with 1".to_string()));
+        lx_dos.refresh().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Failed);
+        assert_eq!(lx_dos.failure(), Some("QEMU exited with 1"));
+        assert_eq!(
+            lx_dos.status().failure.as_deref(),
+            Some("QEMU exited with 1")
+        );
+    }
+
+    #[test]
+    fn input_and_screen_need_a_running_guest() {
+        let (mut lx_dos, fake) = lx_dos();
+        let input = GuestInput::Text("dir".to_string());
+        assert!(lx_dos.send_input(&input).is_err());
+        assert!(lx_dos.fetch_screen().is_err());
+
+        lx_dos.start().unwrap();
+        lx_dos.send_input(&input).unwrap();
+        assert_eq!(lx_dos.fetch_screen().unwrap().width, 1);
+        assert_eq!(fake.inputs(), [input]);
+    }
+
+    #[test]
+    fn delayed_operations_complete() {
+        let (mut lx_dos, fake) = lx_dos();
+        fake.delay(FakeOp::Start, Duration::from_millis(20));
+        lx_dos.start().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Running);
+        assert!(lx_dos.since().elapsed().unwrap() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn operations_run_on_another_thread() {
+        let (mut lx_dos, fake) = lx_dos();
+        fake.delay(FakeOp::Start, Duration::from_millis(50));
+        let operation = lx_dos.begin_start().unwrap();
+        let worker = std::thread::spawn(move || operation.run());
+
+        // 起動中も状態は答えられるが、ランタイムは貸し出し中
+        assert!(lx_dos.busy());
+        let status = lx_dos.status();
+        assert_eq!(status.state, GuestState::Starting);
+        assert_eq!(status.runtime.as_deref(), Some(fake.name()));
+        assert!(
+            lx_dos
+                .refresh()
+                .unwrap_err()
+                .to_string()
+                .contains("Starting")
+        );
+        assert!(lx_dos.begin_stop().is_err());
+        assert!(lx_dos.begin_start().is_err());
+
+        lx_dos.finish(worker.join().unwrap()).unwrap();
+        assert!(!lx_dos.busy());
+        assert_eq!(lx_dos.state(), GuestState::Running);
+
+        let operation = lx_dos.begin_stop().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopping);
+        lx_dos.finish(operation.run()).unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+        assert_eq!(fake.calls(), [FakeOp::Start, FakeOp::Stop]);
+    }
+
+    #[test]
+    fn failed_operation_fails_the_guest() {
+        let (mut lx_dos, fake) = lx_dos();
+        fake.fail_next(FakeOp::Start, "no kvm");
+        let operation = lx_dos.begin_start().unwrap();
+        assert!(lx_dos.finish(operation.run()).is_err());
+        assert_eq!(lx_dos.state(), GuestState::Failed);
+        assert_eq!(lx_dos.failure(), Some("no kvm"));
+        // ランタイムは戻っているので、もう一度起動できる
+        lx_dos.start().unwrap();
+    }
+
+    #[test]
+    fn stopping_a_suspended_guest_resumes_it_first() {
+        let (mut lx_dos, fake) = running();
+        lx_dos.suspend().unwrap();
+        lx_dos.stop().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+        assert_eq!(
+            fake.calls(),
+            [FakeOp::Start, FakeOp::Pause, FakeOp::Resume, FakeOp::Stop]
+        );
+
+        // 再開できなくても止めには行く
+        lx_dos.start().unwrap();
+        lx_dos.suspend().unwrap();
+        fake.fail_next(FakeOp::Resume, "busy");
+        lx_dos.stop().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+    }
+
+    #[test]
+    fn failed_guest_can_be_stopped() {
+        let (mut lx_dos, fake) = lx_dos();
+        fake.fail_next(FakeOp::Start, "QMP handshake timed out");
+        assert!(lx_dos.start().is_err());
+        lx_dos.stop().unwrap();
+        assert_eq!(lx_dos.state(), GuestState::Stopped);
+        assert_eq!(fake.calls(), [FakeOp::Start, FakeOp::Stop]);
+    }
 }
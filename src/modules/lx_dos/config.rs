@@ -0,0 +1,601 @@
+use crate::LxDosError;
+use crate::modules::app::instance::WindowType;
+use crate::modules::app::instance::restart::RestartPolicy;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, ImDocument, Item, Table, Value};
+
+/// Smallest amount of memory Windows can be installed with.
+pub const MIN_MEMORY_MIB: u32 = 1024;
+
+/// Most CPUs a guest may have: the host's CPU count.
+pub fn max_cpus() -> u32 {
+    std::thread::available_parallelism().map_or(1, |n| n.get() as u32)
+}
+
+/// Guest definition, read from the file given by `Profile::config_path`.
+///
+/// ```toml
+/// name = "Windows 11"
+/// disk_image = "/home/alice/lx-dos/windows.qcow2"
+/// memory_mib = 8192
+/// cpus = 4
+/// display = "gtk"
+/// autostart = true
+/// installer = "/home/alice/Downloads/Win11.iso"
+///
+/// [network]
+/// mode = "user"
+///
+/// [[shared_folders]]
+/// name = "work"
+/// path = "/home/alice/work"
+///
+/// [windows.main]
+/// restart = "on-failure"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestConfig {
+    pub name: String,
+    pub disk_image: PathBuf,
+    pub memory_mib: u32,
+    pub cpus: u32,
+    #[serde(default)]
+    pub display: DisplayMode,
+    /// Boot the guest as soon as `lx-dos start` runs.
+    #[serde(default)]
+    pub autostart: bool,
+    /// Windows installer ISO attached as a CD-ROM, to be removed once Windows is installed.
+    pub installer: Option<PathBuf>,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub shared_folders: Vec<SharedFolder>,
+    #[serde(default)]
+    pub windows: WindowsConfig,
+}
+
+/// Per-window settings of the frontend.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowsConfig {
+    #[serde(default = "WindowConfig::main")]
+    pub main: WindowConfig,
+    #[serde(default = "WindowConfig::settings")]
+    pub settings: WindowConfig,
+}
+
+impl Default for WindowsConfig {
+    fn default() -> Self {
+        Self {
+            main: WindowConfig::main(),
+            settings: WindowConfig::settings(),
+        }
+    }
+}
+
+impl WindowsConfig {
+    pub fn get(&self, window_type: &WindowType) -> &WindowConfig {
+        match window_type {
+            WindowType::Main => &self.main,
+            WindowType::Settings => &self.settings,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowConfig {
+    /// What to do when the window's backend exits on its own.
+    pub restart: RestartPolicy,
+}
+
+impl WindowConfig {
+    fn main() -> Self {
+        Self {
+            restart: RestartPolicy::default_for(&WindowType::Main),
+        }
+    }
+
+    fn settings() -> Self {
+        Self {
+            restart: RestartPolicy::default_for(&WindowType::Settings),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayMode {
+    /// A local GTK window.
+    #[default]
+    Gtk,
+    /// A SPICE server for remote viewers.
+    Spice,
+    /// A VNC server for remote viewers.
+    Vnc,
+    /// No display at all; the guest is only reachable over the network.
+    None,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub mode: NetworkMode,
+    /// Host bridge to attach to, required for `NetworkMode::Bridge`.
+    pub bridge: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// NAT through QEMU's user-mode network stack.
+    #[default]
+    User,
+    Bridge,
+    None,
+}
+
+/// A host directory made available to the guest through virtio-fs, under `name`.
+///
+/// The guest mounts it as a drive; see `paths::PathMap` for which one.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedFolder {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// A problem with a guest definition, pointing at the offending key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Dotted key path such as `shared_folders[1].path`, if the problem has one.
+    pub key: Option<String>,
+    /// 1-based line of the key in the file, if it could be located.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// All problems found in one guest definition file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors {
+    pub path: PathBuf,
+    pub errors: Vec<ConfigError>,
+}
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid guest definition {}", self.path.display())?;
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl GuestConfig {
+    pub fn load(path: &Path) -> Result<Self, LxDosError> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            LxDosError::Message(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text).map_err(|errors| {
+            LxDosError::Config(ConfigErrors {
+                path: path.to_path_buf(),
+                errors,
+            })
+        })
+    }
+
+    /// Reads a guest definition without validating it, for editing one that may
+    /// not be valid yet.
+    pub fn load_unchecked(path: &Path) -> Result<Self, LxDosError> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            LxDosError::Message(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        Self::deserialize(&text).map_err(|errors| {
+            LxDosError::Config(ConfigErrors {
+                path: path.to_path_buf(),
+                errors,
+            })
+        })
+    }
+
+    /// Validates the definition and writes it to `path`.
+    ///
+    /// Keys already in the file are updated in place, so comments and the order of
+    /// keys survive.
+    pub fn save(&self, path: &Path) -> Result<(), LxDosError> {
+        let errors = self.validate();
+        if !errors.is_empty() {
+            return Err(LxDosError::Config(ConfigErrors {
+                path: path.to_path_buf(),
+                errors,
+            }));
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut document: DocumentMut = text.parse().map_err(|e| {
+            LxDosError::Message(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        let updated: DocumentMut = toml::to_string(self)
+            .map_err(|e| LxDosError::Message(e.to_string()))?
+            .parse()
+            .map_err(|e| LxDosError::Message(format!("{}", e)))?;
+        merge(document.as_table_mut(), updated.as_table());
+
+        // 書き込み途中で落ちても元のファイルが壊れないように置き換える
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, document.to_string())?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Parses and validates a guest definition.
+    pub fn parse(text: &str) -> Result<Self, Vec<ConfigError>> {
+        let config = Self::deserialize(text)?;
+
+        let errors = config.validate();
+        if errors.is_empty() {
+            return Ok(config);
+        }
+        let document = ImDocument::parse(text).ok();
+        Err(errors
+            .into_iter()
+            .map(|mut error| {
+                error.line = document
+                    .as_ref()
+                    .zip(error.key.as_deref())
+                    .and_then(|(document, key)| locate(document, key))
+                    .map(|offset| line_of(text, offset));
+                error
+            })
+            .collect())
+    }
+
+    fn deserialize(text: &str) -> Result<Self, Vec<ConfigError>> {
+        toml::from_str(text).map_err(|e| {
+            vec![ConfigError {
+                key: None,
+                line: e.span().map(|span| line_of(text, span.start)),
+                message: e.message().to_string(),
+            }]
+        })
+    }
+
+    /// Checks the values that the TOML types alone cannot express.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut error = |key: String, message: String| {
+            errors.push(ConfigError {
+                key: Some(key),
+                line: None,
+                message,
+            })
+        };
+
+        if self.name.trim().is_empty() {
+            error("name".to_string(), "must not be empty".to_string());
+        }
+        if !self.disk_image.is_absolute() {
+            error(
+                "disk_image".to_string(),
+                "must be an absolute path".to_string(),
+            );
+        } else if !self.disk_image.is_file() {
+            error(
+                "disk_image".to_string(),
+                format!("{} does not exist", self.disk_image.display()),
+            );
+        }
+        if let Some(installer) = &self.installer {
+            if !installer.is_absolute() {
+                error(
+                    "installer".to_string(),
+                    "must be an absolute path".to_string(),
+                );
+            } else if !installer.is_file() {
+                error(
+                    "installer".to_string(),
+                    format!("{} does not exist", installer.display()),
+                );
+            }
+        }
+        if self.memory_mib < MIN_MEMORY_MIB {
+            error(
+                "memory_mib".to_string(),
+                format!("must be at least {}", MIN_MEMORY_MIB),
+            );
+        }
+        let max_cpus = max_cpus();
+        if self.cpus == 0 || self.cpus > max_cpus {
+            error(
+                "cpus".to_string(),
+                format!("must be between 1 and {} (the host's CPU count)", max_cpus),
+            );
+        }
+        match (self.network.mode, &self.network.bridge) {
+            (NetworkMode::Bridge, None) => error(
+                "network.bridge".to_string(),
+                "is required when mode is \"bridge\"".to_string(),
+            ),
+            (NetworkMode::User | NetworkMode::None, Some(_)) => error(
+                "network.bridge".to_string(),
+                "is only used when mode is \"bridge\"".to_string(),
+            ),
+            _ => {}
+        }
+
+        let mut names = HashSet::new();
+        for (i, folder) in self.shared_folders.iter().enumerate() {
+            let key = |field: &str| format!("shared_folders[{}].{}", i, field);
+            if folder.name.trim().is_empty() {
+                error(key("name"), "must not be empty".to_string());
+            } else if !names.insert(folder.name.to_lowercase()) {
+                error(key("name"), format!("{:?} is used twice", folder.name));
+            }
+            if !folder.path.is_absolute() {
+                error(key("path"), "must be an absolute path".to_string());
+            } else if !folder.path.is_dir() {
+                error(
+                    key("path"),
+                    format!("{} is not a directory", folder.path.display()),
+                );
+            }
+        }
+        errors
+    }
+}
+
+/// Makes `document` hold the keys of `updated`, keeping the decoration of values
+/// and tables that exist in both.
+fn merge(document: &mut Table, updated: &Table) {
+    document.retain(|key, _| updated.contains_key(key));
+    for (key, item) in updated.iter() {
+        match (document.get_mut(key), item) {
+            (Some(Item::Table(table)), Item::Table(updated)) => merge(table, updated),
+            (Some(Item::Value(Value::InlineTable(table))), Item::Table(updated)) => {
+                let decor = table.decor().clone();
+                *table = updated.clone().into_inline_table();
+                *table.decor_mut() = decor;
+            }
+            (Some(Item::Value(value)), Item::Value(updated)) => {
+                let decor = value.decor().clone();
+                *value = updated.clone();
+                *value.decor_mut() = decor;
+            }
+            _ => {
+                document.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+/// Finds the byte offset of a dotted key path like `shared_folders[1].path`.
+///
+/// Falls back to the closest enclosing table if the key itself is missing.
+fn locate(document: &ImDocument<&str>, key: &str) -> Option<usize> {
+    let mut item: &Item = document.as_item();
+    let mut offset = None;
+    for segment in key.split('.') {
+        let (name, index) = match segment.split_once('[') {
+            Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().ok()),
+            None => (segment, None),
+        };
+        let Some((key, value)) = item.as_table_like().and_then(|t| t.get_key_value(name)) else {
+            break;
+        };
+        offset = key.span().map(|span| span.start).or(offset);
+        item = value;
+        if let Some(index) = index {
+            let Some(element) = item.get(index) else {
+                break;
+            };
+            offset = element.span().map(|span| span.start).or(offset);
+            item = element;
+        }
+    }
+    offset
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A scratch directory holding an empty disk image, removed on drop.
+    struct Scratch {
+        dir: PathBuf,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "lx-dos-config-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("windows.qcow2"), b"").unwrap();
+            Self { dir }
+        }
+
+        /// A valid definition followed by `extra` lines.
+        fn config(&self, extra: &str) -> String {
+            format!(
+                "name = \"Windows 11\"\n\
+                 disk_image = \"{}\"\n\
+                 memory_mib = 4096\n\
+                 cpus = 1\n\
+                 {}",
+                self.dir.join("windows.qcow2").display(),
+                extra
+            )
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn a_valid_definition_parses_with_defaults() {
+        let scratch = Scratch::new();
+        let config = GuestConfig::parse(&scratch.config("")).unwrap();
+        assert_eq!(config.memory_mib, 4096);
+        assert_eq!(config.display, DisplayMode::Gtk);
+        assert_eq!(config.network, NetworkConfig::default());
+        assert_eq!(config.windows, WindowsConfig::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_line() {
+        let scratch = Scratch::new();
+        let errors =
+            GuestConfig::parse(&scratch.config("\n[network]\nmod = \"user\"\n")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(7));
+        assert!(
+            errors[0].message.contains("unknown field `mod`"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_reported_with_their_line() {
+        let scratch = Scratch::new();
+        let text = scratch.config("display = \"sdl\"\n");
+        let errors = GuestConfig::parse(&text).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(5));
+        assert!(
+            errors[0].message.contains("unknown variant `sdl`"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported_with_their_key_and_line() {
+        let scratch = Scratch::new();
+        let folder = scratch.dir.display();
+        let text = scratch
+            .config(&format!(
+                "\n[[shared_folders]]\nname = \"work\"\npath = \"{folder}\"\n\
+                 \n[[shared_folders]]\nname = \"Work\"\npath = \"relative\"\n"
+            ))
+            .replace("memory_mib = 4096", "memory_mib = 512");
+        let errors = GuestConfig::parse(&text).unwrap_err();
+        let found: Vec<_> = errors
+            .iter()
+            .map(|e| (e.key.as_deref().unwrap(), e.line))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("memory_mib", Some(3)),
+                ("shared_folders[1].name", Some(11)),
+                ("shared_folders[1].path", Some(12)),
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "line 3: memory_mib: must be at least 1024"
+        );
+    }
+
+    #[test]
+    fn missing_required_fields_are_reported() {
+        let scratch = Scratch::new();
+        let text = scratch.config("").replace("memory_mib = 4096\n", "");
+        let errors = GuestConfig::parse(&text).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].message.contains("missing field `memory_mib`"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn save_keeps_comments_and_the_order_of_keys() {
+        let scratch = Scratch::new();
+        let path = scratch.dir.join("guest.toml");
+        let text = scratch
+            .config("# 起動時に立ち上げる\nautostart = true\n\n[network]\nmode = \"user\" # NAT\n")
+            .replace(
+                "memory_mib = 4096",
+                "# ゲストのメモリ\nmemory_mib = 4096 # MiB",
+            );
+        fs::write(&path, &text).unwrap();
+
+        let mut config = GuestConfig::load(&path).unwrap();
+        config.memory_mib = 8192;
+        config.autostart = false;
+        config.save(&path).unwrap();
+
+        let saved = fs::read_to_string(&path).unwrap();
+        for kept in [
+            "# ゲストのメモリ\nmemory_mib = 8192 # MiB\n",
+            "# 起動時に立ち上げる\nautostart = false\n",
+            "[network]\nmode = \"user\" # NAT\n",
+        ] {
+            assert!(
+                saved.contains(kept),
+                "{:?} is missing from\n{}",
+                kept,
+                saved
+            );
+        }
+        let position = |key: &str| saved.find(&format!("\n{} = ", key)).unwrap();
+        let keys = ["disk_image", "memory_mib", "cpus", "autostart"];
+        assert!(
+            keys.windows(2).all(|w| position(w[0]) < position(w[1])),
+            "{}",
+            saved
+        );
+        assert_eq!(GuestConfig::load(&path).unwrap(), config);
+        assert!(!path.with_extension("toml.tmp").exists());
+    }
+
+    #[test]
+    fn save_refuses_an_invalid_definition() {
+        let scratch = Scratch::new();
+        let path = scratch.dir.join("guest.toml");
+        let mut config = GuestConfig::parse(&scratch.config("")).unwrap();
+        config.cpus = 0;
+        let err = config.save(&path).unwrap_err();
+        assert!(matches!(err, LxDosError::Config(_)), "{}", err);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,146 @@
+use super::runtime::{GuestInput, GuestRuntime, RuntimeState, Screenshot};
+use crate::LxDosError;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Operations of `GuestRuntime`, used to script `FakeRuntime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FakeOp {
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    QueryState,
+    SendInput,
+    FetchScreen,
+}
+
+#[derive(Debug, Default)]
+struct FakeInner {
+    state: RuntimeState,
+    failures: HashMap<FakeOp, VecDeque<String>>,
+    delays: HashMap<FakeOp, Duration>,
+    calls: Vec<FakeOp>,
+    changes: Vec<RuntimeState>,
+    inputs: Vec<GuestInput>,
+}
+
+/// In-process `GuestRuntime` that launches nothing.
+///
+/// Clones share their state, so a clone kept outside `LxDos` can script failures
+/// and delays, simulate crashes and inspect the calls that were made.
+#[derive(Debug, Clone, Default)]
+pub struct FakeRuntime {
+    inner: Arc<Mutex<FakeInner>>,
+}
+
+impl FakeRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the next call of `op` fail with `reason`. Repeated calls queue up.
+    pub fn fail_next(&self, op: FakeOp, reason: &str) -> &Self {
+        self.lock()
+            .failures
+            .entry(op)
+            .or_default()
+            .push_back(reason.to_string());
+        self
+    }
+
+    /// Makes every call of `op` block for `delay` before completing.
+    pub fn delay(&self, op: FakeOp, delay: Duration) -> &Self {
+        self.lock().delays.insert(op, delay);
+        self
+    }
+
+    /// Overrides what the guest reports, e.g. to simulate a crash or an in-guest shutdown.
+    ///
+    /// The change is also reported by the next `poll_changes`.
+    pub fn set_state(&self, state: RuntimeState) {
+        let mut inner = self.lock();
+        inner.changes.push(state.clone());
+        inner.state = state;
+    }
+
+    pub fn calls(&self) -> Vec<FakeOp> {
+        self.lock().calls.clone()
+    }
+
+    pub fn inputs(&self) -> Vec<GuestInput> {
+        self.lock().inputs.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FakeInner> {
+        // A panicking test must not poison the fake for the assertions that follow.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records the call, applies its delay and returns its scripted failure, if any.
+    fn call(&self, op: FakeOp) -> Result<MutexGuard<'_, FakeInner>, LxDosError> {
+        let delay = {
+            let mut inner = self.lock();
+            inner.calls.push(op);
+            inner.delays.get(&op).copied()
+        };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        let mut inner = self.lock();
+        match inner.failures.get_mut(&op).and_then(VecDeque::pop_front) {
+            Some(reason) => Err(LxDosError::Message(reason)),
+            None => Ok(inner),
+        }
+    }
+}
+
+impl GuestRuntime for FakeRuntime {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn start(&mut self) -> Result<(), LxDosError> {
+        self.call(FakeOp::Start)?.state = RuntimeState::Running;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), LxDosError> {
+        self.call(FakeOp::Stop)?.state = RuntimeState::Stopped;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), LxDosError> {
+        self.call(FakeOp::Pause)?.state = RuntimeState::Paused;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), LxDosError> {
+        self.call(FakeOp::Resume)?.state = RuntimeState::Running;
+        Ok(())
+    }
+
+    fn query_state(&mut self) -> Result<RuntimeState, LxDosError> {
+        Ok(self.call(FakeOp::QueryState)?.state.clone())
+    }
+
+    fn poll_changes(&mut self) -> Result<Vec<RuntimeState>, LxDosError> {
+        Ok(self.lock().changes.drain(..).collect())
+    }
+
+    fn send_input(&mut self, input: &GuestInput) -> Result<(), LxDosError> {
+        self.call(FakeOp::SendInput)?.inputs.push(input.clone());
+        Ok(())
+    }
+
+    fn fetch_screen(&mut self) -> Result<Screenshot, LxDosError> {
+        drop(self.call(FakeOp::FetchScreen)?);
+        Ok(Screenshot {
+            width: 1,
+            height: 1,
+            rgb: vec![0, 0, 0],
+        })
+    }
+}
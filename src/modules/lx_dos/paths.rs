@@ -0,0 +1,794 @@
+use crate::modules::lx_dos::config::{GuestConfig, SharedFolder};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Longest path, counted in UTF-16 units, that Windows programs accept without the
+/// `\\?\` prefix.
+const MAX_PATH: usize = 259;
+/// Longest file name NTFS allows, counted in UTF-16 units.
+const MAX_NAME: usize = 255;
+/// Characters that cannot appear in a Windows file name, besides control characters.
+const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+/// Device names Windows reserves in every directory, with or without an extension.
+const RESERVED_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+
+/// Why a path could not be translated.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PathError {
+    #[error("{0} is not an absolute path")]
+    Relative(String),
+    #[error("{0} is not inside a shared folder, so the guest cannot reach it")]
+    NotShared(String),
+    #[error("{0} is not on a shared folder drive")]
+    NotMapped(String),
+    #[error("{path} cannot be used in the guest: {name:?} {reason}")]
+    Unrepresentable {
+        path: String,
+        name: String,
+        reason: String,
+    },
+    #[error("{path} is ambiguous in the guest: {name:?} and {other:?} differ only in case")]
+    Ambiguous {
+        path: String,
+        name: String,
+        other: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Share {
+    drive: char,
+    host: PathBuf,
+}
+
+/// Translates paths between the host and the Windows guest, e.g.
+/// `/home/alice/work/report.docx` and `Z:\report.docx` for a folder shared from
+/// `/home/alice/work`.
+///
+/// The virtio-fs service of the guest mounts every shared folder on the last free
+/// drive letter, so the folders get `Z:`, `Y:`, ... in the order of the guest
+/// definition. Only shared folders are reachable from the guest; everything else is
+/// reported as `PathError::NotShared`.
+///
+/// Windows compares names case-insensitively while the host does not, so looking a
+/// guest path up picks the host file whatever its case, and a host path whose
+/// directory has another entry differing only in case is `PathError::Ambiguous`.
+/// Paths are normalized lexically; symlinks are not followed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathMap {
+    shares: Vec<Share>,
+}
+
+impl PathMap {
+    pub fn new(folders: &[SharedFolder]) -> Self {
+        // A: and B: are for floppies and C: is the system drive
+        let mut drives = ('D'..='Z').rev();
+        let mut shares = Vec::new();
+        for folder in folders {
+            let Some(drive) = drives.next() else {
+                log::warn!(
+                    "No drive letter left for shared folder {}, the guest cannot reach it",
+                    folder.name
+                );
+                continue;
+            };
+            shares.push(Share {
+                drive,
+                host: normalize(&folder.path),
+            });
+        }
+        Self { shares }
+    }
+
+    pub fn for_guest(config: &GuestConfig) -> Self {
+        Self::new(&config.shared_folders)
+    }
+
+    /// Drive letter of the shared folder whose host directory is `path`.
+    pub fn drive(&self, path: &Path) -> Option<char> {
+        let path = normalize(path);
+        self.shares
+            .iter()
+            .find(|share| share.host == path)
+            .map(|share| share.drive)
+    }
+
+    /// Translates an absolute host path into the path the guest sees it at.
+    ///
+    /// Paths too long for plain Windows paths get the `\\?\` prefix.
+    pub fn to_guest(&self, path: &Path) -> Result<String, PathError> {
+        let display = path.display().to_string();
+        if !path.is_absolute() {
+            return Err(PathError::Relative(display));
+        }
+        let path = normalize(path);
+        // 入れ子になった共有フォルダは一番深いものを使う
+        let Some((share, rest)) = self
+            .shares
+            .iter()
+            .filter_map(|share| Some((share, path.strip_prefix(&share.host).ok()?)))
+            .max_by_key(|(share, _)| share.host.components().count())
+        else {
+            return Err(PathError::NotShared(display));
+        };
+
+        let mut guest = format!("{}:\\", share.drive);
+        let mut dir = share.host.clone();
+        for (i, name) in rest.iter().enumerate() {
+            let unrepresentable = |reason: String| PathError::Unrepresentable {
+                path: display.clone(),
+                name: name.to_string_lossy().into_owned(),
+                reason,
+            };
+            let name = name
+                .to_str()
+                .ok_or_else(|| unrepresentable("is not valid UTF-8".to_string()))?;
+            check_name(name).map_err(unrepresentable)?;
+            if let Some(other) = case_twins(&dir, name)
+                .into_iter()
+                .find(|other| other != name)
+            {
+                return Err(PathError::Ambiguous {
+                    path: display,
+                    name: name.to_string(),
+                    other,
+                });
+            }
+            if i > 0 {
+                guest.push('\\');
+            }
+            guest.push_str(name);
+            dir.push(name);
+        }
+        if guest.encode_utf16().count() > MAX_PATH {
+            guest.insert_str(0, r"\\?\");
+        }
+        Ok(guest)
+    }
+
+    /// Translates an absolute guest path back into a host path.
+    ///
+    /// Accepts `Z:\dir`, `Z:/dir`, the `\\?\` and `\\.\` prefixes, and the
+    /// administrative shares `\\localhost\Z$\dir` of the guest itself. Names are
+    /// matched against the host case-insensitively as far as they exist there.
+    pub fn to_host(&self, path: &str) -> Result<PathBuf, PathError> {
+        let (drive, rest) = parse_guest(path)?;
+        let Some(share) = self
+            .shares
+            .iter()
+            .find(|share| share.drive.eq_ignore_ascii_case(&drive))
+        else {
+            return Err(PathError::NotMapped(path.to_string()));
+        };
+
+        // Windows と同じく `..` はドライブのルートで止まり、末尾のドットと空白は無視される
+        let mut names = Vec::new();
+        for name in rest.split('\\') {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    names.pop();
+                }
+                name => match name.trim_end_matches(['.', ' ']) {
+                    "" => {}
+                    name => names.push(name),
+                },
+            }
+        }
+
+        let mut host = share.host.clone();
+        let mut exists = true;
+        for name in names {
+            check_name(name).map_err(|reason| PathError::Unrepresentable {
+                path: path.to_string(),
+                name: name.to_string(),
+                reason,
+            })?;
+            let found = if exists {
+                let mut twins = case_twins(&host, name);
+                if twins.iter().any(|twin| twin == name) {
+                    Some(name.to_string())
+                } else if twins.len() > 1 {
+                    twins.sort();
+                    return Err(PathError::Ambiguous {
+                        path: path.to_string(),
+                        name: twins[0].clone(),
+                        other: twins[1].clone(),
+                    });
+                } else {
+                    twins.pop()
+                }
+            } else {
+                None
+            };
+            // 存在しない名前はそのまま使う。新しく作るファイルかもしれない
+            exists = found.is_some();
+            host.push(found.as_deref().unwrap_or(name));
+        }
+        Ok(host)
+    }
+}
+
+/// Splits a guest path into its drive letter and the rest, without the leading `\`.
+fn parse_guest(path: &str) -> Result<(char, String), PathError> {
+    let normalized = path.replace('/', "\\");
+    let not_mapped = || PathError::NotMapped(path.to_string());
+
+    let local = if let Some(rest) = normalized
+        .strip_prefix(r"\\?\")
+        .or_else(|| normalized.strip_prefix(r"\\.\"))
+    {
+        match strip_prefix_ignore_case(rest, r"UNC\") {
+            Some(unc) => return admin_share(unc).ok_or_else(not_mapped),
+            None => rest,
+        }
+    } else if let Some(unc) = normalized.strip_prefix(r"\\") {
+        return admin_share(unc).ok_or_else(not_mapped);
+    } else {
+        match drive_path(&normalized) {
+            Some(parsed) => return Ok(parsed),
+            None => return Err(PathError::Relative(path.to_string())),
+        }
+    };
+    drive_path(local).ok_or_else(not_mapped)
+}
+
+/// Parses `Z:\rest`. `Z:rest` is relative to the current directory of `Z:` and is
+/// not accepted.
+fn drive_path(path: &str) -> Option<(char, String)> {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some(':'), Some('\\')) if drive.is_ascii_alphabetic() => {
+            Some((drive, path[3..].to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Parses `server\Z$\rest`, which is the guest's own drive if `server` is the guest.
+fn admin_share(unc: &str) -> Option<(char, String)> {
+    let mut parts = unc.splitn(3, '\\');
+    let server = parts.next()?;
+    let share = parts.next()?;
+    let rest = parts.next().unwrap_or("");
+    if !["localhost", "127.0.0.1", "::1", "."]
+        .iter()
+        .any(|local| server.eq_ignore_ascii_case(local))
+    {
+        return None;
+    }
+    let mut chars = share.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some('$'), None) if drive.is_ascii_alphabetic() => {
+            Some((drive, rest.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &text[prefix.len()..])
+}
+
+/// Checks that `name` can be a Windows file name, returning why it cannot.
+fn check_name(name: &str) -> Result<(), String> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| ILLEGAL_CHARS.contains(c) || c.is_ascii_control())
+    {
+        return Err(format!("contains the character {:?}", c));
+    }
+    if is_reserved(name) {
+        return Err("is a reserved device name".to_string());
+    }
+    if name.ends_with(['.', ' ']) {
+        return Err("ends with a dot or a space".to_string());
+    }
+    if name.encode_utf16().count() > MAX_NAME {
+        return Err(format!("is longer than {} characters", MAX_NAME));
+    }
+    Ok(())
+}
+
+/// Whether `name` is a device such as `NUL`, `nul.txt` or `COM1`.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    let stem = stem.to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        return true;
+    }
+    let Some(number) = stem
+        .strip_prefix("COM")
+        .or_else(|| stem.strip_prefix("LPT"))
+    else {
+        return false;
+    };
+    let mut chars = number.chars();
+    matches!(
+        (chars.next(), chars.next()),
+        (Some('0'..='9' | '¹' | '²' | '³'), None)
+    )
+}
+
+/// Entries of `dir` whose names equal `name` ignoring case, including `name` itself.
+fn case_twins(dir: &Path, name: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let lower = name.to_lowercase();
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|entry| entry.to_lowercase() == lower)
+        .collect()
+}
+
+/// Resolves `.` and `..` without looking at the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn map(paths: &[&str]) -> PathMap {
+        let folders: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| SharedFolder {
+                name: format!("share{}", i),
+                path: PathBuf::from(path),
+                read_only: false,
+            })
+            .collect();
+        PathMap::new(&folders)
+    }
+
+    fn to_guest(map: &PathMap, path: &str) -> Result<String, PathError> {
+        map.to_guest(Path::new(path))
+    }
+
+    fn to_host(map: &PathMap, path: &str) -> Result<PathBuf, PathError> {
+        map.to_host(path)
+    }
+
+    fn unrepresentable(result: Result<impl std::fmt::Debug, PathError>) -> (String, String) {
+        match result {
+            Err(PathError::Unrepresentable { name, reason, .. }) => (name, reason),
+            other => panic!("expected Unrepresentable, got {:?}", other),
+        }
+    }
+
+    /// A directory with the given files, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(files: &[&str]) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "lx-dos-paths-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            for file in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "").unwrap();
+            }
+            Self(dir)
+        }
+
+        fn map(&self) -> PathMap {
+            map(&[self.0.to_str().unwrap()])
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn drives_count_down_from_z() {
+        // D: から Z: までの 23 個を使い切ったら、残りはゲストから見えない
+        let paths: Vec<_> = (0..24).map(|i| format!("/share/{}", i)).collect();
+        let paths: Vec<_> = paths.iter().map(String::as_str).collect();
+        let crowded = map(&paths);
+        assert_eq!(crowded.drive(Path::new("/share/22")), Some('D'));
+        assert_eq!(crowded.drive(Path::new("/share/23")), None);
+
+        let map = map(&["/home/alice/work", "/home/alice/Pictures", "/mnt/data/"]);
+        assert_eq!(map.drive(Path::new("/home/alice/work")), Some('Z'));
+        assert_eq!(map.drive(Path::new("/home/alice/Pictures")), Some('Y'));
+        assert_eq!(map.drive(Path::new("/mnt/./data")), Some('X'));
+        assert_eq!(map.drive(Path::new("/home/alice")), None);
+    }
+
+    #[test]
+    fn shared_paths_translate_both_ways() {
+        let map = map(&["/home/alice/work", "/home/alice/Pictures"]);
+        for (host, guest) in [
+            ("/home/alice/work", r"Z:\"),
+            ("/home/alice/work/report.docx", r"Z:\report.docx"),
+            ("/home/alice/Pictures/2024/beach.jpg", r"Y:\2024\beach.jpg"),
+            ("/home/alice/work/日本語/メモ.txt", r"Z:\日本語\メモ.txt"),
+        ] {
+            assert_eq!(to_guest(&map, host).unwrap(), guest);
+            assert_eq!(to_host(&map, guest).unwrap(), Path::new(host));
+        }
+        // ホスト側の . と .. はファイルシステムを見ずに畳む
+        assert_eq!(
+            to_guest(&map, "/home/alice/work/./drafts/../report.docx").unwrap(),
+            r"Z:\report.docx"
+        );
+    }
+
+    #[test]
+    fn nested_shares_use_the_deepest() {
+        for paths in [
+            ["/home/alice", "/home/alice/work"],
+            ["/home/alice/work", "/home/alice"],
+        ] {
+            let map = map(&paths);
+            let work = map.drive(Path::new("/home/alice/work")).unwrap();
+            let home = map.drive(Path::new("/home/alice")).unwrap();
+            assert_eq!(
+                to_guest(&map, "/home/alice/work/report.docx").unwrap(),
+                format!(r"{}:\report.docx", work)
+            );
+            assert_eq!(
+                to_guest(&map, "/home/alice/workshop/plan.txt").unwrap(),
+                format!(r"{}:\workshop\plan.txt", home)
+            );
+            assert_eq!(
+                to_guest(&map, "/home/alice/work").unwrap(),
+                format!(r"{}:\", work)
+            );
+        }
+    }
+
+    #[test]
+    fn unshared_paths_are_rejected() {
+        let map = map(&["/home/alice/work"]);
+        for path in [
+            "/etc/passwd",
+            "/home/alice",
+            // 名前の前方一致ではなく、パスの要素で比べる
+            "/home/alice/workshop/plan.txt",
+            "/home/alice/work/../secret.txt",
+        ] {
+            assert_eq!(
+                to_guest(&map, path),
+                Err(PathError::NotShared(path.to_string()))
+            );
+        }
+        assert_eq!(
+            to_guest(&map, "work/report.docx"),
+            Err(PathError::Relative("work/report.docx".to_string()))
+        );
+        assert_eq!(
+            to_guest(&PathMap::default(), "/home/alice/work")
+                .unwrap_err()
+                .to_string(),
+            "/home/alice/work is not inside a shared folder, so the guest cannot reach it"
+        );
+    }
+
+    #[test]
+    fn guest_path_forms() {
+        let map = map(&["/home/alice/work"]);
+        let report = Path::new("/home/alice/work/docs/report.docx");
+        for path in [
+            r"Z:\docs\report.docx",
+            r"z:\docs\report.docx",
+            "Z:/docs/report.docx",
+            r"Z:\docs/report.docx",
+            r"Z:\\docs\\\report.docx",
+            r"\\?\Z:\docs\report.docx",
+            r"\\.\Z:\docs\report.docx",
+            r"\\?\UNC\localhost\Z$\docs\report.docx",
+            r"\\.\UNC\localhost\Z$\docs\report.docx",
+            r"\\?\unc\LOCALHOST\z$\docs\report.docx",
+            r"\\localhost\Z$\docs\report.docx",
+            r"\\127.0.0.1\Z$\docs\report.docx",
+            "//localhost/Z$/docs/report.docx",
+        ] {
+            assert_eq!(to_host(&map, path).unwrap(), report, "{}", path);
+        }
+        assert_eq!(
+            to_host(&map, r"\\localhost\Z$").unwrap(),
+            Path::new("/home/alice/work")
+        );
+    }
+
+    #[test]
+    fn guest_paths_off_the_shares_are_rejected() {
+        let map = map(&["/home/alice/work"]);
+        for path in [
+            r"C:\Windows\notepad.exe",
+            r"Y:\report.docx",
+            r"\\fileserver\Z$\report.docx",
+            r"\\localhost\work\report.docx",
+            r"\\localhost\ZZ$\report.docx",
+            // \\.\ の後はデバイス名前空間で、管理共有ではない
+            r"\\.\Z$\report.docx",
+            r"\\?\UNC\fileserver\share\report.docx",
+            r"\\?\Volume{26a21bda-a627-11d7-9931-806e6f6e6963}\report.docx",
+            r"\\?\GLOBALROOT\Device\HarddiskVolume1",
+        ] {
+            assert_eq!(
+                to_host(&map, path),
+                Err(PathError::NotMapped(path.to_string())),
+                "{}",
+                path
+            );
+        }
+        // Z:report.docx は Z: のカレントディレクトリからの相対パス
+        for path in ["Z:report.docx", r"docs\report.docx", r"\report.docx", ""] {
+            assert_eq!(
+                to_host(&map, path),
+                Err(PathError::Relative(path.to_string())),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn dot_dot_stops_at_the_drive_root() {
+        let map = map(&["/home/alice/work"]);
+        let work = Path::new("/home/alice/work");
+        assert_eq!(to_host(&map, r"Z:\..").unwrap(), work);
+        assert_eq!(
+            to_host(&map, r"Z:\..\..\..\etc\passwd").unwrap(),
+            work.join("etc/passwd")
+        );
+        assert_eq!(
+            to_host(&map, r"\\?\Z:\docs\..\..\secret.txt").unwrap(),
+            work.join("secret.txt")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\docs\.\drafts\..\report.docx").unwrap(),
+            work.join("docs/report.docx")
+        );
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        let map = map(&["/home/alice/work"]);
+        let work = Path::new("/home/alice/work");
+        // Windows は名前の末尾のドットと空白を落とす
+        assert_eq!(
+            to_host(&map, r"Z:\report.docx.").unwrap(),
+            work.join("report.docx")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\docs . .\report.docx  ").unwrap(),
+            work.join("docs/report.docx")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\...\ \report.docx").unwrap(),
+            work.join("report.docx")
+        );
+
+        // ホスト側でそう名付けられたファイルはゲストからは開けない
+        for name in ["notes.", "notes ", "draft. "] {
+            let (found, reason) =
+                unrepresentable(to_guest(&map, &format!("/home/alice/work/{}", name)));
+            assert_eq!(found, name);
+            assert_eq!(reason, "ends with a dot or a space");
+        }
+    }
+
+    #[test]
+    fn reserved_names() {
+        let map = map(&["/home/alice/work"]);
+        for name in [
+            "NUL",
+            "nul",
+            "nul.txt",
+            "Nul.tar.gz",
+            "con",
+            "CON .txt",
+            "aux.log",
+            "prn",
+            "conin$",
+            "CONOUT$",
+            "COM1",
+            "com9.txt",
+            "LPT1",
+            "lpt5.doc",
+            "COM0",
+            "COM¹",
+            "lpt²",
+            "com³.txt",
+        ] {
+            let (found, reason) =
+                unrepresentable(to_guest(&map, &format!("/home/alice/work/{}", name)));
+            assert_eq!(found, name);
+            assert_eq!(reason, "is a reserved device name", "{}", name);
+            assert!(
+                to_host(&map, &format!(r"Z:\docs\{}", name)).is_err(),
+                "{}",
+                name
+            );
+        }
+        for name in [
+            "null",
+            "console.txt",
+            "COM10",
+            "COM",
+            "lpt",
+            "auxiliary",
+            "nul_",
+            "COM⁴",
+            "conin",
+            "x.nul",
+        ] {
+            assert_eq!(
+                to_guest(&map, &format!("/home/alice/work/{}", name)).unwrap(),
+                format!(r"Z:\{}", name)
+            );
+        }
+    }
+
+    #[test]
+    fn illegal_characters_and_long_names() {
+        let map = map(&["/home/alice/work"]);
+        for (name, c) in [
+            ("a:b", ':'),
+            ("what?.txt", '?'),
+            ("<tag>", '<'),
+            ("a|b", '|'),
+            ("quote\"d", '"'),
+            ("star*", '*'),
+            (r"back\slash", '\\'),
+            ("bell\u{7}", '\u{7}'),
+        ] {
+            let (_, reason) =
+                unrepresentable(to_guest(&map, &format!("/home/alice/work/{}", name)));
+            assert_eq!(reason, format!("contains the character {:?}", c));
+        }
+
+        let longest = "n".repeat(MAX_NAME);
+        assert!(to_guest(&map, &format!("/home/alice/work/{}", longest)).is_ok());
+        let (_, reason) =
+            unrepresentable(to_guest(&map, &format!("/home/alice/work/{}n", longest)));
+        assert_eq!(reason, "is longer than 255 characters");
+        // UTF-16 で数える。絵文字は 2 単位
+        let (_, reason) = unrepresentable(to_guest(
+            &map,
+            &format!("/home/alice/work/{}", "😀".repeat(128)),
+        ));
+        assert_eq!(reason, "is longer than 255 characters");
+    }
+
+    #[test]
+    fn names_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let map = map(&["/home/alice/work"]);
+        let path = Path::new("/home/alice/work").join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        let (name, reason) = unrepresentable(map.to_guest(&path));
+        assert_eq!(name, "caf\u{fffd}.txt");
+        assert_eq!(reason, "is not valid UTF-8");
+    }
+
+    #[test]
+    fn long_paths_get_the_prefix() {
+        let map = map(&["/home/alice/work"]);
+        let dir = "d".repeat(200);
+        // Z:\ の 3 文字、ディレクトリ、区切り、ファイル名でちょうど MAX_PATH
+        let fits = format!("/home/alice/work/{}/{}", dir, "f".repeat(MAX_PATH - 204));
+        let guest = to_guest(&map, &fits).unwrap();
+        assert_eq!(guest.len(), MAX_PATH);
+        assert!(guest.starts_with(r"Z:\"));
+
+        let too_long = format!("{}f", fits);
+        let guest = to_guest(&map, &too_long).unwrap();
+        assert_eq!(guest.len(), MAX_PATH + 1 + 4);
+        assert!(guest.starts_with(r"\\?\Z:\"));
+        assert_eq!(to_host(&map, &guest).unwrap(), Path::new(&too_long));
+
+        // 長さは UTF-16 で数える
+        let wide = format!("/home/alice/work/{}/{}", dir, "é".repeat(MAX_PATH - 204));
+        assert!(to_guest(&map, &wide).unwrap().starts_with(r"Z:\"));
+    }
+
+    #[test]
+    fn case_twins_are_ambiguous_from_the_host() {
+        let scratch = Scratch::new(&["Report.docx", "report.docx", "Notes.txt", "docs/a.txt"]);
+        let map = scratch.map();
+        let host = |name: &str| scratch.0.join(name).to_str().unwrap().to_string();
+        for (name, other) in [
+            ("Report.docx", "report.docx"),
+            ("report.docx", "Report.docx"),
+        ] {
+            let err = to_guest(&map, &host(name)).unwrap_err();
+            assert_eq!(
+                err,
+                PathError::Ambiguous {
+                    path: host(name),
+                    name: name.to_string(),
+                    other: other.to_string(),
+                }
+            );
+        }
+        assert_eq!(to_guest(&map, &host("Notes.txt")).unwrap(), r"Z:\Notes.txt");
+        assert_eq!(
+            to_guest(&map, &host("docs/a.txt")).unwrap(),
+            r"Z:\docs\a.txt"
+        );
+        // まだ無いファイルに双子はいない
+        assert_eq!(to_guest(&map, &host("NEW.txt")).unwrap(), r"Z:\NEW.txt");
+    }
+
+    #[test]
+    fn case_twins_are_ambiguous_from_the_guest() {
+        let scratch = Scratch::new(&[
+            "Report.docx",
+            "report.docx",
+            "Notes.txt",
+            "Docs/a.txt",
+            "Old/x.txt",
+            "old/x.txt",
+        ]);
+        let map = scratch.map();
+        let root = &scratch.0;
+        // 大文字小文字まで一致すれば曖昧ではない
+        assert_eq!(
+            to_host(&map, r"Z:\report.docx").unwrap(),
+            root.join("report.docx")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\Report.docx").unwrap(),
+            root.join("Report.docx")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\REPORT.DOCX"),
+            Err(PathError::Ambiguous {
+                path: r"Z:\REPORT.DOCX".to_string(),
+                name: "Report.docx".to_string(),
+                other: "report.docx".to_string(),
+            })
+        );
+        assert!(matches!(
+            to_host(&map, r"Z:\OLD\x.txt"),
+            Err(PathError::Ambiguous { .. })
+        ));
+        assert_eq!(
+            to_host(&map, r"Z:\old\X.TXT").unwrap(),
+            root.join("old/x.txt")
+        );
+
+        // 一つしかなければホストの綴りに合わせる
+        assert_eq!(
+            to_host(&map, r"Z:\notes.TXT").unwrap(),
+            root.join("Notes.txt")
+        );
+        assert_eq!(
+            to_host(&map, r"Z:\docs\A.TXT").unwrap(),
+            root.join("Docs/a.txt")
+        );
+        // 無い名前は書かれたまま使う
+        assert_eq!(
+            to_host(&map, r"Z:\DOCS\New\File.txt").unwrap(),
+            root.join("Docs/New/File.txt")
+        );
+    }
+}
@@ -0,0 +1,740 @@
+use super::qmp::{self, QmpClient};
+use super::runtime::{GuestInput, GuestRuntime, PointerButton, RuntimeState, Screenshot};
+use crate::LxDosError;
+use crate::utils::logs::{self, RotatingLog};
+use crate::utils::process;
+use serde_json::{Value, json};
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod command_line;
+pub use command_line::{
+    Accel, Chardev, DiskFormat, Display, Drive, DriveMedia, FsShare, Nic, QemuCommand, QemuLaunch,
+    SerialPort, VirtiofsCommand,
+};
+
+/// Emulator the guest runs in unless `QemuCommand::binary` says otherwise.
+pub const QEMU_BINARY: &str = "qemu-system-x86_64";
+/// Tool used to create and inspect disk images.
+pub const QEMU_IMG: &str = "qemu-img";
+/// Log file in the profile's log directory that QEMU and `virtiofsd` write into.
+pub const GUEST_LOG: &str = "guest.log";
+/// Where distributions install the OVMF UEFI firmware code, most common first.
+const FIRMWARE_PATHS: &[&str] = &[
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    "/usr/share/qemu/edk2-x86_64-code.fd",
+];
+/// Where distributions install `virtiofsd`, which is rarely in `$PATH`.
+const VIRTIOFSD_PATHS: &[&str] = &[
+    "/usr/libexec/virtiofsd",
+    "/usr/lib/qemu/virtiofsd",
+    "/usr/lib/virtiofsd",
+];
+/// How long QEMU gets to create its QMP socket after being spawned.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long Windows gets to shut down after `system_powerdown` before QEMU is killed.
+const POWERDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long QEMU gets to exit after closing its QMP connection before it is killed.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+/// `savevm` writes the whole guest memory and can take a while.
+const SAVEVM_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Locates the OVMF firmware Windows boots with, if one is installed.
+pub fn find_firmware() -> Option<PathBuf> {
+    FIRMWARE_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+/// Locates the `virtiofsd` that serves shared folders, if one is installed.
+pub fn find_virtiofsd() -> Option<PathBuf> {
+    process::find_in_path("virtiofsd").or_else(|| {
+        VIRTIOFSD_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_file())
+    })
+}
+
+/// Creates an empty qcow2 disk image of `size_gib` GiB at `path` with `qemu-img`,
+/// returning whether it did.
+///
+/// A qcow2 image of that size already at `path` is kept, so that a setup that failed
+/// after creating it can be retried. Any other file there is an error.
+pub fn create_disk_image(path: &Path, size_gib: u32) -> Result<bool, LxDosError> {
+    if path.exists() {
+        let (format, size) = disk_image_info(path)?;
+        if format == "qcow2" && size == u64::from(size_gib) << 30 {
+            log::info!("Reusing the disk image {}", path.display());
+            return Ok(false);
+        }
+        return Err(LxDosError::Message(format!(
+            "{} already exists as a {} image of {} bytes, remove it or choose another path",
+            path.display(),
+            format,
+            size
+        )));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let output = Command::new(QEMU_IMG)
+        .args(["create", "-f", "qcow2"])
+        .arg(path)
+        .arg(format!("{}G", size_gib))
+        .output()
+        .map_err(|e| LxDosError::Message(format!("Failed to run qemu-img: {}", e)))?;
+    if !output.status.success() {
+        return Err(LxDosError::Message(format!(
+            "qemu-img failed to create {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(true)
+}
+
+/// Format and virtual size in bytes of the disk image at `path`, from `qemu-img info`.
+fn disk_image_info(path: &Path) -> Result<(String, u64), LxDosError> {
+    let output = Command::new(QEMU_IMG)
+        .args(["info", "--output=json", "--force-share"])
+        .arg(path)
+        .output()
+        .map_err(|e| LxDosError::Message(format!("Failed to run qemu-img: {}", e)))?;
+    if !output.status.success() {
+        return Err(LxDosError::Message(format!(
+            "qemu-img cannot read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let info: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| LxDosError::Message(format!("Invalid output of qemu-img info: {}", e)))?;
+    match (info["format"].as_str(), info["virtual-size"].as_u64()) {
+        (Some(format), Some(size)) => Ok((format.to_string(), size)),
+        _ => Err(LxDosError::Message(format!(
+            "qemu-img info did not report the format and size of {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Runs the guest in a `qemu-system-x86_64` child process controlled over QMP, next
+/// to a `virtiofsd` per shared folder.
+///
+/// The output of both goes to the log at `log_path`.
+#[derive(Debug)]
+pub struct QemuRuntime {
+    launch: QemuLaunch,
+    log_path: PathBuf,
+    qmp_socket: PathBuf,
+    child: Option<Child>,
+    virtiofsd: Vec<Child>,
+    qmp: Option<QmpClient>,
+}
+
+impl QemuRuntime {
+    pub fn new(launch: QemuLaunch, log_path: PathBuf) -> Self {
+        Self {
+            qmp_socket: launch.qemu.qmp_socket().to_path_buf(),
+            launch,
+            log_path,
+            child: None,
+            virtiofsd: Vec::new(),
+            qmp: None,
+        }
+    }
+
+    /// Saves the running guest into the internal snapshot `name` of its disk image.
+    pub fn save_snapshot(&mut self, name: &str) -> Result<(), LxDosError> {
+        let output = self.qmp()?.execute_timeout(
+            "human-monitor-command",
+            Some(json!({ "command-line": format!("savevm {}", name) })),
+            SAVEVM_TIMEOUT,
+        )?;
+        // HMP reports failures as text instead of a QMP error.
+        match output.as_str() {
+            Some(text) if !text.trim().is_empty() => Err(LxDosError::Message(format!(
+                "savevm {} failed: {}",
+                name,
+                text.trim()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn qmp(&mut self) -> Result<&mut QmpClient, LxDosError> {
+        self.qmp
+            .as_mut()
+            .ok_or_else(|| LxDosError::Message("QEMU is not running".to_string()))
+    }
+
+    /// Returns the exit status if the QEMU process has exited.
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, LxDosError> {
+        match self.child.as_mut() {
+            Some(child) => Ok(child.try_wait()?),
+            None => Ok(None),
+        }
+    }
+
+    fn connect_qmp(&mut self) -> Result<QmpClient, LxDosError> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Err(LxDosError::Message(format!(
+                    "QEMU exited during startup with {}",
+                    status
+                )));
+            }
+            match QmpClient::connect(&self.qmp_socket) {
+                Ok(qmp) => return Ok(qmp),
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Starts a `virtiofsd` per shared folder and waits until each one listens.
+    fn start_virtiofsd(&mut self, log: &Arc<Mutex<RotatingLog>>) -> Result<(), LxDosError> {
+        for virtiofsd in &self.launch.virtiofsd {
+            let mut child = virtiofsd
+                .to_command()
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| {
+                    LxDosError::Message(format!(
+                        "Failed to run {} for {}: {}",
+                        virtiofsd.binary.display(),
+                        virtiofsd.shared_dir.display(),
+                        e
+                    ))
+                })?;
+            capture_output(log, "virtiofsd", &mut child);
+            self.virtiofsd.push(child);
+            let deadline = Instant::now() + CONNECT_TIMEOUT;
+            while !virtiofsd.socket.exists() {
+                if Instant::now() >= deadline {
+                    return Err(LxDosError::Message(format!(
+                        "virtiofsd did not create {} in time",
+                        virtiofsd.socket.display()
+                    )));
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets about a QEMU that exited with `status` and reports how it ended.
+    fn exited(&mut self, status: ExitStatus) -> RuntimeState {
+        self.reap();
+        if status.success() {
+            RuntimeState::Stopped
+        } else {
+            RuntimeState::Crashed(format!("QEMU exited with {}", status))
+        }
+    }
+
+    /// Kills QEMU and the `virtiofsd`s if they are still running and forgets about them.
+    fn reap(&mut self) {
+        self.qmp = None;
+        let children = self.child.take().into_iter().map(|child| ("QEMU", child));
+        let virtiofsd = self.virtiofsd.drain(..).map(|child| ("virtiofsd", child));
+        for (name, mut child) in children.chain(virtiofsd) {
+            if let Err(e) = child.kill() {
+                log::error!("Failed to kill {}: {}", name, e);
+            }
+            if let Err(e) = child.wait() {
+                log::error!("Failed to wait for {}: {}", name, e);
+            }
+        }
+        let sockets = self
+            .launch
+            .virtiofsd
+            .iter()
+            .map(|virtiofsd| &virtiofsd.socket);
+        for socket in std::iter::once(&self.qmp_socket).chain(sockets) {
+            if let Err(e) = fs::remove_file(socket)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                log::error!("Failed to remove {}: {}", socket.display(), e);
+            }
+        }
+    }
+}
+
+impl GuestRuntime for QemuRuntime {
+    fn name(&self) -> &str {
+        "qemu"
+    }
+
+    fn start(&mut self) -> Result<(), LxDosError> {
+        if self.child.is_some() {
+            return Err(LxDosError::Message("QEMU is already running".to_string()));
+        }
+        // A socket left behind by a crashed QEMU would make the new one fail to bind.
+        self.reap();
+        let log = Arc::new(Mutex::new(RotatingLog::open(&self.log_path)?));
+        if let Err(e) = self.start_virtiofsd(&log) {
+            self.reap();
+            return Err(e);
+        }
+
+        let binary = self.launch.qemu.program().to_path_buf();
+        let child = self
+            .launch
+            .qemu
+            .to_command()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                self.reap();
+                return Err(LxDosError::Message(format!(
+                    "Failed to run {}: {}",
+                    binary.display(),
+                    e
+                )));
+            }
+        };
+        capture_output(&log, "qemu", &mut child);
+        self.child = Some(child);
+
+        match self.connect_qmp() {
+            Ok(qmp) => {
+                self.qmp = Some(qmp);
+                Ok(())
+            }
+            Err(e) => {
+                self.reap();
+                Err(e)
+            }
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), LxDosError> {
+        if self.child.is_none() {
+            return Ok(());
+        }
+        if let Err(e) = self.qmp()?.execute("system_powerdown", None) {
+            log::warn!("system_powerdown failed, killing QEMU: {}", e);
+            self.reap();
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + POWERDOWN_TIMEOUT;
+        while self.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                log::warn!(
+                    "Guest did not power down within {:?}, killing QEMU",
+                    POWERDOWN_TIMEOUT
+                );
+                break;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+        self.reap();
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), LxDosError> {
+        self.qmp()?.execute("stop", None).map(|_| ())
+    }
+
+    fn resume(&mut self) -> Result<(), LxDosError> {
+        self.qmp()?.execute("cont", None).map(|_| ())
+    }
+
+    fn query_state(&mut self) -> Result<RuntimeState, LxDosError> {
+        if self.child.is_none() {
+            return Ok(RuntimeState::Stopped);
+        }
+        if let Some(status) = self.try_wait()? {
+            return Ok(self.exited(status));
+        }
+        let status = self.qmp()?.execute("query-status", None)?;
+        Ok(qmp::runtime_state_from_status(
+            status["status"].as_str().unwrap_or_default(),
+        ))
+    }
+
+    fn watch(&self) -> Option<OwnedFd> {
+        self.qmp.as_ref()?.watch()
+    }
+
+    fn poll_changes(&mut self) -> Result<Vec<RuntimeState>, LxDosError> {
+        let Some(qmp) = self.qmp.as_mut() else {
+            return Ok(Vec::new());
+        };
+        let mut changes: Vec<_> = qmp
+            .poll_events()?
+            .iter()
+            .filter_map(qmp::QmpEvent::runtime_state)
+            .collect();
+        // QEMU は終了するときに QMP の接続を閉じる。電源オフでもクラッシュでも同じ
+        let closed = qmp.is_closed();
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                changes.push(self.exited(status));
+                break;
+            }
+            if !closed {
+                break;
+            }
+            if Instant::now() >= deadline {
+                self.reap();
+                changes.push(RuntimeState::Crashed(
+                    "QEMU closed its QMP connection".to_string(),
+                ));
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(changes)
+    }
+
+    fn send_input(&mut self, input: &GuestInput) -> Result<(), LxDosError> {
+        match input {
+            GuestInput::Keys(keys) => self.send_keys(keys.iter().map(String::as_str)),
+            GuestInput::Text(text) => {
+                for c in text.chars() {
+                    let keys = qcodes_for_char(c).ok_or_else(|| {
+                        LxDosError::Message(format!("Cannot type {:?} into the guest", c))
+                    })?;
+                    self.send_keys(keys.iter().copied())?;
+                }
+                Ok(())
+            }
+            GuestInput::PointerMove { x, y } => self.send_input_events(json!([
+                { "type": "abs", "data": { "axis": "x", "value": x } },
+                { "type": "abs", "data": { "axis": "y", "value": y } },
+            ])),
+            GuestInput::PointerButton { button, pressed } => {
+                let button = match button {
+                    PointerButton::Left => "left",
+                    PointerButton::Middle => "middle",
+                    PointerButton::Right => "right",
+                };
+                self.send_input_events(json!([
+                    { "type": "btn", "data": { "button": button, "down": pressed } },
+                ]))
+            }
+        }
+    }
+
+    fn fetch_screen(&mut self) -> Result<Screenshot, LxDosError> {
+        let path = self.qmp_socket.with_extension("ppm");
+        self.qmp()?.execute(
+            "screendump",
+            Some(json!({ "filename": path.to_string_lossy() })),
+        )?;
+        let data = fs::read(&path);
+        if let Err(e) = fs::remove_file(&path) {
+            log::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+        parse_ppm(&data?)
+    }
+}
+
+impl QemuRuntime {
+    fn send_keys<'a>(&mut self, keys: impl Iterator<Item = &'a str>) -> Result<(), LxDosError> {
+        let keys: Vec<Value> = keys
+            .map(|key| json!({ "type": "qcode", "data": key }))
+            .collect();
+        self.qmp()?
+            .execute("send-key", Some(json!({ "keys": keys })))
+            .map(|_| ())
+    }
+
+    fn send_input_events(&mut self, events: Value) -> Result<(), LxDosError> {
+        self.qmp()?
+            .execute("input-send-event", Some(json!({ "events": events })))
+            .map(|_| ())
+    }
+}
+
+impl Drop for QemuRuntime {
+    fn drop(&mut self) {
+        self.reap();
+    }
+}
+
+/// Copies the output of `child` into `log`, tagged with `name`.
+fn capture_output(log: &Arc<Mutex<RotatingLog>>, name: &str, child: &mut Child) {
+    if let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) {
+        let source = logs::Source {
+            window: name.to_string(),
+            pid: child.id(),
+        };
+        logs::capture_shared(Arc::clone(log), source, stdout, stderr);
+    }
+}
+
+/// Keys to press together to type `c` on a US layout.
+fn qcodes_for_char(c: char) -> Option<Vec<&'static str>> {
+    const PLAIN: &[(char, &str)] = &[
+        (' ', "spc"),
+        ('\n', "ret"),
+        ('\t', "tab"),
+        ('-', "minus"),
+        ('=', "equal"),
+        ('[', "bracket_left"),
+        (']', "bracket_right"),
+        ('\\', "backslash"),
+        (';', "semicolon"),
+        ('\'', "apostrophe"),
+        ('`', "grave_accent"),
+        (',', "comma"),
+        ('.', "dot"),
+        ('/', "slash"),
+    ];
+    const SHIFTED: &[(char, &str)] = &[
+        ('!', "1"),
+        ('@', "2"),
+        ('#', "3"),
+        ('$', "4"),
+        ('%', "5"),
+        ('^', "6"),
+        ('&', "7"),
+        ('*', "8"),
+        ('(', "9"),
+        (')', "0"),
+        ('_', "minus"),
+        ('+', "equal"),
+        ('{', "bracket_left"),
+        ('}', "bracket_right"),
+        ('|', "backslash"),
+        (':', "semicolon"),
+        ('"', "apostrophe"),
+        ('~', "grave_accent"),
+        ('<', "comma"),
+        ('>', "dot"),
+        ('?', "slash"),
+    ];
+    const LETTERS: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+
+    let lookup = |table: &[(char, &'static str)]| {
+        table
+            .iter()
+            .find(|(key, _)| *key == c)
+            .map(|(_, qcode)| *qcode)
+    };
+    let letter = |c: char| LETTERS.find(c).map(|i| &LETTERS[i..i + 1]);
+
+    if let Some(qcode) = letter(c).or_else(|| lookup(PLAIN)) {
+        return Some(vec![qcode]);
+    }
+    let shifted = if c.is_ascii_uppercase() {
+        letter(c.to_ascii_lowercase())
+    } else {
+        lookup(SHIFTED)
+    };
+    shifted.map(|qcode| vec!["shift", qcode])
+}
+
+/// Decodes the binary PPM (`P6`) written by `screendump`.
+fn parse_ppm(data: &[u8]) -> Result<Screenshot, LxDosError> {
+    let invalid = || LxDosError::Message("Invalid PPM screendump".to_string());
+
+    let mut pos = 0;
+    let mut fields = Vec::with_capacity(4);
+    while fields.len() < 4 {
+        // Skip whitespace and comments between header fields.
+        while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+            if data[pos] == b'#' {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid());
+        }
+        fields.push(std::str::from_utf8(&data[start..pos]).map_err(|_| invalid())?);
+    }
+    // Exactly one whitespace byte separates the header from the pixels.
+    pos += 1;
+
+    let parse = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+    if fields[0] != "P6" || parse(fields[3])? != 255 {
+        return Err(invalid());
+    }
+    let (width, height) = (parse(fields[1])?, parse(fields[2])?);
+    let len = width as usize * height as usize * 3;
+    let rgb = data.get(pos..pos + len).ok_or_else(invalid)?.to_vec();
+    Ok(Screenshot { width, height, rgb })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    /// A stand-in for `qemu-system-x86_64`: it runs until the file `exit` appears in its
+    /// directory and then exits with the status written there. The test itself plays
+    /// the QMP side on the socket.
+    struct FakeQemu {
+        dir: PathBuf,
+        runtime: Option<QemuRuntime>,
+    }
+
+    impl FakeQemu {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "lx-dos-qemu-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let script = dir.join("qemu");
+            fs::write(
+                &script,
+                format!(
+                    "#!/bin/sh\n\
+                     touch '{dir}/started'\n\
+                     while [ ! -e '{dir}/exit' ]; do sleep 0.05; done\n\
+                     exit \"$(cat '{dir}/exit')\"\n",
+                    dir = dir.display()
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+            let launch = QemuLaunch {
+                virtiofsd: Vec::new(),
+                qemu: QemuCommand::new("test", dir.join("qmp.sock")).binary(script),
+            };
+            let runtime = QemuRuntime::new(launch, dir.join("guest.log"));
+            Self {
+                dir,
+                runtime: Some(runtime),
+            }
+        }
+
+        /// Starts the runtime and answers its QMP handshake, returning the connection.
+        fn start(&mut self) -> UnixStream {
+            let _ = fs::remove_file(self.dir.join("started"));
+            let _ = fs::remove_file(self.dir.join("exit"));
+            let mut runtime = self.runtime.take().unwrap();
+            let starting = thread::spawn(move || runtime.start().map(|()| runtime));
+            // 起動時に古いソケットが消されるので、QEMU が動き出してから待ち受ける
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !self.dir.join("started").exists() {
+                assert!(Instant::now() < deadline, "the fake QEMU did not start");
+                thread::sleep(Duration::from_millis(10));
+            }
+            let listener = UnixListener::bind(self.dir.join("qmp.sock")).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            writeln!(
+                stream,
+                r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#
+            )
+            .unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut line)
+                .unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            writeln!(stream, "{}", json!({ "return": {}, "id": request["id"] })).unwrap();
+            self.runtime = Some(starting.join().unwrap().unwrap());
+            stream
+        }
+
+        /// Makes QEMU exit with `status` after closing its QMP connection.
+        fn exit(&self, qmp: UnixStream, status: i32) {
+            drop(qmp);
+            fs::write(self.dir.join("exit"), status.to_string()).unwrap();
+        }
+
+        fn runtime(&mut self) -> &mut QemuRuntime {
+            self.runtime.as_mut().unwrap()
+        }
+
+        /// Polls the runtime until it noticed that QEMU has exited.
+        fn changes_until_exit(&mut self) -> Vec<RuntimeState> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut changes = Vec::new();
+            while self.runtime().child.is_some() {
+                assert!(Instant::now() < deadline, "QEMU was not reaped");
+                changes.extend(self.runtime().poll_changes().unwrap());
+                thread::sleep(Duration::from_millis(10));
+            }
+            changes
+        }
+    }
+
+    impl Drop for FakeQemu {
+        fn drop(&mut self) {
+            let _ = fs::write(self.dir.join("exit"), "0");
+            drop(self.runtime.take());
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn a_guest_that_powered_off_can_be_started_again() {
+        let mut qemu = FakeQemu::new();
+        for _ in 0..2 {
+            let mut qmp = qemu.start();
+            assert_eq!(qemu.runtime().poll_changes().unwrap(), []);
+            let (tx, ready) = mpsc::channel();
+            process::on_readable(qemu.runtime().watch().unwrap(), move || {
+                tx.send(()).unwrap()
+            });
+            assert!(ready.recv_timeout(Duration::from_millis(100)).is_err());
+            let shutdown = json!({
+                "event": "SHUTDOWN",
+                "data": { "guest": true },
+                "timestamp": { "seconds": 1, "microseconds": 0 },
+            });
+            writeln!(qmp, "{}", shutdown).unwrap();
+            ready.recv_timeout(Duration::from_secs(5)).unwrap();
+            qemu.exit(qmp, 0);
+            assert_eq!(
+                qemu.changes_until_exit(),
+                [RuntimeState::Stopped, RuntimeState::Stopped]
+            );
+            assert_eq!(qemu.runtime().query_state().unwrap(), RuntimeState::Stopped);
+            assert!(qemu.runtime().watch().is_none());
+        }
+    }
+
+    #[test]
+    fn qemu_exiting_with_an_error_is_a_crash() {
+        let mut qemu = FakeQemu::new();
+        let qmp = qemu.start();
+        qemu.exit(qmp, 1);
+        assert!(matches!(
+            qemu.changes_until_exit().as_slice(),
+            [RuntimeState::Crashed(reason)] if reason.contains("exit status: 1")
+        ));
+        drop(qemu.start());
+    }
+}
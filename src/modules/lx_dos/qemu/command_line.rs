@@ -0,0 +1,763 @@
+use super::QEMU_BINARY;
+use crate::modules::lx_dos::config::{ConfigError, DisplayMode, GuestConfig, NetworkMode};
+use crate::modules::lx_dos::qga;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How QEMU executes guest code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accel {
+    /// Hardware virtualization through `/dev/kvm`.
+    Kvm,
+    /// Software emulation, slow but available everywhere.
+    Tcg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    Qcow2,
+    Raw,
+}
+
+impl DiskFormat {
+    /// Guesses the format from the file extension; anything but `.qcow2` is raw.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("qcow2") => Self::Qcow2,
+            _ => Self::Raw,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Qcow2 => "qcow2",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveMedia {
+    Disk,
+    Cdrom,
+}
+
+/// An image attached to the machine's AHCI controller, which Windows has a driver for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drive {
+    pub file: PathBuf,
+    pub format: DiskFormat,
+    pub media: DriveMedia,
+    /// Lower boots first; drives without one are not tried.
+    pub boot_index: Option<u32>,
+}
+
+/// A network card, an e1000e for the same reason as the AHCI drives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Nic {
+    /// NAT through QEMU's user-mode network stack.
+    User,
+    /// Attached to the host bridge of this name through `qemu-bridge-helper`.
+    Bridge(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Display {
+    Gtk,
+    /// A SPICE server listening on a unix socket.
+    Spice(PathBuf),
+    /// A VNC server listening on a unix socket.
+    Vnc(PathBuf),
+    None,
+}
+
+/// A character device backed by a unix socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chardev {
+    pub id: String,
+    pub path: PathBuf,
+    /// QEMU listens on `path` instead of connecting to it.
+    pub server: bool,
+}
+
+/// A virtio-serial port the guest opens by `name`, such as the guest agent's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialPort {
+    pub name: String,
+    pub chardev: Chardev,
+}
+
+/// A host directory exported by a `virtiofsd` listening on `socket`, which the guest
+/// mounts by `tag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsShare {
+    pub tag: String,
+    pub socket: PathBuf,
+}
+
+/// The command line of one QEMU process.
+///
+/// Built from typed parts so that every launch, and every `lx-dos start --dry-run`,
+/// spells options the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QemuCommand {
+    binary: PathBuf,
+    name: String,
+    accel: Accel,
+    cpu: String,
+    memory_mib: u32,
+    cpus: u32,
+    firmware: Option<PathBuf>,
+    drives: Vec<Drive>,
+    nics: Vec<Nic>,
+    display: Display,
+    serial_ports: Vec<SerialPort>,
+    shares: Vec<FsShare>,
+    qmp_socket: PathBuf,
+}
+
+impl QemuCommand {
+    /// A KVM machine with 1 GiB of memory and one CPU, controlled over QMP at
+    /// `qmp_socket`.
+    pub fn new(name: impl Into<String>, qmp_socket: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: PathBuf::from(QEMU_BINARY),
+            name: name.into(),
+            accel: Accel::Kvm,
+            cpu: "host".to_string(),
+            memory_mib: 1024,
+            cpus: 1,
+            firmware: None,
+            drives: Vec::new(),
+            nics: Vec::new(),
+            display: Display::None,
+            serial_ports: Vec::new(),
+            shares: Vec::new(),
+            qmp_socket: qmp_socket.into(),
+        }
+    }
+
+    /// The command for `config`, with sockets in `runtime_dir` and booting `firmware`
+    /// if one is given, or the BIOS otherwise. The guest agent listens on
+    /// `qga::SOCKET_NAME` there.
+    ///
+    /// Fails for a definition that `GuestConfig::validate` would reject in a way that
+    /// changes the machine, rather than quietly leaving the part out.
+    pub fn for_guest(
+        config: &GuestConfig,
+        runtime_dir: &Path,
+        firmware: Option<&Path>,
+    ) -> Result<Self, ConfigError> {
+        let mut command = Self::new(&config.name, runtime_dir.join("qmp.sock"))
+            .memory_mib(config.memory_mib)
+            .cpus(config.cpus)
+            .display(match config.display {
+                DisplayMode::Gtk => Display::Gtk,
+                DisplayMode::Spice => Display::Spice(runtime_dir.join("spice.sock")),
+                DisplayMode::Vnc => Display::Vnc(runtime_dir.join("vnc.sock")),
+                DisplayMode::None => Display::None,
+            });
+        if let Some(firmware) = firmware {
+            command = command.firmware(firmware);
+        }
+        // インストーラーがあれば、そちらから先に起動する
+        if let Some(installer) = &config.installer {
+            command = command.drive(Drive {
+                file: installer.clone(),
+                format: DiskFormat::Raw,
+                media: DriveMedia::Cdrom,
+                boot_index: Some(0),
+            });
+        }
+        command = command.drive(Drive {
+            file: config.disk_image.clone(),
+            format: DiskFormat::from_path(&config.disk_image),
+            media: DriveMedia::Disk,
+            boot_index: Some(1),
+        });
+        match (config.network.mode, &config.network.bridge) {
+            (NetworkMode::User, _) => command = command.nic(Nic::User),
+            (NetworkMode::Bridge, Some(bridge)) => {
+                command = command.nic(Nic::Bridge(bridge.clone()))
+            }
+            (NetworkMode::Bridge, None) => {
+                return Err(ConfigError {
+                    key: Some("network.bridge".to_string()),
+                    line: None,
+                    message: "is required when mode is \"bridge\"".to_string(),
+                });
+            }
+            (NetworkMode::None, _) => {}
+        }
+        for (i, folder) in config.shared_folders.iter().enumerate() {
+            command = command.share(FsShare {
+                tag: folder.name.clone(),
+                socket: runtime_dir.join(format!("virtiofs-{}.sock", i)),
+            });
+        }
+        Ok(command.serial_port(SerialPort {
+            name: qga::PORT_NAME.to_string(),
+            chardev: Chardev {
+                id: "qga0".to_string(),
+                path: runtime_dir.join(qga::SOCKET_NAME),
+                server: true,
+            },
+        }))
+    }
+
+    pub fn binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+
+    pub fn accel(mut self, accel: Accel) -> Self {
+        self.accel = accel;
+        self
+    }
+
+    /// CPU model, `host` by default. Only KVM can run `host`.
+    pub fn cpu(mut self, cpu: impl Into<String>) -> Self {
+        self.cpu = cpu.into();
+        self
+    }
+
+    pub fn memory_mib(mut self, memory_mib: u32) -> Self {
+        self.memory_mib = memory_mib;
+        self
+    }
+
+    pub fn cpus(mut self, cpus: u32) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// UEFI firmware code, mapped read-only as pflash.
+    pub fn firmware(mut self, firmware: impl Into<PathBuf>) -> Self {
+        self.firmware = Some(firmware.into());
+        self
+    }
+
+    pub fn drive(mut self, drive: Drive) -> Self {
+        self.drives.push(drive);
+        self
+    }
+
+    /// Adds a network card. A machine without any gets `-nic none`.
+    pub fn nic(mut self, nic: Nic) -> Self {
+        self.nics.push(nic);
+        self
+    }
+
+    pub fn display(mut self, display: Display) -> Self {
+        self.display = display;
+        self
+    }
+
+    pub fn serial_port(mut self, port: SerialPort) -> Self {
+        self.serial_ports.push(port);
+        self
+    }
+
+    /// Adds a virtio-fs device. Guest memory is then shared with `virtiofsd`.
+    pub fn share(mut self, share: FsShare) -> Self {
+        self.shares.push(share);
+        self
+    }
+
+    pub fn program(&self) -> &Path {
+        &self.binary
+    }
+
+    pub fn qmp_socket(&self) -> &Path {
+        &self.qmp_socket
+    }
+
+    pub fn shares(&self) -> &[FsShare] {
+        &self.shares
+    }
+
+    /// The arguments, without the program.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut push = |option: &str, value: String| {
+            args.push(option.to_string());
+            args.push(value);
+        };
+
+        push("-name", escape(&self.name));
+        let accel = match self.accel {
+            Accel::Kvm => "kvm",
+            Accel::Tcg => "tcg",
+        };
+        push("-machine", format!("q35,accel={}", accel));
+        push("-cpu", self.cpu.clone());
+        push("-m", format!("{}M", self.memory_mib));
+        push("-smp", self.cpus.to_string());
+        // Windows keeps the hardware clock in local time.
+        push("-rtc", "base=localtime".to_string());
+        if !self.shares.is_empty() {
+            push(
+                "-object",
+                format!(
+                    "memory-backend-memfd,id=mem,size={}M,share=on",
+                    self.memory_mib
+                ),
+            );
+            push("-numa", "node,memdev=mem".to_string());
+        }
+        if let Some(firmware) = &self.firmware {
+            push(
+                "-drive",
+                format!(
+                    "if=pflash,format=raw,readonly=on,file={}",
+                    escape_path(firmware)
+                ),
+            );
+        }
+
+        for (i, drive) in self.drives.iter().enumerate() {
+            let id = format!("drive{}", i);
+            let (media, device) = match drive.media {
+                DriveMedia::Disk => ("disk", "ide-hd"),
+                DriveMedia::Cdrom => ("cdrom,readonly=on", "ide-cd"),
+            };
+            push(
+                "-drive",
+                format!(
+                    "if=none,id={},media={},format={},file={}",
+                    id,
+                    media,
+                    drive.format.as_str(),
+                    escape_path(&drive.file)
+                ),
+            );
+            let mut device = format!("{},drive={}", device, id);
+            if let Some(boot_index) = drive.boot_index {
+                device.push_str(&format!(",bootindex={}", boot_index));
+            }
+            push("-device", device);
+        }
+
+        if self.nics.is_empty() {
+            push("-nic", "none".to_string());
+        }
+        for (i, nic) in self.nics.iter().enumerate() {
+            let netdev = match nic {
+                Nic::User => format!("user,id=net{}", i),
+                Nic::Bridge(bridge) => format!("bridge,id=net{},br={}", i, escape(bridge)),
+            };
+            push("-netdev", netdev);
+            push("-device", format!("e1000e,netdev=net{}", i));
+        }
+
+        // An absolute pointer keeps the guest cursor where the host put it.
+        push("-device", "qemu-xhci".to_string());
+        push("-device", "usb-tablet".to_string());
+        match &self.display {
+            Display::Gtk => push("-display", "gtk".to_string()),
+            Display::Spice(socket) => {
+                push("-display", "none".to_string());
+                push(
+                    "-spice",
+                    format!("unix=on,addr={},disable-ticketing=on", escape_path(socket)),
+                );
+            }
+            Display::Vnc(socket) => {
+                push("-display", "none".to_string());
+                push("-vnc", format!("unix:{}", escape_path(socket)));
+            }
+            Display::None => push("-display", "none".to_string()),
+        }
+
+        if !self.serial_ports.is_empty() {
+            push("-device", "virtio-serial-pci".to_string());
+        }
+        for port in &self.serial_ports {
+            push("-chardev", chardev_spec(&port.chardev));
+            push(
+                "-device",
+                format!(
+                    "virtserialport,chardev={},name={}",
+                    port.chardev.id,
+                    escape(&port.name)
+                ),
+            );
+        }
+        for (i, share) in self.shares.iter().enumerate() {
+            let chardev = Chardev {
+                id: format!("fs{}", i),
+                path: share.socket.clone(),
+                server: false,
+            };
+            push("-chardev", chardev_spec(&chardev));
+            push(
+                "-device",
+                format!(
+                    "vhost-user-fs-pci,chardev={},tag={}",
+                    chardev.id,
+                    escape(&share.tag)
+                ),
+            );
+        }
+        push(
+            "-qmp",
+            format!("unix:{},server=on,wait=off", escape_path(&self.qmp_socket)),
+        );
+        args
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.binary);
+        command.args(self.args());
+        command
+    }
+}
+
+/// The command as a line that can be pasted into a shell.
+impl fmt::Display for QemuCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", shell_quote(&self.binary.to_string_lossy()))?;
+        for arg in self.args() {
+            write!(f, " {}", shell_quote(&arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// A `virtiofsd` exporting one shared folder over the socket of an `FsShare`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtiofsCommand {
+    pub binary: PathBuf,
+    pub socket: PathBuf,
+    pub shared_dir: PathBuf,
+    pub read_only: bool,
+}
+
+impl VirtiofsCommand {
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--socket-path".to_string(),
+            self.socket.to_string_lossy().into_owned(),
+            "--shared-dir".to_string(),
+            self.shared_dir.to_string_lossy().into_owned(),
+        ];
+        if self.read_only {
+            args.push("--readonly".to_string());
+        }
+        args
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.binary);
+        command.args(self.args());
+        command
+    }
+}
+
+impl fmt::Display for VirtiofsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", shell_quote(&self.binary.to_string_lossy()))?;
+        for arg in self.args() {
+            write!(f, " {}", shell_quote(&arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything started for one boot of the guest: a `virtiofsd` per shared folder,
+/// then QEMU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QemuLaunch {
+    pub virtiofsd: Vec<VirtiofsCommand>,
+    pub qemu: QemuCommand,
+}
+
+impl QemuLaunch {
+    /// See `QemuCommand::for_guest`; `virtiofsd` is the daemon serving shared folders.
+    pub fn for_guest(
+        config: &GuestConfig,
+        runtime_dir: &Path,
+        firmware: Option<&Path>,
+        virtiofsd: &Path,
+    ) -> Result<Self, ConfigError> {
+        let qemu = QemuCommand::for_guest(config, runtime_dir, firmware)?;
+        let virtiofsd = qemu
+            .shares()
+            .iter()
+            .zip(&config.shared_folders)
+            .map(|(share, folder)| VirtiofsCommand {
+                binary: virtiofsd.to_path_buf(),
+                socket: share.socket.clone(),
+                shared_dir: folder.path.clone(),
+                read_only: folder.read_only,
+            })
+            .collect();
+        Ok(Self { virtiofsd, qemu })
+    }
+}
+
+/// One command per line, in the order they are started.
+impl fmt::Display for QemuLaunch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for virtiofsd in &self.virtiofsd {
+            writeln!(f, "{}", virtiofsd)?;
+        }
+        write!(f, "{}", self.qemu)
+    }
+}
+
+fn chardev_spec(chardev: &Chardev) -> String {
+    let mut spec = format!(
+        "socket,id={},path={}",
+        chardev.id,
+        escape_path(&chardev.path)
+    );
+    if chardev.server {
+        spec.push_str(",server=on,wait=off");
+    }
+    spec
+}
+
+/// QEMU splits option values at commas; a literal one is written twice.
+fn escape(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+fn escape_path(path: &Path) -> String {
+    escape(&path.to_string_lossy())
+}
+
+/// Quotes `arg` for a POSIX shell unless it is made of characters that need none.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./,=:@%+".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::lx_dos::config::{NetworkConfig, SharedFolder, WindowsConfig};
+
+    const RUNTIME_DIR: &str = "/run/user/1000/lx-dos/default";
+    const FIRMWARE: &str = "/usr/share/OVMF/OVMF_CODE.fd";
+
+    fn config() -> GuestConfig {
+        GuestConfig {
+            name: "Windows 11".to_string(),
+            disk_image: PathBuf::from("/home/alice/.local/share/lx-dos/default/windows.qcow2"),
+            memory_mib: 4096,
+            cpus: 2,
+            display: DisplayMode::Gtk,
+            autostart: false,
+            installer: None,
+            network: NetworkConfig::default(),
+            shared_folders: Vec::new(),
+            windows: WindowsConfig::default(),
+        }
+    }
+
+    fn command(config: &GuestConfig) -> QemuCommand {
+        QemuCommand::for_guest(config, Path::new(RUNTIME_DIR), Some(Path::new(FIRMWARE))).unwrap()
+    }
+
+    #[test]
+    fn default_guest() {
+        assert_eq!(
+            command(&config()).to_string(),
+            concat!(
+                "qemu-system-x86_64",
+                " -name 'Windows 11'",
+                " -machine q35,accel=kvm",
+                " -cpu host",
+                " -m 4096M",
+                " -smp 2",
+                " -rtc base=localtime",
+                " -drive if=pflash,format=raw,readonly=on,file=/usr/share/OVMF/OVMF_CODE.fd",
+                " -drive if=none,id=drive0,media=disk,format=qcow2,file=/home/alice/.local/share/lx-dos/default/windows.qcow2",
+                " -device ide-hd,drive=drive0,bootindex=1",
+                " -netdev user,id=net0",
+                " -device e1000e,netdev=net0",
+                " -device qemu-xhci",
+                " -device usb-tablet",
+                " -display gtk",
+                " -device virtio-serial-pci",
+                " -chardev socket,id=qga0,path=/run/user/1000/lx-dos/default/qga.sock,server=on,wait=off",
+                " -device virtserialport,chardev=qga0,name=org.qemu.guest_agent.0",
+                " -qmp unix:/run/user/1000/lx-dos/default/qmp.sock,server=on,wait=off",
+            )
+        );
+    }
+
+    #[test]
+    fn bridged_guest() {
+        let mut config = config();
+        config.network = NetworkConfig {
+            mode: NetworkMode::Bridge,
+            bridge: Some("br,lan".to_string()),
+        };
+        config.display = DisplayMode::Spice;
+        let args = command(&config).args();
+        let args: Vec<_> = args.iter().map(String::as_str).collect();
+        // カンマは二重にしないと QEMU が次のオプションだと思う
+        assert_eq!(
+            args[18..28],
+            [
+                "-netdev",
+                "bridge,id=net0,br=br,,lan",
+                "-device",
+                "e1000e,netdev=net0",
+                "-device",
+                "qemu-xhci",
+                "-device",
+                "usb-tablet",
+                "-display",
+                "none",
+            ]
+        );
+        assert_eq!(
+            args[28..30],
+            [
+                "-spice",
+                "unix=on,addr=/run/user/1000/lx-dos/default/spice.sock,disable-ticketing=on",
+            ]
+        );
+
+        config.network.bridge = None;
+        let error = QemuCommand::for_guest(&config, Path::new(RUNTIME_DIR), None).unwrap_err();
+        assert_eq!(error.key.as_deref(), Some("network.bridge"));
+        assert_eq!(
+            error.to_string(),
+            "network.bridge: is required when mode is \"bridge\""
+        );
+    }
+
+    #[test]
+    fn guest_without_network() {
+        let mut config = config();
+        config.network.mode = NetworkMode::None;
+        let args = command(&config).args();
+        assert_eq!(args[18..20], ["-nic", "none"]);
+        assert!(!args.iter().any(|arg| arg == "-netdev"));
+    }
+
+    #[test]
+    fn guest_with_shared_folders() {
+        let mut config = config();
+        config.shared_folders = vec![
+            SharedFolder {
+                name: "work".to_string(),
+                path: PathBuf::from("/home/alice/work"),
+                read_only: false,
+            },
+            SharedFolder {
+                name: "photos".to_string(),
+                path: PathBuf::from("/home/alice/Pictures"),
+                read_only: true,
+            },
+        ];
+        let command = command(&config);
+        assert_eq!(
+            command.shares(),
+            [
+                FsShare {
+                    tag: "work".to_string(),
+                    socket: PathBuf::from(RUNTIME_DIR).join("virtiofs-0.sock"),
+                },
+                FsShare {
+                    tag: "photos".to_string(),
+                    socket: PathBuf::from(RUNTIME_DIR).join("virtiofs-1.sock"),
+                },
+            ]
+        );
+        let args = command.args();
+        // virtiofsd はゲストのメモリを共有できないと動かない
+        assert_eq!(
+            args[12..16],
+            [
+                "-object",
+                "memory-backend-memfd,id=mem,size=4096M,share=on",
+                "-numa",
+                "node,memdev=mem",
+            ]
+        );
+        let tail = &args[args.len() - 10..];
+        assert_eq!(
+            tail,
+            [
+                "-chardev",
+                "socket,id=fs0,path=/run/user/1000/lx-dos/default/virtiofs-0.sock",
+                "-device",
+                "vhost-user-fs-pci,chardev=fs0,tag=work",
+                "-chardev",
+                "socket,id=fs1,path=/run/user/1000/lx-dos/default/virtiofs-1.sock",
+                "-device",
+                "vhost-user-fs-pci,chardev=fs1,tag=photos",
+                "-qmp",
+                "unix:/run/user/1000/lx-dos/default/qmp.sock,server=on,wait=off",
+            ]
+        );
+    }
+
+    /// What `lx-dos start --dry-run` prints.
+    #[test]
+    fn dry_run_output() {
+        let mut config = config();
+        config.name = "Alice's PC".to_string();
+        config.memory_mib = 8192;
+        config.display = DisplayMode::None;
+        config.installer = Some(PathBuf::from("/home/alice/Downloads/Win11 24H2.iso"));
+        config.shared_folders = vec![SharedFolder {
+            name: "work".to_string(),
+            path: PathBuf::from("/home/alice/My Documents"),
+            read_only: true,
+        }];
+        let launch = QemuLaunch::for_guest(
+            &config,
+            Path::new(RUNTIME_DIR),
+            None,
+            Path::new("/usr/libexec/virtiofsd"),
+        )
+        .unwrap();
+        assert_eq!(
+            launch.to_string(),
+            concat!(
+                "/usr/libexec/virtiofsd",
+                " --socket-path /run/user/1000/lx-dos/default/virtiofs-0.sock",
+                " --shared-dir '/home/alice/My Documents'",
+                " --readonly\n",
+                "qemu-system-x86_64",
+                " -name 'Alice'\\''s PC'",
+                " -machine q35,accel=kvm",
+                " -cpu host",
+                " -m 8192M",
+                " -smp 2",
+                " -rtc base=localtime",
+                " -object memory-backend-memfd,id=mem,size=8192M,share=on",
+                " -numa node,memdev=mem",
+                " -drive 'if=none,id=drive0,media=cdrom,readonly=on,format=raw,file=/home/alice/Downloads/Win11 24H2.iso'",
+                " -device ide-cd,drive=drive0,bootindex=0",
+                " -drive if=none,id=drive1,media=disk,format=qcow2,file=/home/alice/.local/share/lx-dos/default/windows.qcow2",
+                " -device ide-hd,drive=drive1,bootindex=1",
+                " -netdev user,id=net0",
+                " -device e1000e,netdev=net0",
+                " -device qemu-xhci",
+                " -device usb-tablet",
+                " -display none",
+                " -device virtio-serial-pci",
+                " -chardev socket,id=qga0,path=/run/user/1000/lx-dos/default/qga.sock,server=on,wait=off",
+                " -device virtserialport,chardev=qga0,name=org.qemu.guest_agent.0",
+                " -chardev socket,id=fs0,path=/run/user/1000/lx-dos/default/virtiofs-0.sock",
+                " -device vhost-user-fs-pci,chardev=fs0,tag=work",
+                " -qmp unix:/run/user/1000/lx-dos/default/qmp.sock,server=on,wait=off",
+            )
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use crate::LxDosError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Name of the virtio-serial port the agent inside the guest opens.
+pub const PORT_NAME: &str = "org.qemu.guest_agent.0";
+/// Socket in the profile's runtime directory that QEMU connects the port to.
+pub const SOCKET_NAME: &str = "qga.sock";
+/// How long a command may take unless the caller asks for more.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What `guest-exec-status` reports about a process started by `QgaClient::exec`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecStatus {
+    pub exited: bool,
+    pub exit_code: Option<i64>,
+    /// Signal that killed the process, on guests that have signals.
+    pub signal: Option<i64>,
+    /// Output captured since the last status, decoded.
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The agent dropped output beyond its buffer size.
+    pub truncated: bool,
+}
+
+impl ExecStatus {
+    /// The exit status to report on the host, which only has 0 to 255.
+    pub fn host_exit_code(&self) -> u8 {
+        match (self.exit_code, self.signal) {
+            // Windows exit codes such as NTSTATUS values do not fit a Unix exit status.
+            (Some(code), _) => u8::try_from(code).unwrap_or(1),
+            (None, Some(signal)) => 128u8.saturating_add(u8::try_from(signal).unwrap_or(u8::MAX)),
+            (None, None) => 1,
+        }
+    }
+}
+
+/// Client for the QEMU Guest Agent, reached through the unix socket QEMU exposes
+/// for the agent's virtio-serial port.
+///
+/// Unlike QMP there is no greeting; a `guest-sync-delimited` exchange on connect
+/// skips whatever a previous client left unread.
+#[derive(Debug)]
+pub struct QgaClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    partial: Vec<u8>,
+}
+
+impl QgaClient {
+    pub fn connect(path: &Path) -> Result<Self, LxDosError> {
+        let writer = UnixStream::connect(path)?;
+        let mut client = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            partial: Vec::new(),
+        };
+        client.sync()?;
+        Ok(client)
+    }
+
+    pub fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<Value, LxDosError> {
+        self.execute_timeout(command, arguments, DEFAULT_TIMEOUT)
+    }
+
+    /// Runs `command` and returns the content of its `return` member.
+    ///
+    /// The agent handles one command at a time, so the next message is the reply.
+    pub fn execute_timeout(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, LxDosError> {
+        self.send(command, arguments)?;
+        let Some(message) = self.read_message(timeout)? else {
+            return Err(LxDosError::Message(format!(
+                "Guest agent command {} timed out after {:?}",
+                command, timeout
+            )));
+        };
+        if let Some(error) = message.get("error") {
+            return Err(LxDosError::Message(format!(
+                "Guest agent command {} failed: {}: {}",
+                command,
+                error["class"].as_str().unwrap_or("Error"),
+                error["desc"].as_str().unwrap_or("unknown error")
+            )));
+        }
+        Ok(message.get("return").cloned().unwrap_or(Value::Null))
+    }
+
+    /// Starts `path` with `args` in the guest, capturing its output, and returns its
+    /// pid. `input` is written to its stdin.
+    pub fn exec(
+        &mut self,
+        path: &str,
+        args: &[String],
+        input: Option<&[u8]>,
+    ) -> Result<i64, LxDosError> {
+        let mut arguments = json!({ "path": path, "arg": args, "capture-output": true });
+        if let Some(input) = input {
+            arguments["input-data"] = json!(BASE64.encode(input));
+        }
+        let reply = self.execute("guest-exec", Some(arguments))?;
+        reply["pid"]
+            .as_i64()
+            .ok_or_else(|| LxDosError::Message(format!("guest-exec returned no pid: {}", reply)))
+    }
+
+    pub fn exec_status(&mut self, pid: i64) -> Result<ExecStatus, LxDosError> {
+        let reply = self.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+        Ok(ExecStatus {
+            exited: reply["exited"].as_bool().unwrap_or(false),
+            exit_code: reply["exitcode"].as_i64(),
+            signal: reply["signal"].as_i64(),
+            stdout: decode(&reply["out-data"])?,
+            stderr: decode(&reply["err-data"])?,
+            truncated: reply["out-truncated"].as_bool().unwrap_or(false)
+                || reply["err-truncated"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// Resynchronizes with the agent: the 0xFF byte resets its parser, and the reply
+    /// is preceded by another one, so anything read before that is stale.
+    fn sync(&mut self) -> Result<(), LxDosError> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos() as u64)
+            ^ (u64::from(std::process::id()) << 32);
+        self.writer.write_all(&[0xFF])?;
+        self.send("guest-sync-delimited", Some(json!({ "id": id })))?;
+
+        let deadline = Instant::now() + DEFAULT_TIMEOUT;
+        let not_responding = || {
+            LxDosError::Message(
+                "The guest agent is not responding, is the QEMU guest agent installed \
+                 and running in the guest?"
+                    .to_string(),
+            )
+        };
+        let mut skipped = Vec::new();
+        self.writer.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+        match self.reader.read_until(0xFF, &mut skipped) {
+            Ok(_) if skipped.ends_with(&[0xFF]) => {}
+            Ok(_) => return Err(not_responding()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(not_responding());
+            }
+            Err(e) => return Err(LxDosError::Io(e)),
+        }
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(message) = self.read_message(remaining)? else {
+                return Err(not_responding());
+            };
+            if message.get("return").and_then(Value::as_u64) == Some(id) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<(), LxDosError> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads one message. `Ok(None)` means nothing arrived within `timeout`.
+    fn read_message(&mut self, timeout: Duration) -> Result<Option<Value>, LxDosError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            self.writer.set_read_timeout(Some(remaining))?;
+            match self.reader.read_until(b'\n', &mut self.partial) {
+                Ok(0) => {
+                    return Err(LxDosError::Message(
+                        "Guest agent connection closed by QEMU".to_string(),
+                    ));
+                }
+                Ok(_) if self.partial.ends_with(b"\n") => {
+                    let line = std::mem::take(&mut self.partial);
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let message = serde_json::from_slice(&line).map_err(|e| {
+                        LxDosError::Message(format!("Invalid guest agent message: {}", e))
+                    })?;
+                    return Ok(Some(message));
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(LxDosError::Io(e)),
+            }
+        }
+    }
+}
+
+/// Decodes an optional base64 member of a reply.
+fn decode(value: &Value) -> Result<Vec<u8>, LxDosError> {
+    match value.as_str() {
+        Some(data) => BASE64.decode(data).map_err(|e| {
+            LxDosError::Message(format!("Invalid base64 from the guest agent: {}", e))
+        }),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{self, JoinHandle};
+
+    /// A guest agent on a unix socket, answering with `reply`.
+    ///
+    /// Like the real agent it answers `guest-sync-delimited` with a 0xFF byte before
+    /// the reply, and it starts with a reply nobody read, left by an earlier client.
+    struct FakeAgent {
+        path: PathBuf,
+        server: JoinHandle<Vec<Value>>,
+    }
+
+    impl FakeAgent {
+        fn start(reply: fn(&Value) -> Vec<String>) -> Self {
+            let path = socket_path();
+            let listener = UnixListener::bind(&path).unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                writeln!(stream, "{}", json!({ "return": { "pid": 1 } })).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut received = Vec::new();
+                loop {
+                    let mut line = Vec::new();
+                    if reader.read_until(b'\n', &mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    // 0xFF はパーサーのリセットなので読み飛ばす
+                    let start = line.iter().position(|&b| b != 0xFF).unwrap_or(line.len());
+                    let request: Value = serde_json::from_slice(&line[start..]).unwrap();
+                    if request["execute"] == "guest-sync-delimited" {
+                        assert_eq!(line[0], 0xFF, "sync without resetting the parser");
+                        stream.write_all(&[0xFF]).unwrap();
+                        writeln!(
+                            stream,
+                            "{}",
+                            json!({ "return": request["arguments"]["id"] })
+                        )
+                        .unwrap();
+                    } else {
+                        for line in reply(&request) {
+                            writeln!(stream, "{}", line).unwrap();
+                        }
+                    }
+                    received.push(request);
+                }
+                received
+            });
+            Self { path, server }
+        }
+
+        /// Requests received until the client disconnected.
+        fn finish(self, client: QgaClient) -> Vec<Value> {
+            drop(client);
+            let received = self.server.join().unwrap();
+            let _ = std::fs::remove_file(&self.path);
+            received
+        }
+    }
+
+    fn socket_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lx-dos-qga-test-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn ok(value: Value) -> String {
+        json!({ "return": value }).to_string()
+    }
+
+    /// Processes by pid: 1 printed and exited, 2 failed, 3 was killed, 4 still runs
+    /// and 5 hit an NTSTATUS.
+    fn processes(request: &Value) -> Vec<String> {
+        let pid = &request["arguments"]["pid"];
+        let status = match (request["execute"].as_str(), pid.as_i64()) {
+            (Some("guest-exec"), _) => json!({ "pid": 1 }),
+            (Some("guest-exec-status"), Some(1)) => json!({
+                "exited": true,
+                "exitcode": 0,
+                "out-data": "aGVsbG8sIHdvcmxkDQo=",
+                "err-data": "4pqgIGRvbmU=",
+            }),
+            (Some("guest-exec-status"), Some(2)) => json!({
+                "exited": true,
+                "exitcode": 3,
+                "err-truncated": true,
+            }),
+            (Some("guest-exec-status"), Some(3)) => json!({ "exited": true, "signal": 9 }),
+            (Some("guest-exec-status"), Some(4)) => json!({ "exited": false }),
+            (Some("guest-exec-status"), Some(5)) => {
+                json!({ "exited": true, "exitcode": 3221225477u32 })
+            }
+            (Some("guest-exec-status"), Some(6)) => {
+                json!({ "exited": true, "exitcode": 0, "out-data": "not base64!" })
+            }
+            _ => {
+                return vec![
+                    json!({
+                        "error": { "class": "GenericError", "desc": "Invalid parameter 'pid'" },
+                    })
+                    .to_string(),
+                ];
+            }
+        };
+        vec![ok(status)]
+    }
+
+    #[test]
+    fn connect_syncs_past_stale_replies() {
+        let agent = FakeAgent::start(|_| vec![ok(json!({}))]);
+        let mut client = QgaClient::connect(&agent.path).unwrap();
+        // 前のクライアントの残した返事ではなく、この ping の返事が届く
+        assert_eq!(client.execute("guest-ping", None).unwrap(), json!({}));
+        let received = agent.finish(client);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0]["execute"], "guest-sync-delimited");
+        assert!(received[0]["arguments"]["id"].is_u64());
+        assert_eq!(received[1]["execute"], "guest-ping");
+    }
+
+    #[test]
+    fn connect_fails_if_no_agent_answers() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        // エージェントが答えないまま QEMU が接続を閉じる。読まずに閉じると RST になる
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            BufReader::new(stream).read_until(b'\n', &mut line).unwrap();
+        });
+        let err = QgaClient::connect(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("guest agent is not responding"),
+            "{}",
+            err
+        );
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn exec_sends_arguments_and_input() {
+        let agent = FakeAgent::start(processes);
+        let mut client = QgaClient::connect(&agent.path).unwrap();
+        let args = ["/c".to_string(), "sort".to_string()];
+        assert_eq!(client.exec("cmd.exe", &args, Some(b"b\na\n")).unwrap(), 1);
+        client.exec("cmd.exe", &[], None).unwrap();
+
+        let received = agent.finish(client);
+        assert_eq!(
+            received[1]["arguments"],
+            json!({
+                "path": "cmd.exe",
+                "arg": ["/c", "sort"],
+                "capture-output": true,
+                "input-data": "YgphCg==",
+            })
+        );
+        assert!(received[2]["arguments"].get("input-data").is_none());
+    }
+
+    #[test]
+    fn exec_status_decodes_output() {
+        let agent = FakeAgent::start(processes);
+        let mut client = QgaClient::connect(&agent.path).unwrap();
+        let status = client.exec_status(1).unwrap();
+        assert_eq!(
+            status,
+            ExecStatus {
+                exited: true,
+                exit_code: Some(0),
+                signal: None,
+                stdout: b"hello, world\r\n".to_vec(),
+                stderr: "⚠ done".as_bytes().to_vec(),
+                truncated: false,
+            }
+        );
+        assert!(client.exec_status(2).unwrap().truncated);
+        assert!(!client.exec_status(4).unwrap().exited);
+
+        let err = client.exec_status(6).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid base64 from the guest agent"),
+            "{}",
+            err
+        );
+        let err = client.exec_status(99).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Guest agent command guest-exec-status failed: GenericError: Invalid parameter 'pid'"
+        );
+        agent.finish(client);
+    }
+
+    #[test]
+    fn exit_codes_map_to_the_host() {
+        let agent = FakeAgent::start(processes);
+        let mut client = QgaClient::connect(&agent.path).unwrap();
+        let mut exit_code = |pid| client.exec_status(pid).unwrap().host_exit_code();
+        assert_eq!(exit_code(1), 0);
+        assert_eq!(exit_code(2), 3);
+        // シグナルで死んだら 128 + シグナル番号、シェルと同じ
+        assert_eq!(exit_code(3), 137);
+        // 0xC0000005 (アクセス違反) は 8 ビットに収まらない
+        assert_eq!(exit_code(5), 1);
+        assert_eq!(exit_code(4), 1);
+        agent.finish(client);
+    }
+}
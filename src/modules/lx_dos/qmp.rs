@@ -0,0 +1,463 @@
+use super::runtime::RuntimeState;
+use crate::LxDosError;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long a command may take unless the caller asks for more.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An asynchronous event emitted by QEMU, e.g. `STOP` or `SHUTDOWN`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QmpEvent {
+    pub event: String,
+    pub data: Value,
+}
+
+impl QmpEvent {
+    /// The guest state this event implies, if it changes it at all.
+    pub fn runtime_state(&self) -> Option<RuntimeState> {
+        match self.event.as_str() {
+            "STOP" | "SUSPEND" => Some(RuntimeState::Paused),
+            "RESUME" | "WAKEUP" => Some(RuntimeState::Running),
+            "SHUTDOWN" => Some(RuntimeState::Stopped),
+            "GUEST_PANICKED" => Some(RuntimeState::Crashed("guest panicked".to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Maps the `status` field of a `query-status` reply.
+pub fn runtime_state_from_status(status: &str) -> RuntimeState {
+    match status {
+        "running" => RuntimeState::Running,
+        "shutdown" => RuntimeState::Stopped,
+        "guest-panicked" | "internal-error" | "io-error" => {
+            RuntimeState::Crashed(format!("guest is in state {}", status))
+        }
+        _ => RuntimeState::Paused,
+    }
+}
+
+/// Client for the QEMU Machine Protocol on a unix socket.
+///
+/// Events that arrive while waiting for a reply are queued and handed out by
+/// `poll_events`.
+#[derive(Debug)]
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    partial: Vec<u8>,
+    events: VecDeque<QmpEvent>,
+    next_id: u64,
+    /// QEMU closed the connection, which it does when it exits.
+    closed: bool,
+}
+
+impl QmpClient {
+    /// Connects to `path` and performs the capabilities handshake.
+    pub fn connect(path: &Path) -> Result<Self, LxDosError> {
+        let writer = UnixStream::connect(path)?;
+        let mut client = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            partial: Vec::new(),
+            events: VecDeque::new(),
+            next_id: 1,
+            closed: false,
+        };
+
+        let greeting = client
+            .read_message(Some(DEFAULT_TIMEOUT))?
+            .ok_or_else(|| LxDosError::Message("No QMP greeting from QEMU".to_string()))?;
+        if greeting.get("QMP").is_none() {
+            return Err(LxDosError::Message(format!(
+                "Unexpected QMP greeting: {}",
+                greeting
+            )));
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    pub fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<Value, LxDosError> {
+        self.execute_timeout(command, arguments, DEFAULT_TIMEOUT)
+    }
+
+    /// Runs `command` and returns the content of its `return` member.
+    pub fn execute_timeout(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, LxDosError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(message) = self.read_message(Some(remaining))? else {
+                return Err(LxDosError::Message(format!(
+                    "QMP command {} timed out after {:?}",
+                    command, timeout
+                )));
+            };
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(LxDosError::Message(format!(
+                    "QMP command {} failed: {}: {}",
+                    command,
+                    error["class"].as_str().unwrap_or("Error"),
+                    error["desc"].as_str().unwrap_or("unknown error")
+                )));
+            }
+            return Ok(message.get("return").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    /// Returns the events received so far without blocking.
+    ///
+    /// Events sent just before QEMU closed the connection, such as the `SHUTDOWN`
+    /// of a guest that powered off, are still returned; see `is_closed`.
+    pub fn poll_events(&mut self) -> Result<Vec<QmpEvent>, LxDosError> {
+        loop {
+            match self.read_message(None) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) if self.closed => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.events.drain(..).collect())
+    }
+
+    /// A duplicate of the connection's descriptor, readable whenever QEMU sent
+    /// something or closed the connection.
+    pub fn watch(&self) -> Option<OwnedFd> {
+        self.writer.try_clone().ok().map(OwnedFd::from)
+    }
+
+    /// Whether QEMU closed the connection.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Reads one non-event message, queueing any events read on the way.
+    ///
+    /// `timeout` of `None` only consumes what is already buffered; `Ok(None)` means
+    /// nothing arrived in time.
+    fn read_message(&mut self, timeout: Option<Duration>) -> Result<Option<Value>, LxDosError> {
+        let deadline = Instant::now() + timeout.unwrap_or_default();
+        loop {
+            // A zero timeout is rejected by the socket, so polls wait for a millisecond.
+            let remaining = deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            self.writer.set_read_timeout(Some(remaining))?;
+            match self.reader.read_until(b'\n', &mut self.partial) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Err(LxDosError::Message(
+                        "QMP connection closed by QEMU".to_string(),
+                    ));
+                }
+                Ok(_) if self.partial.ends_with(b"\n") => {
+                    let message: Value = serde_json::from_slice(&self.partial)
+                        .map_err(|e| LxDosError::Message(format!("Invalid QMP message: {}", e)))?;
+                    self.partial.clear();
+                    match message.get("event").and_then(Value::as_str) {
+                        Some(event) => self.events.push_back(QmpEvent {
+                            event: event.to_string(),
+                            data: message.get("data").cloned().unwrap_or(Value::Null),
+                        }),
+                        None => return Ok(Some(message)),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(LxDosError::Io(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{self, JoinHandle};
+
+    const GREETING: &str = r#"{"QMP": {"version": {}, "capabilities": ["oob"]}}"#;
+
+    /// A fake QEMU listening on a unix socket. It sends `greeting`, then answers each
+    /// command with the lines `reply` returns for it, and finally hands back the
+    /// commands it received.
+    struct FakeQemu {
+        path: PathBuf,
+        server: JoinHandle<Vec<Value>>,
+    }
+
+    impl FakeQemu {
+        fn start(greeting: &'static str, reply: fn(&Value) -> Vec<String>) -> Self {
+            let path = socket_path();
+            let listener = UnixListener::bind(&path).unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                writeln!(stream, "{}", greeting).unwrap();
+                let mut received = Vec::new();
+                for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                    let Ok(line) = line else { break };
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    for line in reply(&request) {
+                        writeln!(stream, "{}", line).unwrap();
+                    }
+                    received.push(request);
+                }
+                received
+            });
+            Self { path, server }
+        }
+
+        /// Commands received until the client disconnected.
+        fn finish(self, client: QmpClient) -> Vec<String> {
+            drop(client);
+            let received = self.server.join().unwrap();
+            let _ = std::fs::remove_file(&self.path);
+            received
+                .iter()
+                .map(|request| request["execute"].as_str().unwrap().to_string())
+                .collect()
+        }
+    }
+
+    fn socket_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lx-dos-qmp-test-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn ok(request: &Value, value: Value) -> String {
+        json!({ "return": value, "id": request["id"] }).to_string()
+    }
+
+    fn event(name: &str) -> String {
+        json!({
+            "event": name,
+            "data": {},
+            "timestamp": { "seconds": 1, "microseconds": 0 },
+        })
+        .to_string()
+    }
+
+    fn answer_everything(request: &Value) -> Vec<String> {
+        match request["execute"].as_str() {
+            Some("query-status") => vec![ok(request, json!({ "status": "paused" }))],
+            _ => vec![ok(request, json!({}))],
+        }
+    }
+
+    #[test]
+    fn connect_negotiates_capabilities_after_the_greeting() {
+        let fake = FakeQemu::start(GREETING, answer_everything);
+        let mut client = QmpClient::connect(&fake.path).unwrap();
+        let status = client.execute("query-status", None).unwrap();
+        assert_eq!(status["status"], "paused");
+        assert_eq!(fake.finish(client), ["qmp_capabilities", "query-status"]);
+    }
+
+    #[test]
+    fn connect_rejects_a_peer_that_is_not_qmp() {
+        let fake = FakeQemu::start(r#"{"hello": "world"}"#, answer_everything);
+        let err = QmpClient::connect(&fake.path).unwrap_err();
+        assert!(
+            err.to_string().contains("Unexpected QMP greeting"),
+            "{}",
+            err
+        );
+        fake.server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_fails_if_capabilities_are_refused() {
+        let fake = FakeQemu::start(GREETING, |request| {
+            vec![
+                json!({
+                    "error": { "class": "CommandNotFound", "desc": "no" },
+                    "id": request["id"],
+                })
+                .to_string(),
+            ]
+        });
+        let err = QmpClient::connect(&fake.path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "QMP command qmp_capabilities failed: CommandNotFound: no"
+        );
+        fake.server.join().unwrap();
+    }
+
+    #[test]
+    fn error_replies_carry_class_and_description() {
+        let fake = FakeQemu::start(GREETING, |request| match request["execute"].as_str() {
+            Some("qmp_capabilities") => vec![ok(request, json!({}))],
+            _ => vec![
+                json!({
+                    "error": { "class": "GenericError", "desc": "Device 'nope' not found" },
+                    "id": request["id"],
+                })
+                .to_string(),
+            ],
+        });
+        let mut client = QmpClient::connect(&fake.path).unwrap();
+        let err = client
+            .execute("device_del", Some(json!({ "id": "nope" })))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "QMP command device_del failed: GenericError: Device 'nope' not found"
+        );
+        fake.finish(client);
+    }
+
+    #[test]
+    fn events_before_a_reply_are_queued() {
+        let fake = FakeQemu::start(GREETING, |request| match request["execute"].as_str() {
+            Some("stop") => vec![event("STOP"), ok(request, json!({}))],
+            Some("cont") => vec![event("RESUME"), ok(request, json!({})), event("SHUTDOWN")],
+            _ => vec![ok(request, json!({}))],
+        });
+        let mut client = QmpClient::connect(&fake.path).unwrap();
+        client.execute("stop", None).unwrap();
+        client.execute("cont", None).unwrap();
+        // SHUTDOWN came after the reply and may still be on its way
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut events = Vec::new();
+        while events.len() < 3 && Instant::now() < deadline {
+            events.extend(client.poll_events().unwrap());
+        }
+        let states: Vec<_> = events.iter().map(QmpEvent::runtime_state).collect();
+        assert_eq!(
+            states,
+            [
+                Some(RuntimeState::Paused),
+                Some(RuntimeState::Running),
+                Some(RuntimeState::Stopped),
+            ]
+        );
+        assert!(client.poll_events().unwrap().is_empty());
+        fake.finish(client);
+    }
+
+    #[test]
+    fn replies_to_other_commands_are_skipped() {
+        let fake = FakeQemu::start(GREETING, |request| {
+            let stale = json!({ "return": { "status": "running" }, "id": 999 }).to_string();
+            vec![stale, ok(request, json!({ "status": "paused" }))]
+        });
+        let mut client = QmpClient::connect(&fake.path).unwrap();
+        let status = client.execute("query-status", None).unwrap();
+        assert_eq!(status["status"], "paused");
+        fake.finish(client);
+    }
+
+    #[test]
+    fn commands_without_a_reply_time_out() {
+        let fake = FakeQemu::start(GREETING, |request| match request["execute"].as_str() {
+            Some("qmp_capabilities") => vec![ok(request, json!({}))],
+            _ => Vec::new(),
+        });
+        let mut client = QmpClient::connect(&fake.path).unwrap();
+        let err = client
+            .execute_timeout("system_powerdown", None, Duration::from_millis(50))
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        fake.finish(client);
+    }
+
+    #[test]
+    fn a_closed_connection_is_an_error() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            writeln!(stream, "{}", GREETING).unwrap();
+            // qmp_capabilities を読んでから返事をせずに切断する
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+        });
+        let err = QmpClient::connect(&path).unwrap_err();
+        assert_eq!(err.to_string(), "QMP connection closed by QEMU");
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn events_sent_before_qemu_exits_are_not_lost() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            writeln!(stream, "{}", GREETING).unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut line)
+                .unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            // ゲストの電源が切れると QEMU は SHUTDOWN を送ってから終了する
+            writeln!(stream, "{}", ok(&request, json!({}))).unwrap();
+            writeln!(stream, "{}", event("SHUTDOWN")).unwrap();
+        });
+        let mut client = QmpClient::connect(&path).unwrap();
+        server.join().unwrap();
+
+        let events = client.poll_events().unwrap();
+        assert_eq!(
+            events.iter().map(|e| e.event.as_str()).collect::<Vec<_>>(),
+            ["SHUTDOWN"]
+        );
+        assert!(client.is_closed());
+        assert!(client.poll_events().unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn status_names_map_to_runtime_states() {
+        assert_eq!(runtime_state_from_status("running"), RuntimeState::Running);
+        assert_eq!(runtime_state_from_status("shutdown"), RuntimeState::Stopped);
+        assert_eq!(runtime_state_from_status("prelaunch"), RuntimeState::Paused);
+        assert!(matches!(
+            runtime_state_from_status("guest-panicked"),
+            RuntimeState::Crashed(_)
+        ));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::LxDosError;
+use std::os::fd::OwnedFd;
+
+/// What a runtime reports about the guest it drives.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RuntimeState {
+    #[default]
+    Stopped,
+    Running,
+    Paused,
+    /// The guest or its runtime died, with a description of why.
+    Crashed(String),
+}
+
+/// Input injected into the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestInput {
+    /// Keys pressed together and released, named after QEMU `QKeyCode`s (e.g. `ctrl`, `alt`, `delete`).
+    Keys(Vec<String>),
+    /// Text typed key by key.
+    Text(String),
+    PointerMove {
+        x: u32,
+        y: u32,
+    },
+    PointerButton {
+        button: PointerButton,
+        pressed: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerButton {
+    Left,
+    Middle,
+    Right,
+}
+
+/// A frame of the guest display as packed 8-bit RGB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+/// Drives a guest for `LxDos`.
+///
+/// Implementations only perform the operation; tracking the lifecycle and
+/// validating transitions is left to `LxDos`.
+pub trait GuestRuntime: Send + std::fmt::Debug {
+    /// Short name used in logs and `lx-dos status`.
+    fn name(&self) -> &str;
+    fn start(&mut self) -> Result<(), LxDosError>;
+    fn stop(&mut self) -> Result<(), LxDosError>;
+    fn pause(&mut self) -> Result<(), LxDosError>;
+    fn resume(&mut self) -> Result<(), LxDosError>;
+    fn query_state(&mut self) -> Result<RuntimeState, LxDosError>;
+    /// A descriptor that becomes readable once `poll_changes` may have something to
+    /// report, so that callers can wait for it. `None` if the runtime has to be polled.
+    fn watch(&self) -> Option<OwnedFd> {
+        None
+    }
+    /// State changes the runtime noticed on its own since the last call, oldest first.
+    fn poll_changes(&mut self) -> Result<Vec<RuntimeState>, LxDosError> {
+        Ok(Vec::new())
+    }
+    fn send_input(&mut self, input: &GuestInput) -> Result<(), LxDosError>;
+    fn fetch_screen(&mut self) -> Result<Screenshot, LxDosError>;
+}
@@ -0,0 +1,274 @@
+use crate::LxDosError;
+use crate::utils::dirs;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+
+/// Profile used when `--profile` is not given.
+pub const DEFAULT_PROFILE: &str = "default";
+/// Longest profile name. The runtime directory is named after the profile and the
+/// sockets in it must fit in the 108 bytes of `sun_path`.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Starting point written by `lx-dos profiles create`.
+const TEMPLATE: &str = r#"# Lx-DOS guest definition, check it with `lx-dos config check`.
+name = "Windows"
+disk_image = "/path/to/windows.qcow2"
+memory_mib = 4096
+cpus = 2
+display = "gtk"
+autostart = false
+# installer = "/path/to/Win11.iso" # attached as a CD-ROM until Windows is installed
+
+[network]
+mode = "user"
+
+# [[shared_folders]]
+# name = "home"
+# path = "/home/user"
+# read_only = false
+
+# [windows.main]
+# restart = "on-failure" # never, on-failure or always
+"#;
+
+/// A named guest with its own definition, runtime directory, pipes and tray entry.
+///
+/// The default profile keeps its definition in `guest.toml`, every other profile
+/// in `profiles/<name>.toml` of the configuration directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    name: String,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Profile {
+    /// Profile names end up in file, pipe and application names, so they are
+    /// limited to ASCII letters, digits, `-` and `_`.
+    pub fn new(name: &str) -> Result<Self, LxDosError> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(LxDosError::Message(format!(
+                "Invalid profile name {:?}: use up to {} ASCII letters, digits, '-' or '_'",
+                name, MAX_NAME_LEN
+            )));
+        }
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_PROFILE
+    }
+
+    /// Location of the profile's guest definition.
+    pub fn config_path(&self) -> Result<PathBuf, LxDosError> {
+        let dir = dirs::config_dir()?;
+        Ok(if self.is_default() {
+            dir.join("guest.toml")
+        } else {
+            dir.join("profiles").join(format!("{}.toml", self.name))
+        })
+    }
+
+    /// The profile's subdirectory of `dirs::runtime_dir`, created on demand. It holds
+    /// the instance lock and the sockets of the control and window pipes and of QEMU.
+    pub fn runtime_dir(&self) -> Result<PathBuf, LxDosError> {
+        let dir = dirs::runtime_dir()?.join(&self.name);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        Ok(dir)
+    }
+
+    /// Where the profile's window backends write their logs, created on demand.
+    ///
+    /// `logs` of `dirs::state_dir` for the default profile, `logs/<name>` otherwise.
+    pub fn log_dir(&self) -> Result<PathBuf, LxDosError> {
+        let dir = dirs::state_dir()?.join("logs");
+        let dir = if self.is_default() {
+            dir
+        } else {
+            dir.join(&self.name)
+        };
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Where `lx-dos welcome` creates a disk image for a new installation.
+    pub fn default_disk_image(&self) -> Result<PathBuf, LxDosError> {
+        Ok(dirs::data_dir()?
+            .join("disks")
+            .join(format!("{}.qcow2", self.name)))
+    }
+
+    /// Whether `lx-dos welcome` has been completed or skipped for the profile.
+    pub fn first_run_done(&self) -> Result<bool, LxDosError> {
+        Ok(self.first_run_marker()?.exists())
+    }
+
+    /// Records that `lx-dos start` need not offer the welcome flow again.
+    pub fn mark_first_run_done(&self) -> Result<(), LxDosError> {
+        let marker = self.first_run_marker()?;
+        if let Some(parent) = marker.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&marker, "")?;
+        Ok(())
+    }
+
+    fn first_run_marker(&self) -> Result<PathBuf, LxDosError> {
+        Ok(dirs::state_dir()?.join("first-run").join(&self.name))
+    }
+
+    /// Lists the profiles that have a guest definition.
+    pub fn list() -> Result<Vec<Self>, LxDosError> {
+        let mut profiles = Vec::new();
+        if Self::default().config_path()?.exists() {
+            profiles.push(Self::default());
+        }
+        let dir = dirs::config_dir()?.join("profiles");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(profiles),
+            Err(e) => return Err(LxDosError::Io(e)),
+        };
+        let mut named = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "toml")
+                && let Some(profile) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| Self::new(stem).ok())
+                && !profile.is_default()
+            {
+                named.push(profile);
+            }
+        }
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        profiles.extend(named);
+        Ok(profiles)
+    }
+
+    /// Writes a guest definition for a new profile, copied from `from` or from a template.
+    pub fn create(&self, from: Option<&Profile>) -> Result<PathBuf, LxDosError> {
+        let path = self.config_path()?;
+        if path.exists() {
+            return Err(LxDosError::Message(format!(
+                "Profile {} already exists at {}",
+                self,
+                path.display()
+            )));
+        }
+        let content = match from {
+            Some(from) => fs::read_to_string(from.config_path()?).map_err(|e| {
+                LxDosError::Message(format!("Failed to read profile {}: {}", from, e))
+            })?,
+            None => TEMPLATE.to_string(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    /// Deletes the profile's guest definition and runtime directory.
+    ///
+    /// Disk images referenced by the definition are left alone.
+    pub fn remove(&self) -> Result<(), LxDosError> {
+        let path = self.config_path()?;
+        if !path.exists() {
+            return Err(LxDosError::Message(format!(
+                "Profile {} does not exist",
+                self
+            )));
+        }
+        fs::remove_file(&path)?;
+        if let Err(e) = fs::remove_file(self.first_run_marker()?)
+            && e.kind() != ErrorKind::NotFound
+        {
+            return Err(LxDosError::Io(e));
+        }
+        match fs::remove_dir_all(dirs::runtime_dir()?.join(&self.name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(LxDosError::Io(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::app::control::control_pipe_name;
+    use crate::modules::app::instance::{WindowType, window_pipe_name, window_pipe_owner};
+    use std::path::Path;
+
+    #[test]
+    fn names_are_limited_to_safe_characters() {
+        for name in ["default", "work-11", "Win_10", &"a".repeat(MAX_NAME_LEN)] {
+            assert_eq!(Profile::new(name).unwrap().name(), name);
+        }
+        for name in [
+            "",
+            "../etc",
+            "a b",
+            "ü",
+            ".hidden",
+            &"a".repeat(MAX_NAME_LEN + 1),
+        ] {
+            assert!(Profile::new(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn sockets_of_the_longest_name_fit_in_sun_path() {
+        let profile = Profile::new(&"a".repeat(MAX_NAME_LEN)).unwrap();
+        // $XDG_RUNTIME_DIR の中で一番長くなるもの
+        let runtime_dir = Path::new("/run/user/4294967294/lx-dos").join(profile.name());
+        let names = [
+            control_pipe_name(),
+            window_pipe_name(u32::MAX, &WindowType::Settings),
+            "virtiofs-99".to_string(),
+        ];
+        for name in names {
+            let path = runtime_dir.join(format!("{}.sock", name));
+            // sun_path は 108 バイトで、終端の NUL を含む
+            assert!(
+                path.as_os_str().len() < 108,
+                "{} is too long",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn window_pipes_name_their_frontend() {
+        let name = window_pipe_name(4242, &WindowType::Main);
+        assert_eq!(name, "lxdos_pipe_4242_main");
+        assert_eq!(window_pipe_owner(&name), Some(4242));
+        assert_eq!(window_pipe_owner(&control_pipe_name()), None);
+    }
+}
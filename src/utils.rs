@@ -1,2 +1,6 @@
 pub mod args;
+pub mod dirs;
 pub mod error;
+pub mod logger;
+pub mod logs;
+pub mod process;
@@ -1,5 +1,8 @@
+use crate::modules::app::instance::WindowType;
+use crate::utils::logger::LogFormat;
 use clap::Parser;
 use clap::Subcommand;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,13 +19,21 @@ pub struct Args {
     #[arg(short, long, conflicts_with_all = &["verbose", "quiet"])]
     pub debug: bool,
 
-    /// Run in command-line interface mode
-    #[arg(long, conflicts_with = "gui")]
+    /// Run in command-line interface mode, without tray or windows
+    #[arg(long, global = true, conflicts_with = "gui")]
     pub cli: bool,
 
     /// Run in graphical user interface mode
-    #[arg(long, default_value_t = true, conflicts_with = "cli")]
+    #[arg(long, global = true, default_value_t = true, conflicts_with = "cli")]
     pub gui: bool,
+
+    /// Format of log records written to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Guest profile to operate on
+    #[arg(long, global = true, default_value = "default")]
+    pub profile: String,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -30,11 +41,103 @@ pub struct Args {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Start Lx-DOS
-    Start,
+    ///
+    /// If Lx-DOS is already running, asks it to open the main window instead and
+    /// exits with status 3.
+    Start {
+        /// Print the commands that would boot the guest instead of running anything
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Stop Lx-DOS
-    Stop,
-    /// Show welcome message
+    Stop {
+        /// Seconds to wait for the running instance to exit
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// Kill the instance and its window backends if it does not exit in time
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show what a running Lx-DOS is doing
+    Status {
+        /// Print machine-readable JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect the guest definition
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Manage guest profiles
+    Profiles {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+    /// Control a running Lx-DOS from an interactive prompt
+    Shell,
+    /// Run a command in the guest and exit with its exit code
+    ///
+    /// For example `lx-dos exec -- cmd.exe /c dir`. The command runs through the QEMU
+    /// guest agent, which has to be installed in the guest. The agent cannot stream,
+    /// so the output only appears once the command has exited.
+    Exec {
+        /// Program to run and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Show the output of window backends, QEMU and virtiofsd
+    Logs {
+        /// Keep printing lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+        /// Only show the log of this window
+        #[arg(long, value_enum)]
+        window: Option<WindowType>,
+    },
+    /// Set up a guest for the profile
+    ///
+    /// Walks through choosing an installer ISO or disk image, memory, CPUs, a shared
+    /// folder and autostart, in a window when a display is available and on the
+    /// terminal otherwise. `lx-dos start` offers this once for a profile without a
+    /// guest definition.
     Welcome,
+    /// Check that this computer can run the guest
+    ///
+    /// Looks for KVM access, QEMU, UEFI firmware, disk space, writable directories,
+    /// a display, a tray host and pipes left by a crashed run. Exits with status 1
+    /// if any check fails.
+    Doctor {
+        /// Print machine-readable JSON instead of a report
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProfileCommands {
+    /// List the profiles that have a guest definition
+    List,
+    /// Create a profile from a template or an existing profile
+    Create {
+        name: String,
+        /// Copy the guest definition of this profile
+        #[arg(long)]
+        from: Option<String>,
+    },
+    /// Remove a profile's guest definition and runtime state
+    Remove { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Validate the guest definition
+    Check {
+        /// File to check instead of the default guest definition
+        file: Option<PathBuf>,
+    },
+    /// Print the location of the guest definition
+    Path,
 }
 
 #[derive(Debug, Parser)]
@@ -49,5 +152,8 @@ pub struct InnerArgs {
 #[derive(Debug, Subcommand)]
 pub enum InnerSubCommands {
     /// Show Window
-    Window,
-}
\ No newline at end of file
+    Window {
+        #[arg(value_enum)]
+        window_type: WindowType,
+    },
+}
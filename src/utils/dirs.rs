@@ -0,0 +1,74 @@
+use crate::LxDosError;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::PathBuf;
+
+/// Per-user directory holding the instance lock and other runtime state.
+///
+/// Uses `$XDG_RUNTIME_DIR/lx-dos` and falls back to `/tmp/lx-dos-<uid>`. The fallback
+/// must be a real directory private to the user, so that nobody else can have
+/// created it beforehand to own our sockets.
+pub fn runtime_dir() -> Result<PathBuf, LxDosError> {
+    let dir = runtime_dir_path();
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    if xdg_runtime_dir().is_some() {
+        return Ok(dir);
+    }
+
+    let metadata = fs::symlink_metadata(&dir)?;
+    // SAFETY: geteuid(2) never fails and has no preconditions.
+    let euid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != euid || metadata.mode() & 0o777 != 0o700 {
+        return Err(LxDosError::Message(format!(
+            "{} is not a directory private to this user, remove it or set $XDG_RUNTIME_DIR",
+            dir.display()
+        )));
+    }
+    Ok(dir)
+}
+
+/// Where `runtime_dir` is, without creating or checking it.
+pub fn runtime_dir_path() -> PathBuf {
+    match xdg_runtime_dir() {
+        Some(dir) => dir.join("lx-dos"),
+        None => env::temp_dir().join(format!("lx-dos-{}", crate::utils::process::current_uid())),
+    }
+}
+
+fn xdg_runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Per-user configuration directory, `$XDG_CONFIG_HOME/lx-dos` or `~/.config/lx-dos`.
+///
+/// Unlike `runtime_dir`, the directory is not created.
+pub fn config_dir() -> Result<PathBuf, LxDosError> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Per-user state directory, `$XDG_STATE_HOME/lx-dos` or `~/.local/state/lx-dos`.
+///
+/// Like `config_dir`, the directory is not created.
+pub fn state_dir() -> Result<PathBuf, LxDosError> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Per-user data directory, `$XDG_DATA_HOME/lx-dos` or `~/.local/share/lx-dos`.
+///
+/// Like `config_dir`, the directory is not created.
+pub fn data_dir() -> Result<PathBuf, LxDosError> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf, LxDosError> {
+    let base = match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::home_dir()
+            .ok_or_else(|| LxDosError::Message(format!("Neither ${} nor $HOME is set", var)))?
+            .join(fallback),
+    };
+    Ok(base.join("lx-dos"))
+}
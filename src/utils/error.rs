@@ -1,3 +1,6 @@
+use crate::modules::lx_dos::GuestState;
+use crate::modules::lx_dos::config::ConfigErrors;
+use crate::modules::lx_dos::paths::PathError;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,6 +13,33 @@ pub enum LxDosError {
     Crossbeam(#[from] crossbeam_channel::RecvError),
     #[error("{0}")]
     SystemTray(#[from] system_tray::Error),
+    #[error("Invalid guest state transition from {from} to {to}")]
+    InvalidTransition { from: GuestState, to: GuestState },
+    #[error("{0}")]
+    Config(ConfigErrors),
+    #[error("{0}")]
+    Path(#[from] PathError),
+    #[error(
+        "Peer lx-dos {binary} speaks window protocol {theirs}{}, this binary speaks {ours}",
+        without(missing)
+    )]
+    IncompatiblePeer {
+        ours: u32,
+        theirs: u32,
+        binary: String,
+        /// Required capabilities the peer did not announce.
+        missing: Vec<String>,
+    },
+    #[error("Unauthenticated peer: {0}")]
+    Unauthenticated(String),
     #[error("process was exit with {0}")]
     Exit(u8),
 }
+
+fn without(missing: &[String]) -> String {
+    if missing.is_empty() {
+        String::new()
+    } else {
+        format!(" without {}", missing.join(", "))
+    }
+}
@@ -0,0 +1,83 @@
+use crate::utils::logs;
+use log::LevelFilter;
+use std::env;
+use std::io::Write;
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// Environment variables through which a frontend hands its logging setup to the
+/// backends it spawns.
+const LEVEL_ENV: &str = "LXDOS_LOG_LEVEL";
+const FORMAT_ENV: &str = "LXDOS_LOG_FORMAT";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// env_logger's human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl LogFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+static CONFIG: OnceLock<(LevelFilter, LogFormat)> = OnceLock::new();
+
+/// Installs the global logger. Records are written to stderr.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    if CONFIG.set((level, format)).is_err() {
+        return;
+    }
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "ts": logs::timestamp(SystemTime::now()),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "pid": std::process::id(),
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Installs the logger of a backend with the setup passed down by `propagate`.
+pub fn init_from_env() {
+    let level = env::var(LEVEL_ENV)
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Warn);
+    let format = match env::var(FORMAT_ENV).as_deref() {
+        Ok("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    init(level, format);
+}
+
+/// Passes the current level and format on to a backend about to be spawned.
+pub fn propagate(command: &mut Command) {
+    let (level, format) = CONFIG
+        .get()
+        .copied()
+        .unwrap_or((log::max_level(), LogFormat::Text));
+    command
+        .env(LEVEL_ENV, level.as_str())
+        .env(FORMAT_ENV, format.as_str());
+}
+
+/// Format chosen by `init`, also used for captured backend output.
+pub fn format() -> LogFormat {
+    CONFIG.get().map(|(_, format)| *format).unwrap_or_default()
+}
@@ -0,0 +1,314 @@
+use crate::LxDosError;
+use crate::utils::logger::{self, LogFormat};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size at which a log file is rotated.
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+/// Rotated files kept besides the current one, as `<name>.1` (newest) to `<name>.3`.
+const KEPT_LOGS: usize = 3;
+
+/// A log file that is moved aside once it grows past `MAX_LOG_SIZE`.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    pub fn open(path: &Path) -> Result<Self, LxDosError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..KEPT_LOGS).rev() {
+            match fs::rename(
+                rotated_path(&self.path, index),
+                rotated_path(&self.path, index + 1),
+            ) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// The existing files of the log at `path`, oldest first and the current one last.
+pub fn log_files(path: &Path) -> Vec<PathBuf> {
+    (1..=KEPT_LOGS)
+        .rev()
+        .map(|index| rotated_path(path, index))
+        .chain([path.to_path_buf()])
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// Where captured output came from.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub window: String,
+    pub pid: u32,
+}
+
+/// Copies the lines of `stdout` and `stderr` into `log` until both are closed.
+///
+/// Each line is prefixed with a timestamp, `source` and the stream name. With
+/// `LogFormat::Json`, lines become JSON objects carrying the same fields instead.
+pub fn capture<O, E>(log: RotatingLog, source: Source, stdout: O, stderr: E)
+where
+    O: Read + Send + 'static,
+    E: Read + Send + 'static,
+{
+    capture_shared(Arc::new(Mutex::new(log)), source, stdout, stderr);
+}
+
+/// Like `capture`, for a log that several processes write into.
+pub fn capture_shared<O, E>(log: Arc<Mutex<RotatingLog>>, source: Source, stdout: O, stderr: E)
+where
+    O: Read + Send + 'static,
+    E: Read + Send + 'static,
+{
+    let source = Arc::new(source);
+    copy_lines(Arc::clone(&log), Arc::clone(&source), "stdout", stdout);
+    copy_lines(log, source, "stderr", stderr);
+}
+
+fn copy_lines<R>(log: Arc<Mutex<RotatingLog>>, source: Arc<Source>, stream: &'static str, input: R)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        // 不正なUTF-8が混ざっていても行単位で書き出す
+        for line in BufReader::new(input).split(b'\n') {
+            let Ok(line) = line else { break };
+            let line = format_line(
+                logger::format(),
+                &source,
+                stream,
+                &String::from_utf8_lossy(&line),
+            );
+            let Ok(mut log) = log.lock() else { break };
+            if let Err(e) = log.write_line(&line) {
+                log::error!("Failed to write {}: {}", log.path.display(), e);
+                break;
+            }
+        }
+    });
+}
+
+fn format_line(format: LogFormat, source: &Source, stream: &str, line: &str) -> String {
+    let now = timestamp(SystemTime::now());
+    if format == LogFormat::Text {
+        return format!(
+            "{} {}[{}] {}: {}",
+            now, source.window, source.pid, stream, line
+        );
+    }
+    // JSONで出力するバックエンドの行はそのまま使い、出所を書き足す
+    let mut object = match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(serde_json::Value::Object(object)) => object,
+        _ => {
+            let mut object = serde_json::Map::new();
+            object.insert("ts".to_string(), now.into());
+            object.insert("msg".to_string(), line.into());
+            object
+        }
+    };
+    object.insert("window".to_string(), source.window.clone().into());
+    object.insert("pid".to_string(), source.pid.into());
+    object.insert("stream".to_string(), stream.into());
+    serde_json::Value::Object(object).to_string()
+}
+
+/// Timestamp a line written by `capture` starts with, or carries in its `ts` field.
+pub fn line_timestamp(line: &str) -> String {
+    if line.starts_with('{')
+        && let Ok(value) = serde_json::from_str::<serde_json::Value>(line)
+        && let Some(ts) = value.get("ts").and_then(|ts| ts.as_str())
+    {
+        return ts.to_string();
+    }
+    line.split_once(' ')
+        .map_or(line, |(timestamp, _)| timestamp)
+        .to_string()
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with millisecond precision.
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "lx-dos-logs-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn timestamps_are_rfc_3339_utc() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_123)),
+            "2000-02-29T00:00:00.123Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_790_000_000)),
+            "2026-09-21T14:13:20.000Z"
+        );
+    }
+
+    #[test]
+    fn logs_rotate_and_keep_the_newest_files() {
+        let dir = scratch_dir();
+        let path = dir.join("window.log");
+        let mut log = RotatingLog::open(&path).unwrap();
+        let line = "x".repeat(MAX_LOG_SIZE as usize / 2);
+        for _ in 0..10 {
+            log.write_line(&line).unwrap();
+        }
+
+        let files = log_files(&path);
+        assert_eq!(
+            files,
+            vec![
+                rotated_path(&path, 3),
+                rotated_path(&path, 2),
+                rotated_path(&path, 1),
+                path.clone()
+            ]
+        );
+        assert!(!rotated_path(&path, KEPT_LOGS + 1).exists());
+        for file in files {
+            assert!(fs::metadata(file).unwrap().len() <= MAX_LOG_SIZE);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn captured_lines_are_tagged_with_their_stream() {
+        let dir = scratch_dir();
+        let path = dir.join("window.log");
+        let log = RotatingLog::open(&path).unwrap();
+        let source = Source {
+            window: "tray".to_string(),
+            pid: 42,
+        };
+        capture(log, source, &b"one\ntwo\n"[..], &b"oops\n"[..]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lines = loop {
+            let text = fs::read_to_string(&path).unwrap();
+            if text.lines().count() == 3 || Instant::now() > deadline {
+                break text.lines().map(str::to_string).collect::<Vec<_>>();
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let mut tagged = lines
+            .iter()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        tagged.sort();
+        assert_eq!(
+            tagged,
+            [
+                "tray[42] stderr: oops",
+                "tray[42] stdout: one",
+                "tray[42] stdout: two"
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_lines_carry_their_source() {
+        let source = Source {
+            window: "tray".to_string(),
+            pid: 42,
+        };
+        let plain: serde_json::Value =
+            serde_json::from_str(&format_line(LogFormat::Json, &source, "stderr", "oops")).unwrap();
+        assert_eq!(plain["msg"], "oops");
+        assert_eq!(plain["window"], "tray");
+        assert_eq!(plain["pid"], 42);
+        assert_eq!(plain["stream"], "stderr");
+        assert!(plain["ts"].is_string());
+
+        // バックエンドが JSON で書いた行は、その項目を残したまま出所を足す
+        let record = r#"{"ts":"2026-01-01T00:00:00.000Z","level":"WARN","msg":"hi"}"#;
+        let merged: serde_json::Value =
+            serde_json::from_str(&format_line(LogFormat::Json, &source, "stderr", record)).unwrap();
+        assert_eq!(merged["ts"], "2026-01-01T00:00:00.000Z");
+        assert_eq!(merged["level"], "WARN");
+        assert_eq!(merged["msg"], "hi");
+        assert_eq!(merged["window"], "tray");
+    }
+}
@@ -0,0 +1,176 @@
+use crate::LxDosError;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::thread;
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid(2) never fails and has no preconditions.
+    unsafe { libc::getuid() }
+}
+
+/// Returns `true` while the process exists and has not become a zombie.
+pub fn is_alive(pid: u32) -> bool {
+    match read_stat(pid) {
+        Some((state, _)) => state != 'Z' && state != 'X',
+        None => false,
+    }
+}
+
+/// Command name of `pid`, as shown by `ps`.
+pub fn name(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_string())
+}
+
+/// Lists the direct children of `pid` by scanning `/proc`.
+pub fn children_of(pid: u32) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|&child| matches!(read_stat(child), Some((_, ppid)) if ppid == pid))
+        .collect()
+}
+
+pub fn send_signal(pid: u32, signal: i32) -> Result<(), LxDosError> {
+    // SAFETY: kill(2) only reads its integer arguments.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(LxDosError::Io(std::io::Error::last_os_error()))
+    }
+}
+
+/// Calls `notify` on a background thread once `pid` exits.
+///
+/// Waits on a pidfd, so nothing is polled and a recycled pid cannot be mistaken
+/// for the original process.
+pub fn on_exit<F>(pid: u32, notify: F) -> Result<(), LxDosError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    // SAFETY: pidfd_open(2) only reads its integer arguments.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(LxDosError::Io(io::Error::last_os_error()));
+    }
+    // SAFETY: the descriptor was just returned to us and is owned by nobody else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    // A pidfd becomes readable when the process terminates.
+    on_readable(fd, notify);
+    Ok(())
+}
+
+/// Calls `notify` on a background thread once `fd` is readable or hung up, then
+/// closes it.
+pub fn on_readable<F>(fd: OwnedFd, notify: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut pollfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid array of one element for the whole call.
+        while unsafe { libc::poll(&mut pollfd, 1, -1) } < 0
+            && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+        {}
+        notify();
+    });
+}
+
+/// Finds an executable `name` in `$PATH`.
+pub fn find_in_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| {
+            fs::metadata(path).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
+}
+
+/// Reads the state character and parent pid from `/proc/<pid>/stat`.
+fn read_stat(pid: u32) -> Option<(char, u32)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is wrapped in parentheses and may itself contain spaces.
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    Some((state, ppid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn a_spawned_child_is_alive_and_listed_under_its_parent() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id();
+        assert!(is_alive(pid));
+        assert_eq!(name(pid).as_deref(), Some("sleep"));
+        assert!(children_of(std::process::id()).contains(&pid));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!is_alive(pid));
+        assert!(!children_of(std::process::id()).contains(&pid));
+    }
+
+    #[test]
+    fn a_zombie_is_not_alive() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id();
+        send_signal(pid, libc::SIGKILL).unwrap();
+        // 回収するまではゾンビとして /proc に残る
+        let mut waited = 0;
+        while is_alive(pid) && waited < 100 {
+            thread::sleep(Duration::from_millis(50));
+            waited += 1;
+        }
+        assert!(!is_alive(pid));
+        assert!(name(pid).is_some());
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn stat_is_read_past_the_command_name() {
+        let (state, ppid) = read_stat(std::process::id()).unwrap();
+        assert!("RSDT".contains(state));
+        assert_eq!(ppid, std::os::unix::process::parent_id());
+    }
+
+    #[test]
+    fn on_exit_fires_once_the_process_is_killed() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let (tx, exited) = mpsc::channel();
+        on_exit(child.id(), move || tx.send(()).unwrap()).unwrap();
+        assert!(exited.recv_timeout(Duration::from_millis(200)).is_err());
+
+        child.kill().unwrap();
+        // ゾンビのままでも pidfd は読めるようになる
+        exited.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!is_alive(child.id()));
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn on_exit_refuses_a_process_that_is_already_gone() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(on_exit(pid, || {}).is_err());
+    }
+}